@@ -1,2 +1,4 @@
+pub mod markdown;
 pub mod notion;
 pub mod notion_opendal;
+pub mod retry;
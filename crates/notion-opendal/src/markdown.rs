@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use notion2md::builder::NotionToMarkdownBuilder;
+use notion2md::notion_to_md::NotionToMarkdown;
+use notion2md::utils;
+use notion_client::endpoints::Client as NotionClient;
+use notion_client::objects::block::{CalloutValue, Icon, Language, TextColor};
+use notion_client::objects::emoji::Emoji;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use crate::notion::normalize_notion_id;
+
+/// Options that change how page blocks are rendered to Markdown.
+///
+/// Two renders of the same page revision only match when their options are
+/// equal, so callers caching rendered Markdown should key on these as well.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RenderOptions {
+    /// Tag code fences with the language name syntax highlighters expect
+    /// (`cpp`, `csharp`, `text`) instead of notion2md's default, which
+    /// lowercases the enum variant (`cplusplus`, `plaintext`).
+    pub code_lang: bool,
+    /// How callout blocks are written.
+    pub callouts: CalloutStyle,
+}
+
+impl RenderOptions {
+    /// Build a converter that renders pages with these options.
+    pub fn converter(&self, client: NotionClient) -> NotionToMarkdown {
+        let mut builder = NotionToMarkdownBuilder::new(client);
+        if self.code_lang {
+            builder = builder.code(|payload| {
+                let text = NotionToMarkdown::rich_text_to_markdown(&payload.value.rich_text);
+                let language = code_language(&payload.value.language);
+                Ok(format!("{}\n", utils::code_block(&text, Some(&language))))
+            });
+        }
+        if self.callouts == CalloutStyle::Github {
+            builder = builder.callout(|payload| {
+                let mut body = NotionToMarkdown::rich_text_to_markdown(&payload.value.rich_text);
+                if !payload.children.is_empty() {
+                    body.push_str("\n\n");
+                    body.push_str(&payload.owner.convert_blocks_to_markdown(payload.children)?);
+                }
+                Ok(github_alert(alert_type(payload.value), &body))
+            });
+        }
+        builder.build()
+    }
+}
+
+/// How callout blocks are written in rendered Markdown.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalloutStyle {
+    /// notion2md's rendering: a quote opening with `[!note]` and the text.
+    #[default]
+    Quote,
+    /// A GitHub alert such as `> [!WARNING]`, its type picked from the
+    /// callout's icon or, failing that, its color.
+    Github,
+}
+
+/// Pick the GitHub alert type for a callout. Common emoji decide first
+/// since they carry the writer's intent; otherwise red reads as a caution,
+/// orange and yellow as a warning, green as a tip and purple as important.
+fn alert_type(callout: &CalloutValue) -> &'static str {
+    if let Some(Icon::Emoji(Emoji::Emoji { emoji })) = &callout.icon {
+        match emoji.trim_end_matches('\u{fe0f}') {
+            "💡" | "✅" | "✨" | "🌱" => return "TIP",
+            "❗" | "❕" | "📌" | "⭐" | "🔑" => return "IMPORTANT",
+            "⚠" | "🚧" | "⚡" => return "WARNING",
+            "🚨" | "❌" | "⛔" | "🛑" | "🔥" => return "CAUTION",
+            "ℹ" | "📝" | "🗒" | "💬" => return "NOTE",
+            _ => {}
+        }
+    }
+    match callout.color {
+        TextColor::Red | TextColor::RedBackground => "CAUTION",
+        TextColor::Orange
+        | TextColor::OrangeBackground
+        | TextColor::Yellow
+        | TextColor::YellowBackground => "WARNING",
+        TextColor::Green | TextColor::GreenBackground => "TIP",
+        TextColor::Purple | TextColor::PurpleBackground => "IMPORTANT",
+        _ => "NOTE",
+    }
+}
+
+/// Quote `body` under a `[!{kind}]` marker. Blank lines stay inside the
+/// quote so multi-paragraph callouts remain one alert.
+fn github_alert(kind: &str, body: &str) -> String {
+    let mut alert = format!("> [!{kind}]\n");
+    for line in body.trim_matches('\n').lines() {
+        if line.is_empty() {
+            alert.push_str(">\n");
+        } else {
+            alert.push_str("> ");
+            alert.push_str(line);
+            alert.push('\n');
+        }
+    }
+    alert.push('\n');
+    alert
+}
+
+/// Map a Notion code language to the fence tag used by common highlighters.
+///
+/// Notion's own names (its API values) are used where they already work,
+/// such as `python` or `objective-c`; the rest are spelled the way GitHub and
+/// highlight.js recognise them.
+fn code_language(language: &Language) -> String {
+    let name = serde_json::to_value(language)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default();
+    match name.as_str() {
+        "c++" => "cpp".to_string(),
+        "c#" => "csharp".to_string(),
+        "f#" => "fsharp".to_string(),
+        "plain text" => "text".to_string(),
+        "vb.net" => "vbnet".to_string(),
+        "visual basic" => "vb".to_string(),
+        // Notion's catch-all for C-like snippets; `c` is the closest match.
+        "java/c/c++/c#" => "c".to_string(),
+        _ => name.replace(' ', "-"),
+    }
+}
+
+/// How links to other Notion pages are written in rendered Markdown.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Keep the links Notion returns.
+    #[default]
+    Notion,
+    /// Point links at `{target_id}.md`.
+    Relative,
+    /// Point links at `{target_slug}.md`, the target's slugified title.
+    Slug,
+}
+
+/// Markdown link destinations that point at a Notion page: `notion.so` and
+/// `*.notion.site` URLs as well as the bare `/{id}` paths Notion uses for
+/// links inside a workspace. Page URLs may carry a workspace segment and a
+/// title prefix (`/acme/Roadmap-{id}`); any query or fragment is dropped
+/// when the link is rewritten.
+static NOTION_PAGE_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\]\((?:https?://(?:www\.)?notion\.so|https?://[\w-]+\.notion\.site)?/(?:[\w.%-]+/)?(?:[\w.%-]*-)?([0-9a-f]{32})(?:[?#][^)\s]*)?\)",
+    )
+    .expect("notion page link pattern is valid")
+});
+
+/// IDs of the Notion pages linked from `markdown`, dashed and in order of
+/// first appearance.
+pub fn linked_page_ids(markdown: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for captures in NOTION_PAGE_LINK.captures_iter(markdown) {
+        let id = normalize_notion_id(&captures[1]);
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// Rewrite links to Notion pages according to `mode`. `slugs` maps dashed
+/// page IDs to their slugs and is only consulted in [`LinkMode::Slug`];
+/// links to pages missing from it are left as they are.
+pub fn rewrite_page_links(
+    markdown: &str,
+    mode: LinkMode,
+    slugs: &HashMap<String, String>,
+) -> String {
+    if mode == LinkMode::Notion {
+        return markdown.to_string();
+    }
+
+    NOTION_PAGE_LINK
+        .replace_all(markdown, |captures: &Captures<'_>| {
+            let id = normalize_notion_id(&captures[1]);
+            match mode {
+                LinkMode::Notion => captures[0].to_string(),
+                LinkMode::Relative => format!("]({id}.md)"),
+                LinkMode::Slug => match slugs.get(&id) {
+                    Some(slug) => format!("]({slug}.md)"),
+                    None => captures[0].to_string(),
+                },
+            }
+        })
+        .into_owned()
+}
+
+/// HTML comments and Obsidian-style `%% ... %%` comments, either of which
+/// may span several lines.
+static COMMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<!--.*?-->|%%.*?%%").expect("comment pattern is valid"));
+
+/// Remove comments from `markdown`.
+///
+/// Notion has no comment block of its own that survives conversion, so
+/// writers leave notes as text in one of the common Markdown comment forms:
+/// `<!-- ... -->` or `%% ... %%`. Those are removed, and lines that held
+/// nothing but comments are dropped entirely. Fenced code blocks are left
+/// untouched; inline code is not special-cased.
+pub fn strip_comments(markdown: &str) -> String {
+    let mut output = String::with_capacity(markdown.len());
+    let mut prose = String::new();
+    let mut in_code = false;
+
+    for line in markdown.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            if !in_code {
+                output.push_str(&strip_prose_comments(&prose));
+                prose.clear();
+            }
+            in_code = !in_code;
+            output.push_str(line);
+        } else if in_code {
+            output.push_str(line);
+        } else {
+            prose.push_str(line);
+        }
+    }
+    output.push_str(&strip_prose_comments(&prose));
+    output
+}
+
+/// ATX headings: up to three spaces of indentation, one to six `#`, then a
+/// space or the end of the line.
+static HEADING: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^( {0,3})(#{1,6})( |\t|\r?\n|$)").expect("heading pattern is valid")
+});
+
+/// Demote every heading in `markdown` by `offset` levels, so `#` becomes
+/// `##` with an offset of 1. Levels past six are capped at `######`, since
+/// Markdown has no deeper heading. Fenced code blocks are left untouched.
+pub fn offset_headings(markdown: &str, offset: usize) -> String {
+    if offset == 0 {
+        return markdown.to_string();
+    }
+
+    let mut output = String::with_capacity(markdown.len());
+    let mut in_code = false;
+    for line in markdown.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            output.push_str(line);
+        } else if in_code {
+            output.push_str(line);
+        } else {
+            output.push_str(&HEADING.replace(line, |captures: &Captures<'_>| {
+                let level = captures[2].len().saturating_add(offset).min(6);
+                format!("{}{}{}", &captures[1], "#".repeat(level), &captures[3])
+            }));
+        }
+    }
+    output
+}
+
+fn strip_prose_comments(prose: &str) -> String {
+    // Comments are first replaced with a marker so lines that only held
+    // comments can be told apart from lines that were blank to begin with.
+    const MARKER: char = '\u{0}';
+    let marked = COMMENT.replace_all(prose, MARKER.to_string().as_str());
+    marked
+        .split_inclusive('\n')
+        .filter(|line| {
+            !(line.contains(MARKER) && line.chars().all(|c| c == MARKER || c.is_whitespace()))
+        })
+        .flat_map(|line| line.chars().filter(|&c| c != MARKER))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use notion2md::notion_to_md::BlockWithChildren;
+    use notion_client::objects::block::BlockType;
+
+    use super::*;
+
+    #[test]
+    fn headings_are_demoted() {
+        let markdown = "# Title\n\nText with a #hashtag.\n\n## Section\n   ### Indented\n#\n";
+        assert_eq!(
+            offset_headings(markdown, 1),
+            "## Title\n\nText with a #hashtag.\n\n### Section\n   #### Indented\n##\n"
+        );
+        assert_eq!(offset_headings(markdown, 0), markdown);
+    }
+
+    #[test]
+    fn headings_are_capped_at_six_levels() {
+        assert_eq!(offset_headings("#### Four\n", 2), "###### Four\n");
+        assert_eq!(offset_headings("#### Four\n", 3), "###### Four\n");
+        assert_eq!(offset_headings("###### Six\n", 1), "###### Six\n");
+        assert_eq!(offset_headings("# One\n", usize::MAX), "###### One\n");
+    }
+
+    #[test]
+    fn code_blocks_and_non_headings_are_left_alone() {
+        let markdown = "```bash\n# a comment\n```\n#hashtag\n    # indented code\n";
+        assert_eq!(offset_headings(markdown, 2), markdown);
+    }
+
+    fn text(content: &str) -> serde_json::Value {
+        serde_json::json!([{
+            "type": "text",
+            "text": { "content": content, "link": null },
+            "annotations": {
+                "bold": false,
+                "italic": false,
+                "strikethrough": false,
+                "underline": false,
+                "code": false,
+                "color": "default",
+            },
+            "plain_text": content,
+            "href": null,
+        }])
+    }
+
+    fn block(value: serde_json::Value) -> BlockWithChildren {
+        BlockWithChildren {
+            block: serde_json::from_value(value).expect("valid notion block"),
+            children: Vec::new(),
+        }
+    }
+
+    fn callout(content: &str, emoji: Option<&str>, color: &str) -> BlockWithChildren {
+        let icon = emoji.map(|emoji| serde_json::json!({ "type": "emoji", "emoji": emoji }));
+        block(serde_json::json!({
+            "type": "callout",
+            "callout": { "rich_text": text(content), "icon": icon, "color": color },
+        }))
+    }
+
+    fn render(options: RenderOptions, blocks: &[BlockWithChildren]) -> String {
+        let client = NotionClient::new("token".to_string(), None).expect("client");
+        options
+            .converter(client)
+            .convert_blocks_to_markdown(blocks)
+            .expect("rendered")
+    }
+
+    #[test]
+    fn callouts_render_as_github_alerts() {
+        let options = RenderOptions {
+            callouts: CalloutStyle::Github,
+            ..RenderOptions::default()
+        };
+        let mut note = callout("Read this first", Some("\u{2139}\u{fe0f}"), "default");
+        note.children.push(block(serde_json::json!({
+            "type": "paragraph",
+            "paragraph": { "rich_text": text("More detail"), "color": "default" },
+        })));
+
+        assert_eq!(
+            render(options, &[note]).trim_end(),
+            "> [!NOTE]\n> Read this first\n>\n> More detail"
+        );
+    }
+
+    #[test]
+    fn callout_alert_types_follow_icon_then_color() {
+        let cases = [
+            (Some("💡"), "default", "TIP"),
+            (Some("⚠️"), "default", "WARNING"),
+            (Some("🚨"), "default", "CAUTION"),
+            (Some("❗"), "default", "IMPORTANT"),
+            (Some("📝"), "red_background", "NOTE"),
+            (Some("🦀"), "red_background", "CAUTION"),
+            (None, "yellow", "WARNING"),
+            (None, "green_background", "TIP"),
+            (None, "purple", "IMPORTANT"),
+            (None, "gray_background", "NOTE"),
+        ];
+        for (emoji, color, kind) in cases {
+            let block = callout("x", emoji, color);
+            let BlockType::Callout { callout } = &block.block.block_type else {
+                unreachable!("callout fixture");
+            };
+            assert_eq!(alert_type(callout), kind, "{emoji:?} {color}");
+        }
+    }
+
+    #[test]
+    fn callouts_are_quotes_by_default() {
+        let markdown = render(
+            RenderOptions::default(),
+            &[callout("Heads up", Some("⚠️"), "default")],
+        );
+        assert_eq!(markdown.trim_end(), "> [!note] Heads up");
+    }
+
+    fn code(content: &str, language: &str) -> BlockWithChildren {
+        block(serde_json::json!({
+            "type": "code",
+            "code": { "rich_text": text(content), "caption": [], "language": language },
+        }))
+    }
+
+    #[test]
+    fn code_blocks_are_fenced_with_their_language() {
+        let options = RenderOptions {
+            code_lang: true,
+            ..RenderOptions::default()
+        };
+        assert_eq!(
+            render(options, &[code("print(1)", "python")]).trim_end(),
+            "```python\nprint(1)\n```"
+        );
+        assert_eq!(
+            render(options, &[code("int x;", "c++")]).trim_end(),
+            "```cpp\nint x;\n```"
+        );
+        assert_eq!(
+            render(RenderOptions::default(), &[code("int x;", "c++")]).trim_end(),
+            "```cplusplus\nint x;\n```"
+        );
+    }
+
+    #[test]
+    fn page_links_are_rewritten_per_mode() {
+        let markdown = "See [Roadmap](https://www.notion.so/acme/Roadmap-1a2b3c4d5e6f70819a2b3c4d5e6f7081?pvs=4), \
+                        [Notes](/ffffffffffffffffffffffffffffffff#intro) \
+                        and [Rust](https://www.rust-lang.org/).";
+        let roadmap = "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081";
+        let notes = "ffffffff-ffff-ffff-ffff-ffffffffffff";
+        assert_eq!(linked_page_ids(markdown), [roadmap, notes]);
+
+        let slugs = HashMap::from([(roadmap.to_string(), "roadmap".to_string())]);
+        assert_eq!(
+            rewrite_page_links(markdown, LinkMode::Notion, &slugs),
+            markdown
+        );
+        assert_eq!(
+            rewrite_page_links(markdown, LinkMode::Relative, &slugs),
+            format!(
+                "See [Roadmap]({roadmap}.md), [Notes]({notes}.md) \
+                 and [Rust](https://www.rust-lang.org/)."
+            )
+        );
+        assert_eq!(
+            rewrite_page_links(markdown, LinkMode::Slug, &slugs),
+            "See [Roadmap](roadmap.md), [Notes](/ffffffffffffffffffffffffffffffff#intro) \
+             and [Rust](https://www.rust-lang.org/)."
+        );
+    }
+
+    #[test]
+    fn comments_are_stripped_outside_code_fences() {
+        let markdown = "Keep <!-- inline --> this.\n\
+                        <!-- a note\nacross lines -->\n\
+                        %% obsidian %%\n\
+                        \n\
+                        Done %% aside %%.\n\
+                        ```html\n<!-- kept -->\n%% kept %%\n```\n";
+        assert_eq!(
+            strip_comments(markdown),
+            "Keep  this.\n\nDone .\n```html\n<!-- kept -->\n%% kept %%\n```\n"
+        );
+    }
+}
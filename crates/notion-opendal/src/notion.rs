@@ -1,20 +1,48 @@
 use std::collections::HashMap;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use notion_client::endpoints::Client as NotionClient;
+use notion_client::objects::database::{Database, DatabaseProperty, NumberFormat};
+use notion_client::objects::emoji::Emoji;
+use notion_client::objects::file::File;
 use notion_client::objects::page::{
-    DateOrDateTime, DatePropertyValue, Page as NotionPage, PageProperty as NotionPageProperty,
+    DateOrDateTime, DatePropertyValue, FormulaPropertyValue, Icon, Page as NotionPage,
+    PageProperty as NotionPageProperty, RollupPropertyValue, UniqueIDPropertyValue,
+    VerificationPropertyValue, VerificationState,
 };
+use notion_client::objects::parent::Parent;
 use notion_client::objects::rich_text::RichText;
-use serde::Serialize;
+use notion_client::objects::user::User;
+use notion_client::NotionClientError;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum PropertyValue {
     String(String),
-    Number(f64),
+    /// Whole numbers serialize as integers, so `42` doesn't become `42.0`.
+    Number(
+        #[serde(serialize_with = "serialize_number")]
+        #[schemars(with = "f64")]
+        f64,
+    ),
     Boolean(bool),
     StringArray(Vec<String>),
+    /// A date-only value, rendered as `YYYY-MM-DD`.
+    Date(NaiveDate),
     DateTime(DateTime<Utc>),
+    /// Users with their IDs, from [`detail_people`].
+    People(Vec<Person>),
+}
+
+/// A Notion user as listed in a people property.
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct Person {
+    pub id: String,
+    /// `None` when the integration can't see the user's name.
+    pub name: Option<String>,
 }
 
 pub fn notion_page_to_properties(page: &NotionPage) -> HashMap<String, PropertyValue> {
@@ -24,11 +52,482 @@ pub fn notion_page_to_properties(page: &NotionPage) -> HashMap<String, PropertyV
         if let Some(value) = property_to_value(property.clone()) {
             properties.insert(name.clone(), value);
         }
+        // A property maps to a single value, so the expiry of a verification
+        // gets a key of its own.
+        if let NotionPageProperty::Verification {
+            verification: Some(verification),
+            ..
+        } = property
+        {
+            if let Some(until) = verification_expiry(verification) {
+                properties.insert(format!("{name} until"), until);
+            }
+        }
     }
 
     properties
 }
 
+/// Whether `id` looks like a Notion ID: 32 hex digits, either bare as in
+/// Notion URLs or dashed in the 8-4-4-4-12 UUID layout.
+pub fn is_valid_notion_id(id: &str) -> bool {
+    let is_hex = |part: &str| part.chars().all(|c| c.is_ascii_hexdigit());
+
+    if id.len() == 32 {
+        return is_hex(id);
+    }
+
+    let parts: Vec<&str> = id.split('-').collect();
+    parts.len() == 5
+        && parts
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(part, len)| part.len() == len && is_hex(part))
+}
+
+/// Extract the dashed page ID from a Notion page URL.
+///
+/// Handles workspace URLs (`https://www.notion.so/acme/Roadmap-{id}`), public
+/// share URLs (`https://acme.notion.site/Roadmap-{id}`) and bare IDs as the
+/// last path segment. A page opened as a peek in a database view
+/// (`...?v={view}&p={id}`) resolves to that page rather than the database.
+/// Returns `None` when no segment ends in an ID.
+pub fn page_id_from_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let (rest, query) = match url.split_once('?') {
+        Some((rest, query)) => (rest, query.split('#').next().unwrap_or_default()),
+        None => (url, ""),
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+
+    let peek = query
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix("p="))
+        .find(|id| is_valid_notion_id(id));
+    if let Some(id) = peek {
+        return Some(normalize_notion_id(id));
+    }
+
+    let path = rest.split_once("://").map_or(rest, |(_, rest)| rest);
+    let segment = path.trim_end_matches('/').rsplit('/').next()?;
+    if is_valid_notion_id(segment) {
+        return Some(normalize_notion_id(segment));
+    }
+    // `Title-{id}`: the ID is the last 32 characters, after a hyphen.
+    let split = segment.len().checked_sub(32)?;
+    let (title, id) = (segment.get(..split)?, segment.get(split..)?);
+    (title.ends_with('-') && is_valid_notion_id(id)).then(|| normalize_notion_id(id))
+}
+
+/// Insert dashes into a bare 32 hex digit ID so it reads as a UUID
+/// (8-4-4-4-12). Anything else, including already dashed IDs, is returned
+/// unchanged.
+pub fn normalize_notion_id(id: &str) -> String {
+    if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return id.to_string();
+    }
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &id[0..8],
+        &id[8..12],
+        &id[12..16],
+        &id[16..20],
+        &id[20..32]
+    )
+}
+
+/// Notion returns at most this many results per list or query call.
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Clamp a requested page size into the `1..=100` range Notion accepts.
+pub fn clamp_page_size(page_size: u32) -> u32 {
+    page_size.clamp(1, MAX_PAGE_SIZE)
+}
+
+/// `User-Agent` sent to Notion by clients that don't set their own.
+pub const DEFAULT_USER_AGENT: &str = concat!("notion-opendal/", env!("CARGO_PKG_VERSION"));
+
+/// Build a Notion client for `token` that identifies itself with
+/// `user_agent`, so operators can pick out their traffic in Notion's logs.
+pub fn new_notion_client(
+    token: String,
+    user_agent: &str,
+) -> Result<NotionClient, NotionClientError> {
+    let builder = reqwest::ClientBuilder::new().user_agent(user_agent);
+    NotionClient::new(token, Some(builder))
+}
+
+/// Replace the name lists of people properties with [`PropertyValue::People`],
+/// keeping each user's ID and users whose names the integration can't see.
+pub fn detail_people(properties: &mut HashMap<String, PropertyValue>, page: &NotionPage) {
+    for (name, property) in &page.properties {
+        let NotionPageProperty::People { people, .. } = property else {
+            continue;
+        };
+        if people.is_empty() {
+            continue;
+        }
+        let people = people
+            .iter()
+            .map(|user| Person {
+                id: user.id.clone(),
+                name: user.name.clone().filter(|name| !name.trim().is_empty()),
+            })
+            .collect();
+        properties.insert(name.clone(), PropertyValue::People(people));
+    }
+}
+
+/// ID of the database the page belongs to, when it is a database row.
+pub fn parent_database_id(page: &NotionPage) -> Option<&str> {
+    match &page.parent {
+        Parent::DatabaseId { database_id } => Some(database_id),
+        _ => None,
+    }
+}
+
+/// Display formats of the number properties in a database schema, by
+/// property name.
+pub fn number_formats(database: &Database) -> HashMap<String, NumberFormat> {
+    database
+        .properties
+        .iter()
+        .filter_map(|(name, property)| match property {
+            DatabaseProperty::Number { number, .. } => Some((name.clone(), number.format.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replace number properties that have a display format in `formats` with
+/// the value as Notion displays it, see [`format_number`].
+pub fn format_numbers(
+    properties: &mut HashMap<String, PropertyValue>,
+    formats: &HashMap<String, NumberFormat>,
+) {
+    for (name, value) in properties.iter_mut() {
+        if let (PropertyValue::Number(number), Some(format)) = (&*value, formats.get(name)) {
+            *value = PropertyValue::String(format_number(*number, format));
+        }
+    }
+}
+
+/// Render a number the way Notion displays it in `format`: percentages are
+/// multiplied by 100 and suffixed with `%`, currencies are prefixed with
+/// their symbol and get thousands separators and two decimals (none for yen
+/// and won), and `number_with_commas` only adds the separators.
+pub fn format_number(value: f64, format: &NumberFormat) -> String {
+    let sign = if value < 0.0 { "-" } else { "" };
+    match format {
+        NumberFormat::Number => number_to_string(value),
+        NumberFormat::NumberWithCommas => {
+            format!("{sign}{}", group_thousands(&number_to_string(value.abs())))
+        }
+        // Rounded so that e.g. 0.07 reads 7% rather than 7.000000000000001%.
+        NumberFormat::Percent => format!("{}%", (value * 1e11).round() / 1e9),
+        currency => {
+            let (symbol, decimals) = currency_symbol(currency);
+            let amount = format!("{:.*}", decimals, value.abs());
+            format!("{sign}{symbol}{}", group_thousands(&amount))
+        }
+    }
+}
+
+/// Largest magnitude below which every whole `f64` is an exact integer.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Whole numbers as integers, without a decimal point, and everything else
+/// in Rust's shortest round-trip form.
+pub fn number_to_string(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < MAX_EXACT_INTEGER {
+        (value as i64).to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn serialize_number<S: serde::Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    if value.fract() == 0.0 && value.abs() < MAX_EXACT_INTEGER {
+        serializer.serialize_i64(*value as i64)
+    } else {
+        serializer.serialize_f64(*value)
+    }
+}
+
+/// Insert commas between thousands in the integer part of a plain decimal.
+fn group_thousands(number: &str) -> String {
+    let (integer, fraction) = match number.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (number, None),
+    };
+    let mut grouped = String::with_capacity(number.len() + integer.len() / 3);
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        grouped.push('.');
+        grouped.push_str(fraction);
+    }
+    grouped
+}
+
+/// Symbol and number of decimals for a currency format.
+fn currency_symbol(format: &NumberFormat) -> (&'static str, usize) {
+    match format {
+        NumberFormat::ArgentinePeso => ("ARS ", 2),
+        NumberFormat::Baht => ("฿", 2),
+        NumberFormat::AustralianDollar => ("A$", 2),
+        NumberFormat::CanadianDollar => ("CA$", 2),
+        NumberFormat::ChileanPeso => ("CLP ", 2),
+        NumberFormat::ColombianPeso => ("COP ", 2),
+        NumberFormat::DanishKrone => ("kr ", 2),
+        NumberFormat::Dirham => ("AED ", 2),
+        NumberFormat::Dollar => ("$", 2),
+        NumberFormat::Euro => ("€", 2),
+        NumberFormat::Forint => ("Ft ", 2),
+        NumberFormat::Franc => ("CHF ", 2),
+        NumberFormat::HongKongDollar => ("HK$", 2),
+        NumberFormat::Koruna => ("Kč ", 2),
+        NumberFormat::Krona => ("kr ", 2),
+        NumberFormat::Leu => ("lei ", 2),
+        NumberFormat::Lira => ("₺", 2),
+        NumberFormat::MexicanPeso => ("MX$", 2),
+        NumberFormat::NewTaiwanDollar => ("NT$", 2),
+        NumberFormat::NewZealandDollar => ("NZ$", 2),
+        NumberFormat::NorwegianKrone => ("kr ", 2),
+        NumberFormat::PhilippinePeso => ("₱", 2),
+        NumberFormat::Pound => ("£", 2),
+        NumberFormat::PeruvianSol => ("S/", 2),
+        NumberFormat::Rand => ("R", 2),
+        NumberFormat::Real => ("R$", 2),
+        NumberFormat::Ringgit => ("RM", 2),
+        NumberFormat::Riyal => ("SAR ", 2),
+        NumberFormat::Ruble => ("₽", 2),
+        NumberFormat::Rupee => ("₹", 2),
+        NumberFormat::Rupiah => ("Rp", 2),
+        NumberFormat::Shekel => ("₪", 2),
+        NumberFormat::SingaporeDollar => ("S$", 2),
+        NumberFormat::UruguayanPeso => ("$U", 2),
+        NumberFormat::Yen => ("¥", 0),
+        NumberFormat::Yuan => ("CN¥", 2),
+        NumberFormat::Won => ("₩", 0),
+        NumberFormat::Zloty => ("zł ", 2),
+        NumberFormat::Number | NumberFormat::NumberWithCommas | NumberFormat::Percent => ("", 2),
+    }
+}
+
+/// Add the page's `created_time` and `last_edited_time` to `properties`,
+/// leaving any existing property with the same name untouched.
+pub fn insert_timestamps(properties: &mut HashMap<String, PropertyValue>, page: &NotionPage) {
+    properties
+        .entry("created_time".to_string())
+        .or_insert(PropertyValue::DateTime(page.created_time));
+    properties
+        .entry("last_edited_time".to_string())
+        .or_insert(PropertyValue::DateTime(page.last_edited_time));
+}
+
+/// Plain-text title of a page, taken from its `title` property.
+///
+/// With `title_property`, the property of that name (matched
+/// case-insensitively) is used instead, for databases whose effective title
+/// lives in another column. Pages where it is missing or empty fall back to
+/// the `title` property.
+pub fn page_title(page: &NotionPage, title_property: Option<&str>) -> Option<String> {
+    let named = title_property.and_then(|name| {
+        let name = name.to_lowercase();
+        page.properties
+            .iter()
+            .find(|(key, _)| key.to_lowercase() == name)
+            .and_then(|(_, property)| property_to_value(property.clone()))
+            .map(|value| property_value_to_string(&value))
+            .filter(|title| !title.trim().is_empty())
+    });
+
+    named.or_else(|| {
+        page.properties
+            .values()
+            .find_map(|property| match property {
+                NotionPageProperty::Title { title, .. } => rich_text_to_string(title),
+                _ => None,
+            })
+    })
+}
+
+/// Naming convention for per-language copies of a property, such as
+/// `{name} ({lang})` for columns named `Title (EN)` and `Title (ZH)`.
+#[derive(Debug, Clone)]
+pub struct LocalizedNames {
+    pattern: Regex,
+}
+
+impl LocalizedNames {
+    /// Parse a template holding both `{name}` and `{lang}`. Language codes
+    /// are two or three letters with optional subtags, e.g. `zh-TW`.
+    pub fn new(template: &str) -> Option<Self> {
+        if !template.contains("{name}") || !template.contains("{lang}") {
+            return None;
+        }
+        let pattern = regex::escape(template)
+            .replace(r"\{name\}", "(?P<name>.+?)")
+            .replace(r"\{lang\}", "(?P<lang>[A-Za-z]{2,3}(?:-[A-Za-z0-9]+)*)");
+        let pattern = Regex::new(&format!("^{pattern}$")).ok()?;
+        Some(Self { pattern })
+    }
+
+    /// Split a property name into its base name and language code.
+    fn split<'a>(&self, key: &'a str) -> Option<(&'a str, &'a str)> {
+        let captures = self.pattern.captures(key)?;
+        Some((
+            captures.name("name")?.as_str(),
+            captures.name("lang")?.as_str(),
+        ))
+    }
+
+    /// Replace each group of per-language properties with a single property
+    /// under the base name, holding the copy in the first of `languages`
+    /// that is available. Groups with none of the languages are left as is.
+    pub fn localize(&self, properties: &mut HashMap<String, PropertyValue>, languages: &[String]) {
+        let mut groups: HashMap<String, (String, Vec<(String, String)>)> = HashMap::new();
+        for key in properties.keys() {
+            if let Some((name, lang)) = self.split(key) {
+                groups
+                    .entry(name.to_lowercase())
+                    .or_insert_with(|| (name.to_string(), Vec::new()))
+                    .1
+                    .push((lang.to_string(), key.clone()));
+            }
+        }
+
+        for (name, variants) in groups.into_values() {
+            let langs: Vec<&str> = variants.iter().map(|(lang, _)| lang.as_str()).collect();
+            let Some(chosen) = best_language(&langs, languages) else {
+                continue;
+            };
+            let value = properties.get(&variants[chosen].1).cloned();
+            for (_, key) in &variants {
+                properties.remove(key);
+            }
+            properties.retain(|key, _| !key.eq_ignore_ascii_case(&name));
+            if let Some(value) = value {
+                properties.insert(name, value);
+            }
+        }
+    }
+
+    /// Name of the per-language copy of the page's title property in the
+    /// first of `languages` that has one, for use as a title property.
+    pub fn title_property(&self, page: &NotionPage, languages: &[String]) -> Option<String> {
+        let title = page.properties.iter().find_map(|(key, property)| {
+            matches!(property, NotionPageProperty::Title { .. }).then_some(key.as_str())
+        })?;
+        let variants: Vec<(&str, &str)> = page
+            .properties
+            .keys()
+            .filter_map(|key| {
+                let (name, lang) = self.split(key)?;
+                name.eq_ignore_ascii_case(title)
+                    .then_some((lang, key.as_str()))
+            })
+            .collect();
+        let langs: Vec<&str> = variants.iter().map(|(lang, _)| *lang).collect();
+        let chosen = best_language(&langs, languages)?;
+        Some(variants[chosen].1.to_string())
+    }
+}
+
+/// Index into `available` of the best match for `preferred`, tried in order:
+/// an exact match (`zh-TW`) first, then one sharing the primary language
+/// (`zh`). Codes compare case-insensitively.
+fn best_language(available: &[&str], preferred: &[String]) -> Option<usize> {
+    let primary = |lang: &str| lang.split('-').next().unwrap_or(lang).to_lowercase();
+    preferred.iter().find_map(|wanted| {
+        available
+            .iter()
+            .position(|lang| lang.eq_ignore_ascii_case(wanted))
+            .or_else(|| {
+                available
+                    .iter()
+                    .position(|lang| primary(lang) == primary(wanted))
+            })
+    })
+}
+
+/// The page icon: the emoji itself, or the URL of an uploaded or external
+/// image.
+pub fn page_icon(page: &NotionPage) -> Option<String> {
+    match page.icon.clone()? {
+        Icon::Emoji(Emoji::Emoji { emoji }) => Some(emoji),
+        Icon::File(file) => Some(file_url(file)),
+    }
+}
+
+/// URL of the page cover image.
+pub fn page_cover(page: &NotionPage) -> Option<String> {
+    page.cover.clone().map(file_url)
+}
+
+fn file_url(file: File) -> String {
+    match file {
+        File::External { external } => external.url,
+        File::File { file } => file.url,
+    }
+}
+
+/// Filename-safe slug for a page: its slugified title, or a fallback when
+/// the title is empty or has no alphanumeric characters. `title_property`
+/// picks the title as in [`page_title`]. The fallback is the page ID, or
+/// `untitled_template` rendered by [`untitled_slug`] when one is given.
+pub fn page_slug(
+    page: &NotionPage,
+    title_property: Option<&str>,
+    untitled_template: Option<&str>,
+) -> String {
+    page_title(page, title_property)
+        .map(|title| slugify(&title))
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| match untitled_template {
+            Some(template) => untitled_slug(template, &page.id),
+            None => page.id.clone(),
+        })
+}
+
+/// Render a slug template for an untitled page. `{id}` is replaced with the
+/// page ID and `{short_id}` with the first 8 hex digits of it, so
+/// `untitled-{short_id}` gives `untitled-1a2b3c4d`. The result is slugified;
+/// templates that slugify to nothing fall back to the page ID.
+pub fn untitled_slug(template: &str, id: &str) -> String {
+    let short_id: String = id.chars().filter(|c| *c != '-').take(8).collect();
+    let slug = slugify(
+        &template
+            .replace("{short_id}", &short_id)
+            .replace("{id}", id),
+    );
+    if slug.is_empty() {
+        id.to_string()
+    } else {
+        slug
+    }
+}
+
+/// Lowercase `value`, turn whitespace into hyphens and drop everything that
+/// isn't alphanumeric. Unicode letters and digits are kept as is.
+pub fn slugify(value: &str) -> String {
+    let mut slug = String::new();
+    for c in value.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
 pub fn property_to_value(property: NotionPageProperty) -> Option<PropertyValue> {
     match property {
         NotionPageProperty::Title { title, .. } => {
@@ -64,9 +563,7 @@ pub fn property_to_value(property: NotionPageProperty) -> Option<PropertyValue>
         NotionPageProperty::PhoneNumber { phone_number, .. } => {
             phone_number.map(PropertyValue::String)
         }
-        NotionPageProperty::Date { date, .. } => {
-            date.and_then(date_to_datetime).map(PropertyValue::DateTime)
-        }
+        NotionPageProperty::Date { date, .. } => date.and_then(date_to_value),
         NotionPageProperty::CreatedTime { created_time, .. } => {
             Some(PropertyValue::DateTime(created_time))
         }
@@ -75,44 +572,423 @@ pub fn property_to_value(property: NotionPageProperty) -> Option<PropertyValue>
         } => last_edited_time.map(PropertyValue::DateTime),
         NotionPageProperty::People { people, .. } => {
             let names: Vec<String> = people.into_iter().filter_map(|user| user.name).collect();
-            (!names.is_empty()).then(|| PropertyValue::StringArray(names))
+            (!names.is_empty()).then_some(PropertyValue::StringArray(names))
+        }
+        NotionPageProperty::Relation { relation, .. } => {
+            let ids: Vec<String> = relation.into_iter().map(|item| item.id).collect();
+            (!ids.is_empty()).then_some(PropertyValue::StringArray(ids))
+        }
+        NotionPageProperty::Rollup { rollup, .. } => rollup.and_then(rollup_to_value),
+        NotionPageProperty::Formula { formula, .. } => formula.and_then(formula_to_value),
+        NotionPageProperty::UniqueID { unique_id, .. } => unique_id.and_then(unique_id_to_value),
+        NotionPageProperty::Verification { verification, .. } => verification.map(|verification| {
+            PropertyValue::String(
+                match verification.state {
+                    VerificationState::Verified => "verified",
+                    VerificationState::Unverified => "unverified",
+                }
+                .to_string(),
+            )
+        }),
+        // Notion-hosted file URLs are signed and expire after about an hour.
+        NotionPageProperty::Files { files, .. } => {
+            let urls: Vec<String> = files
+                .into_iter()
+                .map(|item| file_url(item.file))
+                .filter(|url| !url.is_empty())
+                .collect();
+            (!urls.is_empty()).then_some(PropertyValue::StringArray(urls))
+        }
+        NotionPageProperty::CreatedBy { created_by, .. } => {
+            Some(PropertyValue::String(user_display_name(created_by)))
+        }
+        NotionPageProperty::LastEditedBy { last_edited_by, .. } => {
+            Some(PropertyValue::String(user_display_name(last_edited_by)))
         }
         _ => None,
     }
 }
 
-pub fn apply_frontmatter(properties: &HashMap<String, PropertyValue>, markdown: &str) -> String {
+/// Syntax used for the frontmatter block prepended to rendered pages.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontmatterFormat {
+    /// `---` fenced YAML, understood by most static site generators.
+    #[default]
+    Yaml,
+    /// `+++` fenced TOML, as used by Zola and Hugo.
+    Toml,
+    /// A bare JSON object, as supported by Hugo.
+    Json,
+}
+
+/// Line closing a YAML frontmatter block. TOML and JSON frontmatter have a
+/// fixed syntax and ignore it.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClosingFence {
+    /// `---`, matching the opening fence.
+    #[default]
+    Dashes,
+    /// `...`, YAML's document end marker, which some parsers expect.
+    Dots,
+}
+
+/// Layout details of the frontmatter block, on top of its
+/// [`FrontmatterFormat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrontmatterStyle {
+    pub closing_fence: ClosingFence,
+    /// Whether a blank line separates the frontmatter from the content.
+    pub blank_line: bool,
+}
+
+impl Default for FrontmatterStyle {
+    fn default() -> Self {
+        Self {
+            closing_fence: ClosingFence::Dashes,
+            blank_line: true,
+        }
+    }
+}
+
+/// The formula's computed result, or `None` when it evaluated to nothing.
+fn formula_to_value(formula: FormulaPropertyValue) -> Option<PropertyValue> {
+    match formula {
+        FormulaPropertyValue::String { string } => string
+            .filter(|value| !value.is_empty())
+            .map(PropertyValue::String),
+        FormulaPropertyValue::Number { number } => number
+            .and_then(|value| value.as_f64())
+            .map(PropertyValue::Number),
+        FormulaPropertyValue::Boolean { boolean } => Some(PropertyValue::Boolean(boolean)),
+        FormulaPropertyValue::Date { date } => date.and_then(date_to_value),
+    }
+}
+
+/// Ticket-style identifier such as `TASK-42`, or just `42` without a prefix.
+fn unique_id_to_value(unique_id: UniqueIDPropertyValue) -> Option<PropertyValue> {
+    let number = unique_id.number?;
+    let id = match unique_id.prefix.filter(|prefix| !prefix.is_empty()) {
+        Some(prefix) => format!("{prefix}-{number}"),
+        None => number.to_string(),
+    };
+    Some(PropertyValue::String(id))
+}
+
+/// When the verification of a wiki page lapses; `None` for unverified pages
+/// and verifications that don't expire.
+fn verification_expiry(verification: &VerificationPropertyValue) -> Option<PropertyValue> {
+    if verification.state != VerificationState::Verified {
+        return None;
+    }
+    let end = verification.date.as_ref()?.end.clone()?;
+    Some(date_or_datetime_to_value(end))
+}
+
+fn rollup_to_value(rollup: RollupPropertyValue) -> Option<PropertyValue> {
+    match rollup {
+        RollupPropertyValue::Number { number, .. } => number
+            .and_then(|value| value.as_f64())
+            .map(PropertyValue::Number),
+        RollupPropertyValue::Date { date, .. } => date.map(PropertyValue::DateTime),
+        RollupPropertyValue::Array { array, .. } => {
+            let mut values = Vec::new();
+            for item in array {
+                match property_to_value(item) {
+                    Some(PropertyValue::StringArray(items)) => values.extend(items),
+                    Some(value) => values.push(property_value_to_string(&value)),
+                    None => {}
+                }
+            }
+            (!values.is_empty()).then_some(PropertyValue::StringArray(values))
+        }
+        RollupPropertyValue::Incomplete { .. } | RollupPropertyValue::Unsupported { .. } => None,
+    }
+}
+
+/// Keep only the properties named in `included`. Names are matched
+/// case-insensitively since they are typed by hand.
+pub fn include_properties(properties: &mut HashMap<String, PropertyValue>, included: &[String]) {
+    properties.retain(|key, _| names_contain(included, key));
+}
+
+/// Drop the properties named in `excluded`, such as internal formula or ID
+/// columns that shouldn't be published. Names are matched case-insensitively.
+pub fn exclude_properties(properties: &mut HashMap<String, PropertyValue>, excluded: &[String]) {
+    properties.retain(|key, _| !names_contain(excluded, key));
+}
+
+/// Whether the property `name`, matched case-insensitively, is a checkbox
+/// (or boolean formula) that is checked. Missing properties count as
+/// unchecked.
+pub fn property_is_true(properties: &HashMap<String, PropertyValue>, name: &str) -> bool {
+    let name = name.to_lowercase();
+    properties.iter().any(|(key, value)| {
+        key.to_lowercase() == name && matches!(value, PropertyValue::Boolean(true))
+    })
+}
+
+fn names_contain(names: &[String], key: &str) -> bool {
+    let key = key.to_lowercase();
+    names.iter().any(|name| name.to_lowercase() == key)
+}
+
+/// Rename properties whose names appear in `aliases`, e.g. `Published At` to
+/// `date`, so frontmatter matches what a site generator expects. Other
+/// properties keep their names. A renamed property replaces any property
+/// that already had the new name.
+pub fn alias_properties(
+    properties: HashMap<String, PropertyValue>,
+    aliases: &HashMap<String, String>,
+) -> HashMap<String, PropertyValue> {
+    if aliases.is_empty() {
+        return properties;
+    }
+
+    let (renamed, kept): (Vec<_>, Vec<_>) = properties
+        .into_iter()
+        .partition(|(key, _)| aliases.contains_key(key));
+    let mut properties: HashMap<_, _> = kept.into_iter().collect();
+    for (key, value) in renamed {
+        properties.insert(aliases[&key].clone(), value);
+    }
+    properties
+}
+
+/// Prepend `properties` to `markdown` as frontmatter. See
+/// [`render_frontmatter`] for how keys are ordered.
+pub fn apply_frontmatter(
+    properties: &HashMap<String, PropertyValue>,
+    markdown: &str,
+    format: FrontmatterFormat,
+    key_order: &[String],
+    style: FrontmatterStyle,
+) -> String {
     if properties.is_empty() {
         return markdown.to_string();
     }
 
+    let mut frontmatter = render_frontmatter(properties, format, key_order, style.closing_fence);
+    if style.blank_line {
+        frontmatter.push('\n');
+    }
+    frontmatter.push_str(markdown);
+    frontmatter
+}
+
+/// Render `properties` as a frontmatter block. Keys listed in `key_order`
+/// come first, in that order; all other keys follow alphabetically.
+pub fn render_frontmatter(
+    properties: &HashMap<String, PropertyValue>,
+    format: FrontmatterFormat,
+    key_order: &[String],
+    closing_fence: ClosingFence,
+) -> String {
+    let rank = |key: &str| {
+        key_order
+            .iter()
+            .position(|preferred| preferred == key)
+            .unwrap_or(key_order.len())
+    };
     let mut entries: Vec<_> = properties.iter().collect();
-    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries.sort_by(|a, b| rank(a.0).cmp(&rank(b.0)).then_with(|| a.0.cmp(b.0)));
 
+    match format {
+        FrontmatterFormat::Yaml => render_yaml_frontmatter(&entries, closing_fence),
+        FrontmatterFormat::Toml => render_toml_frontmatter(&entries),
+        FrontmatterFormat::Json => render_json_frontmatter(&entries),
+    }
+}
+
+fn render_yaml_frontmatter(
+    entries: &[(&String, &PropertyValue)],
+    closing_fence: ClosingFence,
+) -> String {
     let mut frontmatter = String::from("---\n");
     for (key, value) in entries {
-        let rendered = property_value_to_string(value);
-        let escaped = rendered
-            .replace('\\', "\\\\")
-            .replace('\n', "\\n")
-            .replace('"', "\\\"");
-        frontmatter.push_str(&format!("{key}: \"{escaped}\"\n"));
-    }
-    frontmatter.push_str("---\n\n");
-    frontmatter.push_str(markdown);
+        let key = yaml_key(key);
+        match value {
+            PropertyValue::StringArray(values) if values.is_empty() => {
+                frontmatter.push_str(&format!("{key}: []\n"));
+            }
+            PropertyValue::StringArray(values) => {
+                frontmatter.push_str(&format!("{key}:\n"));
+                for item in values {
+                    let escaped = escape_yaml_string(item);
+                    frontmatter.push_str(&format!("  - \"{escaped}\"\n"));
+                }
+            }
+            PropertyValue::People(people) => {
+                frontmatter.push_str(&format!("{key}:\n"));
+                for person in people {
+                    let id = escape_yaml_string(&person.id);
+                    frontmatter.push_str(&format!("  - id: \"{id}\"\n"));
+                    match &person.name {
+                        Some(name) => frontmatter
+                            .push_str(&format!("    name: \"{}\"\n", escape_yaml_string(name))),
+                        None => frontmatter.push_str("    name: null\n"),
+                    }
+                }
+            }
+            _ => {
+                let escaped = escape_yaml_string(&property_value_to_string(value));
+                frontmatter.push_str(&format!("{key}: \"{escaped}\"\n"));
+            }
+        }
+    }
+    frontmatter.push_str(match closing_fence {
+        ClosingFence::Dashes => "---\n",
+        ClosingFence::Dots => "...\n",
+    });
     frontmatter
 }
 
+fn render_toml_frontmatter(entries: &[(&String, &PropertyValue)]) -> String {
+    let mut frontmatter = String::from("+++\n");
+    for (key, value) in entries {
+        let key = toml_key(key);
+        let rendered = match value {
+            PropertyValue::Number(value) => number_to_string(*value),
+            PropertyValue::Boolean(value) => value.to_string(),
+            PropertyValue::StringArray(values) => {
+                let items: Vec<String> = values
+                    .iter()
+                    .map(|item| format!("\"{}\"", escape_toml_string(item)))
+                    .collect();
+                format!("[{}]", items.join(", "))
+            }
+            // TOML has no null, so nameless users only get their ID.
+            PropertyValue::People(people) => {
+                let items: Vec<String> = people
+                    .iter()
+                    .map(|person| match &person.name {
+                        Some(name) => format!(
+                            "{{ id = \"{}\", name = \"{}\" }}",
+                            escape_toml_string(&person.id),
+                            escape_toml_string(name)
+                        ),
+                        None => format!("{{ id = \"{}\" }}", escape_toml_string(&person.id)),
+                    })
+                    .collect();
+                format!("[{}]", items.join(", "))
+            }
+            PropertyValue::String(_) | PropertyValue::Date(_) | PropertyValue::DateTime(_) => {
+                format!(
+                    "\"{}\"",
+                    escape_toml_string(&property_value_to_string(value))
+                )
+            }
+        };
+        frontmatter.push_str(&format!("{key} = {rendered}\n"));
+    }
+    frontmatter.push_str("+++\n");
+    frontmatter
+}
+
+fn render_json_frontmatter(entries: &[(&String, &PropertyValue)]) -> String {
+    if entries.is_empty() {
+        return "{}\n".to_string();
+    }
+
+    // Written by hand rather than through `serde_json::Map`, which would
+    // sort the keys again. The layout matches `to_string_pretty`.
+    let fields: Vec<String> = entries
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                PropertyValue::Date(_) | PropertyValue::DateTime(_) => {
+                    serde_json::Value::String(property_value_to_string(value))
+                }
+                other => serde_json::to_value(other).unwrap_or(serde_json::Value::Null),
+            };
+            let key = serde_json::Value::String(key.to_string());
+            let value = serde_json::to_string_pretty(&value)
+                .unwrap_or_else(|_| "null".into())
+                .replace('\n', "\n  ");
+            format!("  {key}: {value}")
+        })
+        .collect();
+
+    format!("{{\n{}\n}}\n", fields.join(",\n"))
+}
+
+/// Property names are free text in Notion, so any key that YAML would not
+/// read back as the same plain string is double-quoted.
+fn yaml_key(key: &str) -> String {
+    const INDICATORS: &[char] = &[
+        '-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%', '@',
+        '`',
+    ];
+    const RESERVED: &[&str] = &["true", "false", "yes", "no", "on", "off", "null", "~"];
+
+    let plain = !key.is_empty()
+        && key.trim() == key
+        && !key.starts_with(INDICATORS)
+        && !key.contains([':', '#'])
+        && !key.chars().any(char::is_control)
+        && !RESERVED.contains(&key.to_ascii_lowercase().as_str())
+        && key.parse::<f64>().is_err();
+    if plain {
+        key.to_string()
+    } else {
+        format!("\"{}\"", escape_yaml_string(key))
+    }
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        format!("\"{}\"", escape_toml_string(key))
+    }
+}
+
+fn escape_toml_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t")
+        .replace('"', "\\\"")
+}
+
+fn escape_yaml_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t")
+        .replace('"', "\\\"")
+}
+
 pub fn property_value_to_string(value: &PropertyValue) -> String {
     match value {
         PropertyValue::String(value) => value.clone(),
-        PropertyValue::Number(value) => value.to_string(),
+        PropertyValue::Number(value) => number_to_string(*value),
         PropertyValue::Boolean(value) => value.to_string(),
         PropertyValue::StringArray(values) => values.join(", "),
+        PropertyValue::People(people) => people
+            .iter()
+            .map(|person| person.name.as_deref().unwrap_or(&person.id))
+            .collect::<Vec<_>>()
+            .join(", "),
+        PropertyValue::Date(value) => value.format("%Y-%m-%d").to_string(),
         PropertyValue::DateTime(value) => value.to_rfc3339(),
     }
 }
 
+/// The user's name, or their ID when the integration can't see the name.
+fn user_display_name(user: User) -> String {
+    user.name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or(user.id)
+}
+
 pub fn rich_text_to_string(text: &[RichText]) -> Option<String> {
     let combined = text
         .iter()
@@ -127,15 +1003,892 @@ pub fn rich_text_to_string(text: &[RichText]) -> Option<String> {
     }
 }
 
-pub fn date_to_datetime(date: DatePropertyValue) -> Option<DateTime<Utc>> {
-    date.start.and_then(date_or_datetime_to_datetime)
+pub fn date_to_value(date: DatePropertyValue) -> Option<PropertyValue> {
+    date.start.map(date_or_datetime_to_value)
 }
 
-pub fn date_or_datetime_to_datetime(date: DateOrDateTime) -> Option<DateTime<Utc>> {
+/// Keep date-only values as dates so they render without a time component.
+pub fn date_or_datetime_to_value(date: DateOrDateTime) -> PropertyValue {
     match date {
-        DateOrDateTime::Date(date) => date
-            .and_hms_opt(0, 0, 0)
-            .map(|dt| DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc)),
-        DateOrDateTime::DateTime(date_time) => Some(date_time),
+        DateOrDateTime::Date(date) => PropertyValue::Date(date),
+        DateOrDateTime::DateTime(date_time) => PropertyValue::DateTime(date_time),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notion_client::objects::page::RelationPropertyValue;
+
+    use super::*;
+
+    /// Split the YAML between the frontmatter fences off `document`.
+    fn frontmatter_yaml(document: &str) -> &str {
+        let body = document.strip_prefix("---\n").expect("opening fence");
+        let end = body.find("\n---\n").expect("closing fence");
+        &body[..end]
+    }
+
+    #[test]
+    fn page_size_is_clamped_to_notion_limits() {
+        assert_eq!(clamp_page_size(200), 100);
+        assert_eq!(clamp_page_size(0), 1);
+        assert_eq!(clamp_page_size(1), 1);
+        assert_eq!(clamp_page_size(50), 50);
+        assert_eq!(clamp_page_size(100), 100);
+    }
+
+    #[test]
+    fn yaml_frontmatter_lists_round_trip() {
+        let tags = vec![
+            "rust".to_string(),
+            "key: value".to_string(),
+            "a, b".to_string(),
+        ];
+        let properties = HashMap::from([
+            ("tags".to_string(), PropertyValue::StringArray(tags.clone())),
+            (
+                "title".to_string(),
+                PropertyValue::String("Hello".to_string()),
+            ),
+        ]);
+
+        let document = apply_frontmatter(
+            &properties,
+            "# Hello\n",
+            FrontmatterFormat::Yaml,
+            &[],
+            FrontmatterStyle::default(),
+        );
+        assert!(document.ends_with("\n\n# Hello\n"));
+
+        let parsed: serde_yaml::Mapping =
+            serde_yaml::from_str(frontmatter_yaml(&document)).expect("valid YAML");
+        let parsed_tags: Vec<String> =
+            serde_yaml::from_value(parsed["tags"].clone()).expect("tags is a sequence");
+        assert_eq!(parsed_tags, tags);
+        assert_eq!(parsed["title"].as_str(), Some("Hello"));
+    }
+
+    #[test]
+    fn notion_ids_are_validated() {
+        let cases = [
+            ("1a2b3c4d5e6f70819a2b3c4d5e6f7081", true),
+            ("1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081", true),
+            ("1A2B3C4D5E6F70819A2B3C4D5E6F7081", true),
+            ("1A2B3C4D-5E6F-7081-9A2B-3C4D5E6F7081", true),
+            ("", false),
+            ("1a2b3c4d5e6f70819a2b3c4d5e6f708", false),
+            ("1a2b3c4d5e6f70819a2b3c4d5e6f70811", false),
+            ("1a2b3c4d5e6f70819a2b3c4d5e6f708g", false),
+            ("1a2b3c4d5-e6f-7081-9a2b-3c4d5e6f7081", false),
+            ("1a2b3c4d-5e6f-7081-9a2b3c4d5e6f7081", false),
+            ("../1a2b3c4d5e6f70819a2b3c4d5e6f70", false),
+            ("Roadmap-1a2b3c4d5e6f70819a2b3c4d5e6f7081", false),
+        ];
+        for (id, valid) in cases {
+            assert_eq!(is_valid_notion_id(id), valid, "{id:?}");
+        }
+    }
+
+    #[test]
+    fn notion_ids_are_normalized() {
+        let dashed = "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081";
+        let cases = [
+            ("1a2b3c4d5e6f70819a2b3c4d5e6f7081", dashed),
+            (dashed, dashed),
+            (
+                "1A2B3C4D5E6F70819A2B3C4D5E6F7081",
+                "1A2B3C4D-5E6F-7081-9A2B-3C4D5E6F7081",
+            ),
+            ("not-an-id", "not-an-id"),
+            ("", ""),
+        ];
+        for (id, expected) in cases {
+            assert_eq!(normalize_notion_id(id), expected, "{id:?}");
+        }
+        assert_eq!(normalize_notion_id(&dashed.replace('-', "")), dashed);
+    }
+
+    #[test]
+    fn page_ids_are_read_from_urls() {
+        let id = "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081";
+        let cases = [
+            ("https://www.notion.so/acme/Roadmap-1a2b3c4d5e6f70819a2b3c4d5e6f7081", Some(id)),
+            ("https://acme.notion.site/Roadmap-1a2b3c4d5e6f70819a2b3c4d5e6f7081", Some(id)),
+            ("https://www.notion.so/1a2b3c4d5e6f70819a2b3c4d5e6f7081", Some(id)),
+            ("https://www.notion.so/1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081/", Some(id)),
+            ("notion.so/Multi-Word-Title-1a2b3c4d5e6f70819a2b3c4d5e6f7081#heading", Some(id)),
+            (
+                "https://www.notion.so/acme/ffffffffffffffffffffffffffffffff?v=0123&p=1a2b3c4d5e6f70819a2b3c4d5e6f7081",
+                Some(id),
+            ),
+            (" 1a2b3c4d5e6f70819a2b3c4d5e6f7081 ", Some(id)),
+            ("https://www.notion.so/acme/Roadmap", None),
+            ("https://www.notion.so/acme/Roadmap1a2b3c4d5e6f70819a2b3c4d5e6f7081", None),
+            ("https://www.notion.so/acme/Roadmap-1a2b3c4d5e6f70819a2b3c4d5e6f708", None),
+            ("", None),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(page_id_from_url(url).as_deref(), expected, "{url:?}");
+        }
+    }
+
+    /// A page in a database with the given `properties`, as Notion returns it.
+    fn page(properties: serde_json::Value) -> NotionPage {
+        serde_json::from_value(serde_json::json!({
+            "object": "page",
+            "id": "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081",
+            "created_time": "2024-01-01T00:00:00.000Z",
+            "created_by": { "object": "user", "id": "user" },
+            "last_edited_time": "2024-01-02T00:00:00.000Z",
+            "last_edited_by": { "object": "user", "id": "user" },
+            "archived": false,
+            "icon": null,
+            "cover": null,
+            "properties": properties,
+            "parent": { "type": "database_id", "database_id": "ffffffff-ffff-ffff-ffff-ffffffffffff" },
+            "url": "https://www.notion.so/1a2b3c4d5e6f70819a2b3c4d5e6f7081",
+            "public_url": null,
+        }))
+        .expect("valid notion page")
+    }
+
+    fn titled_page(title: &str) -> NotionPage {
+        page(serde_json::json!({
+            "Name": {
+                "id": "title",
+                "type": "title",
+                "title": [{
+                    "type": "text",
+                    "text": { "content": title, "link": null },
+                    "annotations": {
+                        "bold": false,
+                        "italic": false,
+                        "strikethrough": false,
+                        "underline": false,
+                        "code": false,
+                        "color": "default",
+                    },
+                    "plain_text": title,
+                    "href": null,
+                }],
+            },
+        }))
+    }
+
+    #[test]
+    fn titles_are_slugified() {
+        let cases = [
+            ("Hello World", "hello-world"),
+            ("Hello, World!", "hello-world"),
+            ("  Rust -- 2024 edition  ", "rust-2024-edition"),
+            ("snake_case_title", "snake-case-title"),
+            ("Café Über", "café-über"),
+            ("東京 タワー", "東京-タワー"),
+            ("ÉCOLE", "école"),
+            ("🚀 Launch 🚀", "launch"),
+            ("?!", ""),
+            ("", ""),
+        ];
+        for (title, expected) in cases {
+            assert_eq!(slugify(title), expected, "{title:?}");
+        }
+    }
+
+    #[test]
+    fn untitled_pages_are_slugged_by_id() {
+        let id = "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081";
+        assert_eq!(
+            page_slug(&titled_page("Hello World"), None, None),
+            "hello-world"
+        );
+        assert_eq!(page_slug(&page(serde_json::json!({})), None, None), id);
+        // Titles made only of punctuation slugify to nothing.
+        assert_eq!(page_slug(&titled_page("?!"), None, None), id);
+        assert_eq!(
+            page_slug(&titled_page(""), None, Some("untitled-{short_id}")),
+            "untitled-1a2b3c4d"
+        );
+    }
+
+    #[test]
+    fn relations_keep_their_order() {
+        let relation = |ids: &[&str]| NotionPageProperty::Relation {
+            id: Some("rel".to_string()),
+            relation: ids
+                .iter()
+                .map(|id| RelationPropertyValue { id: id.to_string() })
+                .collect(),
+            has_more: Some(false),
+        };
+
+        let value = property_to_value(relation(&["page-b", "page-a"]));
+        assert!(matches!(
+            value,
+            Some(PropertyValue::StringArray(ids)) if ids == ["page-b", "page-a"]
+        ));
+        assert!(property_to_value(relation(&[])).is_none());
+    }
+
+    /// Map a page property, given as Notion returns it, to its value.
+    fn property(property: serde_json::Value) -> Option<PropertyValue> {
+        property_to_value(serde_json::from_value(property).expect("valid notion property"))
+    }
+
+    fn rendered(value: Option<PropertyValue>) -> Option<String> {
+        value.as_ref().map(property_value_to_string)
+    }
+
+    #[test]
+    fn dates_keep_their_precision() {
+        let date = |start: &str, end: Option<&str>, time_zone: Option<&str>| {
+            property(serde_json::json!({
+                "id": "date",
+                "type": "date",
+                "date": { "start": start, "end": end, "time_zone": time_zone },
+            }))
+        };
+
+        let day = date("2024-03-01", None, None);
+        assert!(matches!(day, Some(PropertyValue::Date(_))));
+        assert_eq!(rendered(day).as_deref(), Some("2024-03-01"));
+        // Ranges are represented by their start.
+        assert_eq!(
+            rendered(date("2024-03-01", Some("2024-03-05"), None)).as_deref(),
+            Some("2024-03-01")
+        );
+        // Times are converted to UTC, whatever zone they were entered in.
+        let time = date("2024-03-01T10:30:00.000+02:00", None, Some("Europe/Berlin"));
+        assert!(matches!(time, Some(PropertyValue::DateTime(_))));
+        assert_eq!(rendered(time).as_deref(), Some("2024-03-01T08:30:00+00:00"));
+
+        let empty = serde_json::json!({ "id": "date", "type": "date", "date": null });
+        assert!(property(empty).is_none());
+    }
+
+    #[test]
+    fn authors_are_named_or_identified() {
+        let created_by = serde_json::json!({
+            "id": "created",
+            "type": "created_by",
+            "created_by": { "object": "user", "id": "user-1", "name": "Ada Lovelace" },
+        });
+        assert_eq!(
+            rendered(property(created_by)).as_deref(),
+            Some("Ada Lovelace")
+        );
+
+        // Users the integration can't see come without a name.
+        let last_edited_by = serde_json::json!({
+            "id": "edited",
+            "type": "last_edited_by",
+            "last_edited_by": { "object": "user", "id": "user-2" },
+        });
+        assert_eq!(
+            rendered(property(last_edited_by)).as_deref(),
+            Some("user-2")
+        );
+    }
+
+    #[test]
+    fn files_are_listed_by_url() {
+        let files = serde_json::json!({
+            "id": "files",
+            "type": "files",
+            "files": [
+                {
+                    "name": "cover.png",
+                    "type": "file",
+                    "file": {
+                        "url": "https://files.notion.so/cover.png?signature=abc",
+                        "expiry_time": "2024-01-01T01:00:00.000Z",
+                    },
+                },
+                {
+                    "name": "diagram",
+                    "type": "external",
+                    "external": { "url": "https://example.com/diagram.svg" },
+                },
+            ],
+        });
+        assert!(matches!(
+            property(files),
+            Some(PropertyValue::StringArray(urls)) if urls == [
+                "https://files.notion.so/cover.png?signature=abc",
+                "https://example.com/diagram.svg",
+            ]
+        ));
+
+        let empty = serde_json::json!({ "id": "files", "type": "files", "files": [] });
+        assert!(property(empty).is_none());
+    }
+
+    #[test]
+    fn page_properties_take_precedence_over_timestamps() {
+        let mut properties = HashMap::from([(
+            "created_time".to_string(),
+            PropertyValue::String("2020".to_string()),
+        )]);
+        insert_timestamps(&mut properties, &page(serde_json::json!({})));
+
+        let created = properties.get("created_time").map(property_value_to_string);
+        assert_eq!(created.as_deref(), Some("2020"));
+        let edited = properties
+            .get("last_edited_time")
+            .map(property_value_to_string);
+        assert_eq!(edited.as_deref(), Some("2024-01-02T00:00:00+00:00"));
+    }
+
+    fn strings(pairs: &[(&str, &str)]) -> HashMap<String, PropertyValue> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), PropertyValue::String(value.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn frontmatter_keys_follow_the_configured_order() {
+        let properties = strings(&[
+            ("author", "Ada"),
+            ("date", "2024-03-01"),
+            ("tags", "rust"),
+            ("title", "Hello"),
+        ]);
+        let key_order = [
+            "title".to_string(),
+            "missing".to_string(),
+            "date".to_string(),
+        ];
+
+        let frontmatter = render_frontmatter(
+            &properties,
+            FrontmatterFormat::Yaml,
+            &key_order,
+            ClosingFence::Dashes,
+        );
+        assert_eq!(
+            frontmatter,
+            "---\ntitle: \"Hello\"\ndate: \"2024-03-01\"\nauthor: \"Ada\"\ntags: \"rust\"\n---\n"
+        );
+        // Without an order every key is sorted.
+        let sorted = render_frontmatter(
+            &properties,
+            FrontmatterFormat::Yaml,
+            &[],
+            ClosingFence::Dashes,
+        );
+        assert_eq!(
+            sorted,
+            "---\nauthor: \"Ada\"\ndate: \"2024-03-01\"\ntags: \"rust\"\ntitle: \"Hello\"\n---\n"
+        );
+    }
+
+    #[test]
+    fn yaml_keys_are_quoted_when_needed() {
+        let cases = [
+            ("title", "title"),
+            ("Published At", "Published At"),
+            ("key: value", "\"key: value\""),
+            (" padded", "\" padded\""),
+            ("yes", "\"yes\""),
+            ("42", "\"42\""),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(yaml_key(key), expected, "{key:?}");
+        }
+
+        let properties = strings(&[("key: value", "a"), (" padded", "b")]);
+        let frontmatter = render_frontmatter(
+            &properties,
+            FrontmatterFormat::Yaml,
+            &[],
+            ClosingFence::Dashes,
+        );
+        let parsed: serde_yaml::Mapping =
+            serde_yaml::from_str(frontmatter_yaml(&frontmatter)).expect("valid YAML");
+        assert_eq!(parsed["key: value"].as_str(), Some("a"));
+        assert_eq!(parsed[" padded"].as_str(), Some("b"));
+    }
+
+    #[test]
+    fn aliases_rename_only_mapped_properties() {
+        let properties = strings(&[("Published At", "2024-03-01"), ("Tags", "rust")]);
+        let aliases = HashMap::from([
+            ("Published At".to_string(), "date".to_string()),
+            ("Missing".to_string(), "unused".to_string()),
+        ]);
+
+        let aliased = alias_properties(properties, &aliases);
+        assert_eq!(sorted_keys(&aliased), ["Tags", "date"]);
+        assert_eq!(
+            aliased.get("date").map(property_value_to_string).as_deref(),
+            Some("2024-03-01")
+        );
+    }
+
+    fn sorted_keys(properties: &HashMap<String, PropertyValue>) -> Vec<&str> {
+        let mut keys: Vec<_> = properties.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn included_properties_are_kept() {
+        let mut properties = strings(&[("Title", "Hello"), ("Tags", "rust"), ("Internal ID", "7")]);
+        include_properties(&mut properties, &["title".to_string(), "TAGS".to_string()]);
+        assert_eq!(sorted_keys(&properties), ["Tags", "Title"]);
+    }
+
+    #[test]
+    fn excluded_properties_are_dropped() {
+        let all = || strings(&[("Title", "Hello"), ("Tags", "rust"), ("Internal ID", "7")]);
+
+        let mut properties = all();
+        exclude_properties(&mut properties, &["internal id".to_string()]);
+        assert_eq!(sorted_keys(&properties), ["Tags", "Title"]);
+
+        // Exclusions still apply to included properties.
+        let mut properties = all();
+        include_properties(&mut properties, &["title".to_string(), "tags".to_string()]);
+        exclude_properties(&mut properties, &["Tags".to_string()]);
+        assert_eq!(sorted_keys(&properties), ["Title"]);
+    }
+
+    #[test]
+    fn formulas_keep_their_result_type() {
+        let formula = |result: serde_json::Value| {
+            property(serde_json::json!({ "id": "f", "type": "formula", "formula": result }))
+        };
+
+        let string = formula(serde_json::json!({ "type": "string", "string": "draft" }));
+        assert!(matches!(string, Some(PropertyValue::String(_))));
+        assert_eq!(rendered(string).as_deref(), Some("draft"));
+        let number = formula(serde_json::json!({ "type": "number", "number": 2.5 }));
+        assert!(matches!(number, Some(PropertyValue::Number(_))));
+        assert_eq!(rendered(number).as_deref(), Some("2.5"));
+        let boolean = formula(serde_json::json!({ "type": "boolean", "boolean": true }));
+        assert!(matches!(boolean, Some(PropertyValue::Boolean(true))));
+        let date = formula(serde_json::json!({
+            "type": "date",
+            "date": { "start": "2024-03-01", "end": null, "time_zone": null },
+        }));
+        assert!(matches!(date, Some(PropertyValue::Date(_))));
+        assert_eq!(rendered(date).as_deref(), Some("2024-03-01"));
+
+        // Empty results are left out like empty properties.
+        assert!(formula(serde_json::json!({ "type": "string", "string": "" })).is_none());
+        assert!(formula(serde_json::json!({ "type": "number", "number": null })).is_none());
+        assert!(formula(serde_json::json!({ "type": "date", "date": null })).is_none());
+    }
+
+    #[test]
+    fn unique_ids_keep_their_prefix() {
+        let unique_id = |number: serde_json::Value, prefix: Option<&str>| {
+            property(serde_json::json!({
+                "id": "id",
+                "type": "unique_id",
+                "unique_id": { "number": number, "prefix": prefix },
+            }))
+        };
+
+        assert_eq!(
+            rendered(unique_id(serde_json::json!(42), Some("TASK"))).as_deref(),
+            Some("TASK-42")
+        );
+        assert_eq!(
+            rendered(unique_id(serde_json::json!(42), None)).as_deref(),
+            Some("42")
+        );
+        assert_eq!(
+            rendered(unique_id(serde_json::json!(42), Some(""))).as_deref(),
+            Some("42")
+        );
+        assert!(unique_id(serde_json::Value::Null, Some("TASK")).is_none());
+    }
+
+    #[test]
+    fn frontmatter_style_combinations_are_byte_exact() {
+        let properties = strings(&[("title", "Hello")]);
+        let cases = [
+            (
+                ClosingFence::Dashes,
+                true,
+                "---\ntitle: \"Hello\"\n---\n\n# Hello\n",
+            ),
+            (
+                ClosingFence::Dashes,
+                false,
+                "---\ntitle: \"Hello\"\n---\n# Hello\n",
+            ),
+            (
+                ClosingFence::Dots,
+                true,
+                "---\ntitle: \"Hello\"\n...\n\n# Hello\n",
+            ),
+            (
+                ClosingFence::Dots,
+                false,
+                "---\ntitle: \"Hello\"\n...\n# Hello\n",
+            ),
+        ];
+        for (closing_fence, blank_line, expected) in cases {
+            let style = FrontmatterStyle {
+                closing_fence,
+                blank_line,
+            };
+            let document = apply_frontmatter(
+                &properties,
+                "# Hello\n",
+                FrontmatterFormat::Yaml,
+                &[],
+                style,
+            );
+            assert_eq!(document, expected, "{style:?}");
+        }
+        assert_eq!(
+            FrontmatterStyle::default(),
+            FrontmatterStyle {
+                closing_fence: ClosingFence::Dashes,
+                blank_line: true,
+            }
+        );
+    }
+
+    #[test]
+    fn verifications_note_when_they_expire() {
+        let verification = |state: &str, end: Option<&str>| {
+            serde_json::json!({
+                "id": "verification",
+                "type": "verification",
+                "verification": {
+                    "state": state,
+                    "verified_by": { "object": "user", "id": "user-1" },
+                    "date": { "start": "2024-03-01", "end": end, "time_zone": null },
+                },
+            })
+        };
+        let properties = notion_page_to_properties(&page(serde_json::json!({
+            "Expiring": verification("verified", Some("2024-06-01")),
+            "Lasting": verification("verified", None),
+            "Unverified": verification("unverified", Some("2024-06-01")),
+            "Unset": { "id": "unset", "type": "verification", "verification": null },
+        })));
+        let value = |key: &str| properties.get(key).map(property_value_to_string);
+
+        assert_eq!(value("Expiring").as_deref(), Some("verified"));
+        assert_eq!(value("Expiring until").as_deref(), Some("2024-06-01"));
+        assert_eq!(value("Lasting").as_deref(), Some("verified"));
+        assert_eq!(value("Lasting until"), None);
+        assert_eq!(value("Unverified").as_deref(), Some("unverified"));
+        assert_eq!(value("Unverified until"), None);
+        assert_eq!(value("Unset"), None);
+        assert_eq!(properties.len(), 4);
+    }
+
+    #[test]
+    fn detailed_people_keep_nameless_users() {
+        let page = page(serde_json::json!({
+            "Authors": {
+                "id": "people",
+                "type": "people",
+                "people": [
+                    { "object": "user", "id": "user-1", "name": "Ada Lovelace" },
+                    { "object": "user", "id": "user-2" },
+                    { "object": "user", "id": "user-3", "name": " " },
+                ],
+            },
+        }));
+        let mut properties = notion_page_to_properties(&page);
+        let names = properties
+            .get("Authors")
+            .map(|value| serde_json::to_value(value).unwrap());
+        assert_eq!(names, Some(serde_json::json!(["Ada Lovelace", " "])));
+
+        detail_people(&mut properties, &page);
+        let people = properties
+            .get("Authors")
+            .map(|value| serde_json::to_value(value).unwrap());
+        assert_eq!(
+            people,
+            Some(serde_json::json!([
+                { "id": "user-1", "name": "Ada Lovelace" },
+                { "id": "user-2", "name": null },
+                { "id": "user-3", "name": null },
+            ]))
+        );
+
+        let frontmatter = render_frontmatter(
+            &properties,
+            FrontmatterFormat::Yaml,
+            &[],
+            ClosingFence::Dashes,
+        );
+        assert_eq!(
+            frontmatter,
+            "---\nAuthors:\n  - id: \"user-1\"\n    name: \"Ada Lovelace\"\n  - id: \"user-2\"\n    \
+             name: null\n  - id: \"user-3\"\n    name: null\n---\n"
+        );
+    }
+
+    #[test]
+    fn untitled_templates_expand_ids() {
+        let id = "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081";
+        let cases = [
+            ("untitled-{short_id}", "untitled-1a2b3c4d"),
+            ("Untitled {short_id}", "untitled-1a2b3c4d"),
+            ("{short_id}", "1a2b3c4d"),
+            ("page-{id}", "page-1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081"),
+            ("draft", "draft"),
+            // Templates that slugify to nothing fall back to the ID.
+            ("!!!", id),
+            ("", id),
+        ];
+        for (template, slug) in cases {
+            assert_eq!(untitled_slug(template, id), slug, "{template:?}");
+        }
+
+        // Titled pages ignore the template.
+        assert_eq!(
+            page_slug(&titled_page("Hello"), None, Some("untitled-{short_id}")),
+            "hello"
+        );
+        assert_eq!(
+            page_slug(&titled_page("?!"), None, Some("untitled-{short_id}")),
+            "untitled-1a2b3c4d"
+        );
+    }
+
+    #[test]
+    fn numbers_are_formatted_like_notion() {
+        let cases = [
+            (0.25, NumberFormat::Percent, "25%"),
+            (0.07, NumberFormat::Percent, "7%"),
+            (0.125, NumberFormat::Percent, "12.5%"),
+            (-0.5, NumberFormat::Percent, "-50%"),
+            (1234.5, NumberFormat::Number, "1234.5"),
+            (42.0, NumberFormat::Number, "42"),
+            (1234567.5, NumberFormat::NumberWithCommas, "1,234,567.5"),
+            (-1234.0, NumberFormat::NumberWithCommas, "-1,234"),
+            (999.0, NumberFormat::NumberWithCommas, "999"),
+            (1234.5, NumberFormat::Dollar, "$1,234.50"),
+            (-1234.5, NumberFormat::Euro, "-€1,234.50"),
+            (1234.0, NumberFormat::Yen, "¥1,234"),
+        ];
+        for (value, format, expected) in cases {
+            assert_eq!(
+                format_number(value, &format),
+                expected,
+                "{value} {format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_numbers_with_a_format_are_formatted() {
+        let mut properties = HashMap::from([
+            ("Ratio".to_string(), PropertyValue::Number(0.25)),
+            ("Count".to_string(), PropertyValue::Number(3.0)),
+            ("Name".to_string(), PropertyValue::String("0.5".to_string())),
+        ]);
+        let formats = HashMap::from([
+            ("Ratio".to_string(), NumberFormat::Percent),
+            ("Name".to_string(), NumberFormat::Percent),
+        ]);
+
+        format_numbers(&mut properties, &formats);
+        assert!(matches!(&properties["Ratio"], PropertyValue::String(value) if value == "25%"));
+        assert!(matches!(properties["Count"], PropertyValue::Number(_)));
+        assert!(matches!(&properties["Name"], PropertyValue::String(value) if value == "0.5"));
+    }
+
+    #[test]
+    fn whole_numbers_have_no_decimal_point() {
+        let cases = [
+            (42.0, "42"),
+            (-7.0, "-7"),
+            (0.0, "0"),
+            (2.5, "2.5"),
+            (-0.125, "-0.125"),
+            (1e20, "100000000000000000000"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(number_to_string(value), expected, "{value}");
+        }
+
+        let json = |value: f64| serde_json::to_string(&PropertyValue::Number(value)).unwrap();
+        assert_eq!(json(42.0), "42");
+        assert_eq!(json(2.5), "2.5");
+        assert_eq!(json(1e20), "1e20");
+    }
+
+    fn languages(codes: &[&str]) -> Vec<String> {
+        codes.iter().map(|code| code.to_string()).collect()
+    }
+
+    #[test]
+    fn localized_names_need_both_placeholders() {
+        assert!(LocalizedNames::new("{name} ({lang})").is_some());
+        assert!(LocalizedNames::new("{lang}_{name}").is_some());
+        assert!(LocalizedNames::new("{name}").is_none());
+        assert!(LocalizedNames::new("({lang})").is_none());
+    }
+
+    #[test]
+    fn languages_match_exactly_before_by_primary_subtag() {
+        let available = ["zh-CN", "zh-TW", "en"];
+        let best = |preferred: &[&str]| best_language(&available, &languages(preferred));
+
+        assert_eq!(best(&["zh-TW"]), Some(1));
+        assert_eq!(best(&["ZH-tw"]), Some(1));
+        assert_eq!(best(&["zh"]), Some(0));
+        assert_eq!(best(&["zh-HK"]), Some(0));
+        assert_eq!(best(&["en-US"]), Some(2));
+        assert_eq!(best(&["fr", "en"]), Some(2));
+        assert_eq!(best(&["fr", "de"]), None);
+        assert_eq!(best(&[]), None);
+    }
+
+    #[test]
+    fn localized_properties_take_the_preferred_language() {
+        let names = LocalizedNames::new("{name} ({lang})").expect("valid template");
+        let mut properties = strings(&[
+            ("Title (EN)", "Hello"),
+            ("Title (ZH)", "你好"),
+            ("Summary (en)", "A greeting"),
+            ("Summary (de)", "Ein Gruß"),
+            ("Status", "Done"),
+        ]);
+
+        names.localize(&mut properties, &languages(&["zh-TW", "en"]));
+
+        assert_eq!(sorted_keys(&properties), ["Status", "Summary", "Title"]);
+        assert_eq!(property_value_to_string(&properties["Title"]), "你好");
+        assert_eq!(
+            property_value_to_string(&properties["Summary"]),
+            "A greeting"
+        );
+        assert_eq!(property_value_to_string(&properties["Status"]), "Done");
+    }
+
+    #[test]
+    fn localized_properties_fall_back_through_the_languages() {
+        let names = LocalizedNames::new("{name} ({lang})").expect("valid template");
+        let mut properties = strings(&[("Title (EN)", "Hello"), ("Title (ZH)", "你好")]);
+
+        // The default language comes last, after the client's own.
+        names.localize(&mut properties, &languages(&["fr", "de", "en"]));
+        assert_eq!(sorted_keys(&properties), ["Title"]);
+        assert_eq!(property_value_to_string(&properties["Title"]), "Hello");
+
+        let mut properties = strings(&[("Title (EN)", "Hello"), ("Title (ZH)", "你好")]);
+        names.localize(&mut properties, &languages(&["fr"]));
+        assert_eq!(sorted_keys(&properties), ["Title (EN)", "Title (ZH)"]);
+    }
+
+    #[test]
+    fn localized_copies_replace_the_unlocalized_property() {
+        let names = LocalizedNames::new("{name} ({lang})").expect("valid template");
+        let mut properties = strings(&[("title", "Hello"), ("Title (ZH)", "你好")]);
+
+        names.localize(&mut properties, &languages(&["zh"]));
+
+        assert_eq!(sorted_keys(&properties), ["Title"]);
+        assert_eq!(property_value_to_string(&properties["Title"]), "你好");
+    }
+
+    /// A `title` or `rich_text` property holding `content`.
+    fn text(kind: &str, content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": content,
+            "type": kind,
+            kind: [{
+                "type": "text",
+                "text": { "content": content, "link": null },
+                "annotations": {
+                    "bold": false,
+                    "italic": false,
+                    "strikethrough": false,
+                    "underline": false,
+                    "code": false,
+                    "color": "default",
+                },
+                "plain_text": content,
+                "href": null,
+            }],
+        })
+    }
+
+    #[test]
+    fn titles_come_from_the_localized_copy() {
+        let page = page(serde_json::json!({
+            "Name": text("title", "Hello"),
+            "Name (EN)": text("rich_text", "Hello there"),
+            "Name (ZH)": text("rich_text", "你好"),
+        }));
+        let names = LocalizedNames::new("{name} ({lang})").expect("valid template");
+
+        let title = names.title_property(&page, &languages(&["zh-CN", "en"]));
+        assert_eq!(title.as_deref(), Some("Name (ZH)"));
+        assert_eq!(page_title(&page, title.as_deref()).as_deref(), Some("你好"));
+        assert_eq!(
+            names
+                .title_property(&page, &languages(&["de", "en"]))
+                .as_deref(),
+            Some("Name (EN)")
+        );
+        assert_eq!(names.title_property(&page, &languages(&["de"])), None);
+    }
+
+    #[test]
+    fn icons_and_covers_are_read_from_the_page() {
+        let mut page = page(serde_json::json!({}));
+        assert_eq!(page_icon(&page), None);
+        assert_eq!(page_cover(&page), None);
+
+        page.icon = serde_json::from_value(serde_json::json!({ "type": "emoji", "emoji": "🚀" }))
+            .expect("valid icon");
+        page.cover = serde_json::from_value(serde_json::json!({
+            "type": "external",
+            "external": { "url": "https://example.com/cover.png" },
+        }))
+        .expect("valid cover");
+        assert_eq!(page_icon(&page).as_deref(), Some("🚀"));
+        assert_eq!(
+            page_cover(&page).as_deref(),
+            Some("https://example.com/cover.png")
+        );
+
+        page.icon = serde_json::from_value(serde_json::json!({
+            "type": "external",
+            "external": { "url": "https://example.com/icon.png" },
+        }))
+        .expect("valid icon");
+        assert_eq!(
+            page_icon(&page).as_deref(),
+            Some("https://example.com/icon.png")
+        );
+    }
+
+    #[test]
+    fn titles_come_from_the_named_property_or_the_title() {
+        let titled = page(serde_json::json!({
+            "Name": text("title", "Hello"),
+            "Headline": text("rich_text", "Hello there"),
+            "Blank": text("rich_text", " "),
+        }));
+        assert_eq!(page_title(&titled, None).as_deref(), Some("Hello"));
+        assert_eq!(
+            page_title(&titled, Some("headline")).as_deref(),
+            Some("Hello there")
+        );
+        assert_eq!(
+            page_title(&titled, Some("Missing")).as_deref(),
+            Some("Hello")
+        );
+        assert_eq!(page_title(&titled, Some("Blank")).as_deref(), Some("Hello"));
+
+        let untitled = page(serde_json::json!({ "Headline": text("rich_text", "Hello there") }));
+        assert_eq!(page_title(&untitled, None), None);
+        assert_eq!(page_title(&untitled, Some("Missing")), None);
     }
 }
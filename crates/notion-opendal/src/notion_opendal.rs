@@ -1,28 +1,182 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::error;
-use notion2md::builder::NotionToMarkdownBuilder;
+use log::{error, warn};
+use notion_client::endpoints::blocks::append::request::AppendBlockChildrenRequest;
 use notion_client::endpoints::databases::query::request::QueryDatabaseRequest;
+use notion_client::endpoints::pages::update::request::UpdatePagePropertiesRequest;
 use notion_client::endpoints::Client as NotionClient;
+use notion_client::objects::block::{Block, BlockType, ParagraphValue};
+use notion_client::objects::page::Page as NotionPage;
+use notion_client::objects::rich_text::{RichText, Text};
 use notion_client::NotionClientError;
 use opendal::raw::oio;
-use opendal::raw::{Access, AccessorInfo, OpList, OpRead, OpStat, RpList, RpRead, RpStat};
+use opendal::raw::{
+    Access, AccessorInfo, BytesRange, OpDelete, OpList, OpRead, OpStat, OpWrite, RpDelete, RpList,
+    RpRead, RpStat, RpWrite,
+};
 use opendal::{
     Buffer, Builder, Capability, Configurator, EntryMode, Error, ErrorKind, Metadata, Result,
 };
+use tokio::time::timeout;
+
+use crate::markdown::{
+    linked_page_ids, rewrite_page_links, strip_comments, CalloutStyle, LinkMode, RenderOptions,
+};
+use crate::notion::{
+    alias_properties, apply_frontmatter, clamp_page_size, detail_people, exclude_properties,
+    format_numbers, include_properties, insert_timestamps, is_valid_notion_id, new_notion_client,
+    normalize_notion_id, notion_page_to_properties, number_formats, page_cover, page_icon,
+    page_slug, parent_database_id, ClosingFence, FrontmatterFormat, FrontmatterStyle,
+    PropertyValue, DEFAULT_USER_AGENT, MAX_PAGE_SIZE,
+};
+use crate::retry::{retry_transient, DEFAULT_MAX_RETRIES};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// Notion rejects rich text items longer than this many characters.
+const MAX_RICH_TEXT_CHARS: usize = 2000;
+/// Notion accepts at most this many children per append request.
+const MAX_APPEND_BLOCKS: usize = 100;
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Shape of the content returned when reading a page.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentMode {
+    /// The rendered Markdown, with frontmatter when enabled.
+    #[default]
+    Markdown,
+    /// A JSON document with the page `id`, its `properties` and the rendered
+    /// Markdown as `content`, like the server's JSON page response.
+    Json,
+}
+
+/// The document read in [`ContentMode::Json`], shaped like the server's JSON
+/// page response.
+#[derive(serde::Serialize)]
+struct PageDocument<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<String>,
+    properties: &'a HashMap<String, PropertyValue>,
+    content: &'a str,
+}
 
-use crate::notion::{apply_frontmatter, notion_page_to_properties};
+impl ContentMode {
+    fn content_type(self) -> &'static str {
+        match self {
+            ContentMode::Markdown => "text/markdown",
+            ContentMode::Json => "application/json",
+        }
+    }
+}
 
-/// Config for the Notion read-only service.
+/// Config for the Notion service.
+///
+/// Pages are read as Markdown. Writing `{id}.md` appends the written Markdown
+/// to the page; only plain paragraph blocks are supported for now, so
+/// headings, lists and other Markdown syntax are kept as literal text.
+/// Deleting `{id}.md` archives the page.
+///
+/// Listing the root returns the database pages; listing `{page_id}/` returns
+/// that page's child pages, each as a `{child_id}.md` file plus a
+/// `{child_id}/` directory when it has content of its own. With several
+/// databases configured, the root lists a `{database_id}/` directory per
+/// database instead, and listing one of those returns its pages.
+///
+/// Fields missing from a deserialized config take their defaults, so a
+/// config file only needs the settings it changes; see
+/// [`NotionServiceBuilder::from_config_file`].
 #[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct NotionConfig {
     /// Notion integration token.
     pub token: Option<String>,
     /// Default database id to list pages from.
     pub database_id: Option<String>,
+    /// More databases to list pages from, each as its own directory. Adds to
+    /// `database_id` when both are set.
+    pub database_ids: Vec<String>,
     /// Whether to prepend properties as frontmatter when reading.
     pub frontmatter: bool,
+    /// Whether to add a `slug` key derived from the page title to the
+    /// frontmatter.
+    pub slug: bool,
+    /// Whether to add the page's `created_time` and `last_edited_time` to the
+    /// properties.
+    pub timestamps: bool,
+    /// Timeout in seconds for each Notion call, including rate-limit
+    /// retries. Defaults to 30 seconds.
+    pub timeout_secs: Option<u64>,
+    /// How many times a Notion call is retried after a 429 or 5xx response.
+    /// Defaults to 3.
+    pub max_retries: Option<u32>,
+    /// Whether reads return Markdown or a JSON document. Paths keep their
+    /// `.md` extension either way.
+    pub content_mode: ContentMode,
+    /// Whether list names database pages `{slugified title}.md` instead of
+    /// `{page_id}.md`.
+    pub use_title_filenames: bool,
+    /// Whether code fences are tagged with highlighter-friendly language
+    /// names (`cpp`, `csharp`) instead of notion2md's defaults.
+    pub code_lang: bool,
+    /// How callout blocks are written in page content.
+    pub callouts: CalloutStyle,
+    /// How links to other Notion pages are written in page content.
+    pub link_mode: LinkMode,
+    /// Whether `<!-- -->` and `%% %%` comments are removed from page
+    /// content.
+    pub strip_comments: bool,
+    /// Frontmatter keys written first, in this order; the rest follow
+    /// alphabetically.
+    pub frontmatter_key_order: Vec<String>,
+    /// Frontmatter key renames, from property name to the key written.
+    pub frontmatter_aliases: HashMap<String, String>,
+    /// Property names to keep in frontmatter and JSON content; `None` keeps
+    /// all of them.
+    pub include_properties: Option<Vec<String>>,
+    /// Property names left out of frontmatter and JSON content.
+    pub exclude_properties: Vec<String>,
+    /// Path prefix every path must start with, e.g. `notion/`. Defaults to
+    /// the top level.
+    pub root: Option<String>,
+    /// Largest chunk in bytes a reader yields at once. Defaults to 64 KiB.
+    pub chunk_size: Option<usize>,
+    /// Whether stat renders the page to report its exact content length.
+    pub stat_exact_size: bool,
+    /// How many pages each database query asks Notion for, clamped to
+    /// `1..=100`. Defaults to 100.
+    pub page_size: Option<u32>,
+    /// Property used as the page title for slugs and file names instead of
+    /// the `title` property.
+    pub title_property: Option<String>,
+    /// Slug template for untitled pages, e.g. `untitled-{short_id}`. Untitled
+    /// pages are named by their ID when unset.
+    pub untitled_template: Option<String>,
+    /// Line closing YAML frontmatter.
+    pub frontmatter_closing_fence: ClosingFence,
+    /// Whether a blank line follows the frontmatter. Defaults to true.
+    pub frontmatter_blank_line: Option<bool>,
+    /// Whether people properties list `{ id, name }` objects instead of
+    /// names.
+    pub people_detailed: bool,
+    /// Whether number properties are rendered in their database's display
+    /// format, such as `25%` or `$1,000.00`.
+    pub format_numbers: bool,
+    /// Largest rendered page, in bytes, before reads fail. Unlimited when
+    /// unset.
+    pub max_markdown_bytes: Option<usize>,
+    /// Whether archived and trashed pages can be read. Notion still returns
+    /// them by ID, but they are treated as missing unless this is set.
+    pub include_archived: bool,
+    /// `User-Agent` sent with every Notion request. Defaults to
+    /// `notion-opendal/{version}`.
+    pub user_agent: Option<String>,
 }
 
 impl Configurator for NotionConfig {
@@ -44,12 +198,81 @@ impl Debug for NotionServiceBuilder {
         f.debug_struct("NotionServiceBuilder")
             .field("has_token", &self.config.token.as_ref().map(|_| "***"))
             .field("database_id", &self.config.database_id)
+            .field("database_ids", &self.config.database_ids)
             .field("frontmatter", &self.config.frontmatter)
+            .field("slug", &self.config.slug)
+            .field("timestamps", &self.config.timestamps)
+            .field("timeout_secs", &self.config.timeout_secs)
+            .field("max_retries", &self.config.max_retries)
+            .field("use_title_filenames", &self.config.use_title_filenames)
+            .field("content_mode", &self.config.content_mode)
+            .field("code_lang", &self.config.code_lang)
+            .field("callouts", &self.config.callouts)
+            .field("link_mode", &self.config.link_mode)
+            .field("strip_comments", &self.config.strip_comments)
+            .field("frontmatter_key_order", &self.config.frontmatter_key_order)
+            .field("frontmatter_aliases", &self.config.frontmatter_aliases)
+            .field("include_properties", &self.config.include_properties)
+            .field("exclude_properties", &self.config.exclude_properties)
+            .field("root", &self.config.root)
+            .field("chunk_size", &self.config.chunk_size)
+            .field("stat_exact_size", &self.config.stat_exact_size)
+            .field("page_size", &self.config.page_size)
+            .field("title_property", &self.config.title_property)
+            .field("untitled_template", &self.config.untitled_template)
+            .field(
+                "frontmatter_closing_fence",
+                &self.config.frontmatter_closing_fence,
+            )
+            .field(
+                "frontmatter_blank_line",
+                &self.config.frontmatter_blank_line,
+            )
+            .field("people_detailed", &self.config.people_detailed)
+            .field("format_numbers", &self.config.format_numbers)
+            .field("max_markdown_bytes", &self.config.max_markdown_bytes)
+            .field("include_archived", &self.config.include_archived)
+            .field("user_agent", &self.config.user_agent)
             .finish()
     }
 }
 
 impl NotionServiceBuilder {
+    /// Load the config from a TOML file whose keys are the [`NotionConfig`]
+    /// field names:
+    ///
+    /// ```toml
+    /// token = "${NOTION_API_TOKEN}"
+    /// database_id = "1a2b3c4d5e6f70819a2b3c4d5e6f7081"
+    /// frontmatter = true
+    /// link_mode = "slug"
+    /// ```
+    ///
+    /// `${NAME}` in the token is replaced with the environment variable
+    /// `NAME`, so the file can be committed without the secret. Builder
+    /// methods called afterwards override values from the file.
+    pub fn from_config_file(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            Error::new(
+                ErrorKind::ConfigInvalid,
+                "failed to read notion config file",
+            )
+            .with_context("path", path.display())
+            .set_source(err)
+        })?;
+        let mut config: NotionConfig = toml::from_str(&text).map_err(|err| {
+            Error::new(
+                ErrorKind::ConfigInvalid,
+                "failed to parse notion config file",
+            )
+            .with_context("path", path.display())
+            .set_source(err)
+        })?;
+        config.token = config.token.as_deref().map(interpolate_env).transpose()?;
+        Ok(config.into_builder())
+    }
+
     /// Set the token used to talk to Notion.
     pub fn token(mut self, token: &str) -> Self {
         if !token.is_empty() {
@@ -66,205 +289,1100 @@ impl NotionServiceBuilder {
         self
     }
 
+    /// Set several databases to list. With more than one, each database is
+    /// listed as a `{database_id}/` directory under the root; a single
+    /// database keeps the flat layout of [`Self::database_id`].
+    pub fn database_ids(mut self, database_ids: Vec<String>) -> Self {
+        self.config.database_ids = database_ids
+            .into_iter()
+            .filter(|id| !id.is_empty())
+            .collect();
+        self
+    }
+
     /// Enable or disable frontmatter on page reads.
     pub fn frontmatter(mut self, enabled: bool) -> Self {
         self.config.frontmatter = enabled;
         self
     }
-}
 
-impl Builder for NotionServiceBuilder {
-    type Config = NotionConfig;
+    /// Add a `slug` frontmatter key derived from the page title, falling back
+    /// to the page id for untitled pages.
+    pub fn slug(mut self, enabled: bool) -> Self {
+        self.config.slug = enabled;
+        self
+    }
 
-    fn build(self) -> Result<impl Access> {
+    /// Add `created_time` and `last_edited_time` keys from the page itself.
+    /// Page properties with the same names take precedence.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.config.timestamps = enabled;
+        self
+    }
+
+    /// Set the timeout in seconds for each Notion call.
+    pub fn timeout_secs(mut self, timeout_secs: u64) -> Self {
+        self.config.timeout_secs = Some(timeout_secs);
+        self
+    }
+
+    /// Choose between Markdown and JSON content on reads. Frontmatter is not
+    /// added in JSON mode since the properties are part of the document.
+    pub fn content_mode(mut self, mode: ContentMode) -> Self {
+        self.config.content_mode = mode;
+        self
+    }
+
+    /// Set how many times a Notion call is retried, with exponential backoff,
+    /// after a rate limit or server error.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.config.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Name listed pages after their slugified title instead of their id.
+    /// Duplicate titles get a numeric suffix (`post-2.md`) and untitled
+    /// pages keep their id.
+    ///
+    /// Listing gets titles from the database query at no extra cost, but
+    /// every stat or read of a title-based path queries the whole database
+    /// again to map the name back to a page id.
+    pub fn use_title_filenames(mut self, enabled: bool) -> Self {
+        self.config.use_title_filenames = enabled;
+        self
+    }
+
+    /// Tag code fences with the language names syntax highlighters expect,
+    /// e.g. `cpp` rather than `cplusplus`.
+    pub fn code_lang(mut self, enabled: bool) -> Self {
+        self.config.code_lang = enabled;
+        self
+    }
+
+    /// Write callouts as GitHub alerts (`> [!NOTE]`) instead of notion2md's
+    /// `> [!note]` quotes.
+    pub fn callouts(mut self, style: CalloutStyle) -> Self {
+        self.config.callouts = style;
+        self
+    }
+
+    /// Rewrite links to other Notion pages to `{id}.md` or `{slug}.md`.
+    /// Targets are relative to the root, so links from nested pages may
+    /// need adjusting. [`LinkMode::Slug`] retrieves each linked page once
+    /// per read.
+    pub fn link_mode(mut self, mode: LinkMode) -> Self {
+        self.config.link_mode = mode;
+        self
+    }
+
+    /// Remove `<!-- ... -->` and `%% ... %%` comments from page content.
+    /// Fenced code blocks are kept as they are.
+    pub fn strip_comments(mut self, enabled: bool) -> Self {
+        self.config.strip_comments = enabled;
+        self
+    }
+
+    /// Write these frontmatter keys first, in the given order. Keys not
+    /// listed, and all keys by default, are sorted alphabetically.
+    pub fn frontmatter_key_order<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.frontmatter_key_order = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Rename a property when writing frontmatter, e.g. `Published At` to
+    /// `date`. Properties without an alias keep their names.
+    pub fn frontmatter_alias(mut self, property: &str, key: &str) -> Self {
+        self.config
+            .frontmatter_aliases
+            .insert(property.to_string(), key.to_string());
+        self
+    }
+
+    /// Keep only these properties in frontmatter and JSON content. Names are
+    /// matched case-insensitively; exclusions still apply afterwards.
+    pub fn include_properties<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.include_properties = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Leave these properties out of frontmatter and JSON content, e.g.
+    /// internal formula or ID columns. Names are matched case-insensitively.
+    pub fn exclude_properties<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.exclude_properties = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serve pages under a path prefix such as `notion/`, so `notion/{id}.md`
+    /// names a page. Paths outside the prefix are not found.
+    pub fn root(mut self, root: &str) -> Self {
+        if !root.is_empty() {
+            self.config.root = Some(root.to_string());
+        }
+        self
+    }
+
+    /// Set the largest chunk in bytes a reader yields at once. Pages are
+    /// still rendered in full before reading starts, but callers copying
+    /// the content out get bounded pieces instead of the whole page.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.config.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Render pages on stat to report their exact content length.
+    ///
+    /// Off by default: stat then only retrieves the page, reporting its
+    /// content type and last edit time without a content length. Rendering
+    /// fetches every block of the page, which costs as much as a read.
+    pub fn stat_exact_size(mut self, enabled: bool) -> Self {
+        self.config.stat_exact_size = enabled;
+        self
+    }
+
+    /// Set how many pages each database query asks Notion for. Values
+    /// outside `1..=100` are clamped; smaller batches spread listing over
+    /// more, cheaper calls under strict rate limits.
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.config.page_size = Some(page_size);
+        self
+    }
+
+    /// Take page titles from this property, for slugs and title file names,
+    /// instead of the `title` property. Pages without a value for it fall
+    /// back to their real title.
+    pub fn title_property(mut self, property: &str) -> Self {
+        if !property.is_empty() {
+            self.config.title_property = Some(property.to_string());
+        }
+        self
+    }
+
+    /// Name untitled pages, in slugs and title file names, with this
+    /// template instead of their ID. `{id}` expands to the page ID and
+    /// `{short_id}` to its first 8 characters, as in `untitled-{short_id}`.
+    pub fn untitled_template(mut self, template: &str) -> Self {
+        if !template.is_empty() {
+            self.config.untitled_template = Some(template.to_string());
+        }
+        self
+    }
+
+    /// Identify Notion requests with this `User-Agent` instead of
+    /// `notion-opendal/{version}`.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        if !user_agent.is_empty() {
+            self.config.user_agent = Some(user_agent.to_string());
+        }
+        self
+    }
+
+    /// Close YAML frontmatter with `...` instead of `---`.
+    pub fn frontmatter_closing_fence(mut self, fence: ClosingFence) -> Self {
+        self.config.frontmatter_closing_fence = fence;
+        self
+    }
+
+    /// Choose whether a blank line separates frontmatter from the content.
+    pub fn frontmatter_blank_line(mut self, enabled: bool) -> Self {
+        self.config.frontmatter_blank_line = Some(enabled);
+        self
+    }
+
+    /// List people properties as `{ id, name }` objects, keeping users whose
+    /// names the integration can't see, instead of as plain names.
+    pub fn people_detailed(mut self, enabled: bool) -> Self {
+        self.config.people_detailed = enabled;
+        self
+    }
+
+    /// Render number properties the way Notion displays them, following the
+    /// number format in the page's database (percent, currency, commas).
+    /// Costs one database retrieval per rendered page.
+    pub fn format_numbers(mut self, enabled: bool) -> Self {
+        self.config.format_numbers = enabled;
+        self
+    }
+
+    /// Fail reads of pages whose Markdown exceeds `bytes`, instead of
+    /// holding an arbitrarily large render in memory. `0` means no limit.
+    pub fn max_markdown_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_markdown_bytes = (bytes > 0).then_some(bytes);
+        self
+    }
+
+    /// Read and stat archived and trashed pages instead of reporting them
+    /// as not found.
+    pub fn include_archived(mut self, enabled: bool) -> Self {
+        self.config.include_archived = enabled;
+        self
+    }
+
+    /// The accessor [`Builder::build`] returns.
+    fn build_accessor(self) -> Result<NotionAccessor> {
         let token = self
             .config
             .token
             .ok_or_else(|| Error::new(ErrorKind::ConfigInvalid, "notion token is required"))?;
 
-        let client = NotionClient::new(token, None).map_err(|err| {
+        let user_agent = self
+            .config
+            .user_agent
+            .as_deref()
+            .unwrap_or(DEFAULT_USER_AGENT);
+        let client = new_notion_client(token, user_agent).map_err(|err| {
             Error::new(ErrorKind::ConfigInvalid, "failed to build notion client")
                 .with_context("source", err.to_string())
         })?;
 
+        let mut database_ids: Vec<String> = Vec::new();
+        for id in self
+            .config
+            .database_id
+            .into_iter()
+            .chain(self.config.database_ids)
+        {
+            let id = if is_valid_notion_id(&id) {
+                normalize_notion_id(&id)
+            } else {
+                id
+            };
+            if !database_ids.contains(&id) {
+                database_ids.push(id);
+            }
+        }
+
+        let root = normalize_root(self.config.root.as_deref().unwrap_or_default());
+
         let info = AccessorInfo::default();
         info.set_scheme("notion");
-        info.set_root("/");
+        info.set_root(&format!("/{root}"));
         info.set_native_capability(Capability {
             stat: true,
             read: true,
-            list: self.config.database_id.is_some(),
+            write: true,
+            delete: true,
+            list: true,
             ..Default::default()
         });
 
         Ok(NotionAccessor {
             client,
-            database_id: self.config.database_id,
+            database_ids: database_ids.into(),
             frontmatter: self.config.frontmatter,
+            slug: self.config.slug,
+            timestamps: self.config.timestamps,
+            use_title_filenames: self.config.use_title_filenames,
+            content_mode: self.config.content_mode,
+            render_options: RenderOptions {
+                code_lang: self.config.code_lang,
+                callouts: self.config.callouts,
+            },
+            link_mode: self.config.link_mode,
+            strip_comments: self.config.strip_comments,
+            frontmatter_key_order: self.config.frontmatter_key_order.into(),
+            frontmatter_aliases: Arc::new(self.config.frontmatter_aliases),
+            include_properties: self.config.include_properties.map(Into::into),
+            exclude_properties: self.config.exclude_properties.into(),
+            timeout: Duration::from_secs(self.config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            max_retries: self.config.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            root,
+            chunk_size: self.config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1),
+            stat_exact_size: self.config.stat_exact_size,
+            page_size: clamp_page_size(self.config.page_size.unwrap_or(MAX_PAGE_SIZE)),
+            title_property: self.config.title_property,
+            untitled_template: self.config.untitled_template,
+            frontmatter_style: FrontmatterStyle {
+                closing_fence: self.config.frontmatter_closing_fence,
+                blank_line: self.config.frontmatter_blank_line.unwrap_or(true),
+            },
+            people_detailed: self.config.people_detailed,
+            format_numbers: self.config.format_numbers,
+            max_markdown_bytes: self.config.max_markdown_bytes,
+            include_archived: self.config.include_archived,
             info: Arc::new(info),
         })
     }
 }
 
+impl Builder for NotionServiceBuilder {
+    type Config = NotionConfig;
+
+    fn build(self) -> Result<impl Access> {
+        self.build_accessor()
+    }
+}
+
 #[derive(Clone)]
 pub struct NotionAccessor {
     client: NotionClient,
-    database_id: Option<String>,
+    database_ids: Arc<[String]>,
     frontmatter: bool,
+    slug: bool,
+    timestamps: bool,
+    use_title_filenames: bool,
+    content_mode: ContentMode,
+    render_options: RenderOptions,
+    link_mode: LinkMode,
+    strip_comments: bool,
+    frontmatter_key_order: Arc<[String]>,
+    frontmatter_aliases: Arc<HashMap<String, String>>,
+    include_properties: Option<Arc<[String]>>,
+    exclude_properties: Arc<[String]>,
+    timeout: Duration,
+    max_retries: u32,
+    /// Normalized path prefix without a leading slash, e.g. `notion/`, or
+    /// empty when pages live at the top level.
+    root: String,
+    chunk_size: usize,
+    stat_exact_size: bool,
+    page_size: u32,
+    title_property: Option<String>,
+    untitled_template: Option<String>,
+    frontmatter_style: FrontmatterStyle,
+    people_detailed: bool,
+    format_numbers: bool,
+    max_markdown_bytes: Option<usize>,
+    include_archived: bool,
     info: Arc<AccessorInfo>,
 }
 
 impl Debug for NotionAccessor {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NotionAccessor")
-            .field("database_id", &self.database_id)
+            .field("database_ids", &self.database_ids)
             .field("frontmatter", &self.frontmatter)
+            .field("slug", &self.slug)
+            .field("timestamps", &self.timestamps)
+            .field("use_title_filenames", &self.use_title_filenames)
+            .field("content_mode", &self.content_mode)
+            .field("render_options", &self.render_options)
+            .field("link_mode", &self.link_mode)
+            .field("strip_comments", &self.strip_comments)
+            .field("frontmatter_key_order", &self.frontmatter_key_order)
+            .field("frontmatter_aliases", &self.frontmatter_aliases)
+            .field("include_properties", &self.include_properties)
+            .field("exclude_properties", &self.exclude_properties)
+            .field("timeout", &self.timeout)
+            .field("max_retries", &self.max_retries)
+            .field("root", &self.root)
+            .field("chunk_size", &self.chunk_size)
+            .field("stat_exact_size", &self.stat_exact_size)
+            .field("page_size", &self.page_size)
+            .field("title_property", &self.title_property)
+            .field("untitled_template", &self.untitled_template)
+            .field("frontmatter_style", &self.frontmatter_style)
+            .field("people_detailed", &self.people_detailed)
+            .field("format_numbers", &self.format_numbers)
+            .field("max_markdown_bytes", &self.max_markdown_bytes)
+            .field("include_archived", &self.include_archived)
             .finish()
     }
 }
 
-impl Access for NotionAccessor {
-    type Reader = Buffer;
-    type Writer = ();
-    type Lister = NotionLister;
-    type Deleter = ();
+impl NotionAccessor {
+    /// Slug for `page` under the configured title property and untitled
+    /// template.
+    fn page_slug(&self, page: &NotionPage) -> String {
+        page_slug(
+            page,
+            self.title_property.as_deref(),
+            self.untitled_template.as_deref(),
+        )
+    }
 
-    fn info(&self) -> Arc<AccessorInfo> {
-        self.info.clone()
+    /// Strip the configured root from `path`. Paths outside the root don't
+    /// name anything this service serves.
+    fn relative_path<'a>(&self, path: &'a str) -> Result<&'a str> {
+        if self.root.is_empty() {
+            return Ok(path);
+        }
+
+        let path = path.trim_start_matches('/');
+        if path == self.root.trim_end_matches('/') {
+            return Ok("");
+        }
+        path.strip_prefix(self.root.as_str()).ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "path is outside the configured root")
+                .with_context("path", path)
+                .with_context("root", &self.root)
+        })
     }
 
-    async fn stat(&self, path: &str, _: OpStat) -> Result<RpStat> {
-        if is_root(path) {
-            return Ok(RpStat::new(Metadata::new(EntryMode::DIR)));
+    /// Fetch the page and render it to Markdown, applying frontmatter when
+    /// enabled, or to a JSON document in [`ContentMode::Json`].
+    async fn render_page(&self, page_id: &str) -> Result<(NotionPage, String)> {
+        let page = self.retrieve_page(page_id).await?;
+        let mut properties = notion_page_to_properties(&page);
+        if self.people_detailed {
+            detail_people(&mut properties, &page);
+        }
+        if self.format_numbers {
+            self.format_numbers(&mut properties, &page).await;
         }
+        if self.slug {
+            properties.insert(
+                "slug".to_string(),
+                PropertyValue::String(self.page_slug(&page)),
+            );
+        }
+        if self.timestamps {
+            insert_timestamps(&mut properties, &page);
+        }
+        if let Some(included) = &self.include_properties {
+            include_properties(&mut properties, included);
+        }
+        exclude_properties(&mut properties, &self.exclude_properties);
 
-        let page_id = parse_page_path(path)?;
-        let page = self
-            .client
-            .pages
-            .retrieve_a_page(&page_id, None)
-            .await
-            .map_err(map_notion_error)?;
-        let properties = notion_page_to_properties(&page);
+        let converter = self.render_options.converter(self.client.clone());
+        let markdown = timeout(
+            self.timeout,
+            retry_transient(self.max_retries, || converter.convert_page(page_id)),
+        )
+        .await
+        .map_err(|_| timeout_error(self.timeout))?
+        .map_err(map_render_error)?;
+        if let Some(limit) = self.max_markdown_bytes {
+            if markdown.len() > limit {
+                return Err(Error::new(
+                    ErrorKind::Unexpected,
+                    "rendered markdown exceeds the size limit",
+                )
+                .with_context("page_id", page_id)
+                .with_context("size", markdown.len())
+                .with_context("limit", limit));
+            }
+        }
+        let mut markdown = self.rewrite_links(&markdown).await;
+        if self.strip_comments {
+            markdown = strip_comments(&markdown);
+        }
 
-        let markdown = NotionToMarkdownBuilder::new(self.client.clone())
-            .build()
-            .convert_page(&page_id)
-            .await
+        let content = self.page_content(&page, properties, markdown)?;
+        Ok((page, content))
+    }
+
+    /// The content read for `page`: its Markdown with frontmatter when
+    /// enabled, or the JSON document in [`ContentMode::Json`].
+    fn page_content(
+        &self,
+        page: &NotionPage,
+        properties: HashMap<String, PropertyValue>,
+        markdown: String,
+    ) -> Result<String> {
+        let content = match self.content_mode {
+            ContentMode::Json => serde_json::to_string(&PageDocument {
+                id: &page.id,
+                icon: page_icon(page),
+                cover: page_cover(page),
+                properties: &properties,
+                content: &markdown,
+            })
             .map_err(|err| {
-                Error::new(ErrorKind::Unexpected, "failed to render notion page")
+                Error::new(ErrorKind::Unexpected, "failed to serialize notion page")
                     .with_context("source", err.to_string())
-            })?;
-
-        let content = if self.frontmatter {
-            apply_frontmatter(&properties, &markdown)
-        } else {
-            markdown
+            })?,
+            ContentMode::Markdown if self.frontmatter => apply_frontmatter(
+                &alias_properties(properties, &self.frontmatter_aliases),
+                &markdown,
+                FrontmatterFormat::default(),
+                &self.frontmatter_key_order,
+                self.frontmatter_style,
+            ),
+            ContentMode::Markdown => markdown,
         };
+        Ok(content)
+    }
 
+    /// Metadata stat reports for `page`, with a content length only when its
+    /// rendered `content` is known.
+    fn page_metadata(&self, page: &NotionPage, content: Option<&str>) -> Metadata {
         let mut meta = Metadata::new(EntryMode::FILE);
-        meta.set_content_length(content.as_bytes().len() as u64);
-        meta.set_content_type("text/markdown");
+        if let Some(content) = content {
+            meta.set_content_length(content.len() as u64);
+        }
+        meta.set_content_type(self.content_mode.content_type());
         meta.set_last_modified(page.last_edited_time);
+        meta
+    }
 
-        Ok(RpStat::new(meta))
+    /// Retrieve a page, treating archived and trashed pages as missing
+    /// unless `include_archived` is set.
+    async fn retrieve_page(&self, page_id: &str) -> Result<NotionPage> {
+        let page = timeout(
+            self.timeout,
+            retry_transient(self.max_retries, || {
+                self.client.pages.retrieve_a_page(page_id, None)
+            }),
+        )
+        .await
+        .map_err(|_| timeout_error(self.timeout))?
+        .map_err(map_notion_error)?;
+        if page.archived && !self.include_archived {
+            return Err(Error::new(ErrorKind::NotFound, "notion page is archived")
+                .with_context("page_id", page_id));
+        }
+        Ok(page)
     }
 
-    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
-        if !args.range().is_full() {
-            return Err(Error::new(
-                ErrorKind::Unsupported,
-                "range reads are not supported for notion",
-            ));
+    /// Render number properties in their database's display format. Pages
+    /// outside a database, or whose database can't be retrieved, keep plain
+    /// numbers.
+    async fn format_numbers(
+        &self,
+        properties: &mut HashMap<String, PropertyValue>,
+        page: &NotionPage,
+    ) {
+        let Some(database_id) = parent_database_id(page) else {
+            return;
+        };
+        let database = timeout(
+            self.timeout,
+            retry_transient(self.max_retries, || {
+                self.client.databases.retrieve_a_database(database_id)
+            }),
+        )
+        .await;
+        match database {
+            Ok(Ok(database)) => format_numbers(properties, &number_formats(&database)),
+            Ok(Err(err)) => warn!("failed to retrieve notion database {database_id}: {err:?}"),
+            Err(_) => warn!("timed out retrieving notion database {database_id}"),
+        }
+    }
+
+    /// Rewrite links to other Notion pages according to the link mode. Links
+    /// to pages that can't be retrieved in slug mode are left unchanged.
+    async fn rewrite_links(&self, markdown: &str) -> String {
+        let mut slugs = HashMap::new();
+        if self.link_mode == LinkMode::Slug {
+            for id in linked_page_ids(markdown) {
+                let page = timeout(
+                    self.timeout,
+                    retry_transient(self.max_retries, || {
+                        self.client.pages.retrieve_a_page(&id, None)
+                    }),
+                )
+                .await;
+                match page {
+                    Ok(Ok(page)) => {
+                        slugs.insert(id, self.page_slug(&page));
+                    }
+                    Ok(Err(err)) => warn!("failed to resolve linked notion page {id}: {err:?}"),
+                    Err(_) => warn!("timed out resolving linked notion page {id}"),
+                }
+            }
         }
+        rewrite_page_links(markdown, self.link_mode, &slugs)
+    }
 
-        let page_id = parse_page_path(path)?;
-        let page = self
-            .client
-            .pages
-            .retrieve_a_page(&page_id, None)
+    /// File names (without the `.md` extension) and page ids of every page in
+    /// the database, in query order.
+    async fn list_database_pages(&self, database_id: &str) -> Result<Vec<(String, String)>> {
+        let mut cursor: Option<String> = None;
+        let mut pages: Vec<(String, String)> = Vec::new();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+
+        loop {
+            let request = QueryDatabaseRequest {
+                start_cursor: cursor.clone(),
+                page_size: Some(self.page_size),
+                ..Default::default()
+            };
+
+            let response = timeout(
+                self.timeout,
+                retry_transient(self.max_retries, || {
+                    self.client
+                        .databases
+                        .query_a_database(database_id, request.clone())
+                }),
+            )
             .await
+            .map_err(|_| timeout_error(self.timeout))?
             .map_err(map_notion_error)?;
-        let properties = notion_page_to_properties(&page);
 
-        let markdown = NotionToMarkdownBuilder::new(self.client.clone())
-            .build()
-            .convert_page(&page_id)
+            for page in response.results {
+                if !self.use_title_filenames {
+                    pages.push((page.id.clone(), page.id));
+                    continue;
+                }
+
+                let slug = self.page_slug(&page);
+                let count = seen.entry(slug.clone()).or_insert(0);
+                *count += 1;
+                let name = if *count == 1 {
+                    slug
+                } else {
+                    format!("{slug}-{count}")
+                };
+                pages.push((name, page.id));
+            }
+
+            cursor = response.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Ids and `has_children` flags of the child pages directly under
+    /// `page_id`.
+    async fn list_child_pages(&self, page_id: &str) -> Result<Vec<(String, bool)>> {
+        let mut cursor: Option<String> = None;
+        let mut pages: Vec<(String, bool)> = Vec::new();
+
+        loop {
+            let response = timeout(
+                self.timeout,
+                retry_transient(self.max_retries, || {
+                    self.client.blocks.retrieve_block_children(
+                        page_id,
+                        cursor.as_deref(),
+                        Some(100),
+                    )
+                }),
+            )
             .await
-            .map_err(|err| {
-                Error::new(ErrorKind::Unexpected, "failed to render notion page")
-                    .with_context("source", err.to_string())
-            })?;
+            .map_err(|_| timeout_error(self.timeout))?
+            .map_err(map_notion_error)?;
 
-        let content = if self.frontmatter {
-            apply_frontmatter(&properties, &markdown)
+            for block in response.results {
+                if let (BlockType::ChildPage { .. }, Some(id)) = (&block.block_type, block.id) {
+                    pages.push((id, block.has_children.unwrap_or(false)));
+                }
+            }
+
+            cursor = response.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Map a `{name}.md` path to a page id. With title file names enabled,
+    /// top-level names are looked up in the database listing; names that
+    /// don't match any listed page are treated as page ids. Nested pages are
+    /// always listed by id.
+    async fn resolve_page_id(&self, path: &str) -> Result<String> {
+        let page_id = self.lookup_page_id(self.relative_path(path)?).await?;
+        if is_valid_notion_id(&page_id) {
+            Ok(normalize_notion_id(&page_id))
         } else {
-            markdown
+            Err(invalid_id_error(&page_id))
+        }
+    }
+
+    async fn lookup_page_id(&self, path: &str) -> Result<String> {
+        let name = parse_page_path(path)?;
+        if !self.use_title_filenames {
+            return Ok(name);
+        }
+        let database_id = match (path.rsplit_once('/'), &*self.database_ids) {
+            (None, [database_id]) => database_id.as_str(),
+            (Some((dir, _)), _) => match self.listed_database(dir) {
+                Some(database_id) => database_id,
+                None => return Ok(name),
+            },
+            _ => return Ok(name),
         };
 
-        let size = content.as_bytes().len() as u64;
+        let pages = self.list_database_pages(database_id).await?;
+        Ok(pages
+            .into_iter()
+            .find(|(listed, _)| *listed == name)
+            .map(|(_, page_id)| page_id)
+            .unwrap_or(name))
+    }
+
+    /// The configured database `dir` names when several databases are
+    /// listed as directories.
+    fn listed_database(&self, dir: &str) -> Option<&str> {
+        if self.database_ids.len() < 2 || !is_valid_notion_id(dir) {
+            return None;
+        }
+        let id = normalize_notion_id(dir);
+        self.database_ids
+            .iter()
+            .find(|database_id| **database_id == id)
+            .map(String::as_str)
+    }
+
+    /// Entries for the pages of a database listed under `prefix`. Whether a
+    /// database page has child pages is only known after fetching its
+    /// blocks, so every page is listed as a directory too.
+    async fn database_entries(&self, database_id: &str, prefix: &str) -> Result<Vec<oio::Entry>> {
+        Ok(self
+            .list_database_pages(database_id)
+            .await?
+            .into_iter()
+            .flat_map(|(name, page_id)| {
+                [
+                    page_entry(&format!("{prefix}{name}.md"), self.content_mode),
+                    dir_entry(&format!("{prefix}{page_id}/")),
+                ]
+            })
+            .collect())
+    }
+}
+
+impl Access for NotionAccessor {
+    type Reader = NotionReader;
+    type Writer = NotionWriter;
+    type Lister = NotionLister;
+    type Deleter = oio::OneShotDeleter<NotionDeleter>;
+
+    fn info(&self) -> Arc<AccessorInfo> {
+        self.info.clone()
+    }
+
+    async fn stat(&self, path: &str, _: OpStat) -> Result<RpStat> {
+        let relative = self.relative_path(path)?;
+        if is_root(relative) || relative.ends_with('/') {
+            return Ok(RpStat::new(Metadata::new(EntryMode::DIR)));
+        }
+
+        let page_id = self.resolve_page_id(path).await?;
+        if self.stat_exact_size {
+            let (page, content) = self.render_page(&page_id).await?;
+            Ok(RpStat::new(self.page_metadata(&page, Some(&content))))
+        } else {
+            let page = self.retrieve_page(&page_id).await?;
+            Ok(RpStat::new(self.page_metadata(&page, None)))
+        }
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        let page_id = self.resolve_page_id(path).await?;
+        let (_, content) = self.render_page(&page_id).await?;
+
+        let buffer = slice_range(content, args.range());
+        Ok((
+            RpRead::new().with_size(Some(buffer.len() as u64)),
+            NotionReader::new(buffer, self.chunk_size),
+        ))
+    }
+
+    async fn write(&self, path: &str, _: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        check_write_path(self.relative_path(path)?)?;
+        let page_id = self.resolve_page_id(path).await?;
+        Ok((
+            RpWrite::default(),
+            NotionWriter::new(self.client.clone(), page_id, self.timeout, self.max_retries),
+        ))
+    }
+
+    async fn delete(&self) -> Result<(RpDelete, Self::Deleter)> {
         Ok((
-            RpRead::new().with_size(Some(size)),
-            Buffer::from(content.into_bytes()),
+            RpDelete::default(),
+            oio::OneShotDeleter::new(NotionDeleter {
+                accessor: self.clone(),
+            }),
         ))
     }
 
     async fn list(&self, path: &str, _: OpList) -> Result<(RpList, Self::Lister)> {
-        let Some(database_id) = &self.database_id else {
-            return Err(Error::new(
-                ErrorKind::Unsupported,
-                "list requires a database_id",
-            ));
-        };
+        let dir_id = parse_dir_path(self.relative_path(path)?)?;
+        if let Some(database_id) = dir_id.as_deref().and_then(|id| self.listed_database(id)) {
+            let entries = self.database_entries(database_id, path).await?;
+            return Ok((RpList::default(), NotionLister::new(entries)));
+        }
 
-        if !is_root_dir(path) {
-            return Err(Error::new(
-                ErrorKind::NotADirectory,
-                "only root directory is listable",
-            ));
+        if let Some(page_id) = dir_id {
+            let entries = self
+                .list_child_pages(&page_id)
+                .await?
+                .into_iter()
+                .flat_map(|(child_id, has_children)| {
+                    let file = page_entry(&format!("{path}{child_id}.md"), self.content_mode);
+                    let dir = has_children.then(|| dir_entry(&format!("{path}{child_id}/")));
+                    std::iter::once(file).chain(dir)
+                })
+                .collect();
+            return Ok((RpList::default(), NotionLister::new(entries)));
         }
 
-        let pages = list_database_pages(self.client.clone(), database_id).await?;
-        Ok((RpList::default(), NotionLister::new(pages)))
+        let entries = match &*self.database_ids {
+            [] => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "listing the root requires a database_id",
+                ))
+            }
+            [database_id] => self.database_entries(database_id, &self.root).await?,
+            database_ids => database_ids
+                .iter()
+                .map(|database_id| dir_entry(&format!("{}{database_id}/", self.root)))
+                .collect(),
+        };
+        Ok((RpList::default(), NotionLister::new(entries)))
     }
 }
 
 #[derive(Debug)]
 pub struct NotionLister {
-    pages: Vec<String>,
-    idx: usize,
+    entries: std::vec::IntoIter<oio::Entry>,
 }
 
 impl NotionLister {
-    fn new(pages: Vec<String>) -> Self {
-        Self { pages, idx: 0 }
+    fn new(entries: Vec<oio::Entry>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+        }
     }
 }
 
 impl oio::List for NotionLister {
     async fn next(&mut self) -> Result<Option<oio::Entry>> {
-        if self.idx >= self.pages.len() {
-            return Ok(None);
+        Ok(self.entries.next())
+    }
+}
+
+/// Yields rendered content in chunks of at most `chunk_size` bytes.
+#[derive(Debug)]
+pub struct NotionReader {
+    buffer: Buffer,
+    chunk_size: usize,
+}
+
+impl NotionReader {
+    fn new(buffer: Buffer, chunk_size: usize) -> Self {
+        Self { buffer, chunk_size }
+    }
+}
+
+impl oio::Read for NotionReader {
+    async fn read(&mut self) -> Result<Buffer> {
+        let len = self.chunk_size.min(self.buffer.len());
+        let chunk = self.buffer.slice(..len);
+        self.buffer = self.buffer.slice(len..);
+        Ok(chunk)
+    }
+}
+
+fn page_entry(path: &str, mode: ContentMode) -> oio::Entry {
+    let meta = Metadata::new(EntryMode::FILE).with_content_type(mode.content_type().to_string());
+    oio::Entry::new(path, meta)
+}
+
+fn dir_entry(path: &str) -> oio::Entry {
+    oio::Entry::new(path, Metadata::new(EntryMode::DIR))
+}
+
+/// Buffers the written Markdown and appends it to the page as paragraph
+/// blocks on close.
+pub struct NotionWriter {
+    client: NotionClient,
+    page_id: String,
+    timeout: Duration,
+    max_retries: u32,
+    buffer: Vec<u8>,
+}
+
+impl NotionWriter {
+    fn new(client: NotionClient, page_id: String, timeout: Duration, max_retries: u32) -> Self {
+        Self {
+            client,
+            page_id,
+            timeout,
+            max_retries,
+            buffer: Vec::new(),
         }
+    }
+}
+
+impl oio::Write for NotionWriter {
+    async fn write(&mut self, bs: Buffer) -> Result<()> {
+        self.buffer.extend(bs.to_bytes());
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<Metadata> {
+        let content = std::str::from_utf8(&self.buffer).map_err(|err| {
+            Error::new(ErrorKind::Unexpected, "written content is not valid utf-8")
+                .with_context("source", err.to_string())
+        })?;
+        let blocks = markdown_to_paragraphs(content);
+        append_blocks(&blocks, self.timeout, self.max_retries, |request| {
+            self.client
+                .blocks
+                .append_block_children(&self.page_id, request)
+        })
+        .await?;
+
+        let meta = Metadata::new(EntryMode::FILE).with_content_length(self.buffer.len() as u64);
+        self.buffer.clear();
+        Ok(meta)
+    }
 
-        let page_id = &self.pages[self.idx];
-        self.idx += 1;
+    async fn abort(&mut self) -> Result<()> {
+        self.buffer.clear();
+        Ok(())
+    }
+}
 
-        let meta = Metadata::new(EntryMode::FILE).with_content_type("text/markdown".to_string());
-        let path = format!("{page_id}.md");
-        Ok(Some(oio::Entry::new(&path, meta)))
+/// Append `blocks` in batches of at most [`MAX_APPEND_BLOCKS`], retrying
+/// rate limits and server errors like reads do. A 5xx can still have added
+/// a batch, so a retried batch may occasionally appear twice.
+async fn append_blocks<T, F, Fut>(
+    blocks: &[Block],
+    call_timeout: Duration,
+    max_retries: u32,
+    mut append: F,
+) -> Result<()>
+where
+    F: FnMut(AppendBlockChildrenRequest) -> Fut,
+    Fut: Future<Output = std::result::Result<T, NotionClientError>>,
+{
+    for chunk in blocks.chunks(MAX_APPEND_BLOCKS) {
+        let request = AppendBlockChildrenRequest {
+            children: chunk.to_vec(),
+            after: None,
+        };
+        timeout(
+            call_timeout,
+            retry_transient(max_retries, || append(request.clone())),
+        )
+        .await
+        .map_err(|_| timeout_error(call_timeout))?
+        .map_err(map_notion_error)?;
     }
+    Ok(())
 }
 
+/// Archives pages, since Notion has no way to delete them permanently
+/// through the API.
+pub struct NotionDeleter {
+    accessor: NotionAccessor,
+}
+
+impl oio::OneShotDelete for NotionDeleter {
+    async fn delete_once(&self, path: String, _: OpDelete) -> Result<()> {
+        if is_root_dir(self.accessor.relative_path(&path)?) {
+            return Err(Error::new(
+                ErrorKind::IsADirectory,
+                "the root directory cannot be deleted",
+            ));
+        }
+
+        let page_id = self.accessor.resolve_page_id(&path).await?;
+        let request = UpdatePagePropertiesRequest {
+            archived: Some(true),
+            ..Default::default()
+        };
+        let result = timeout(
+            self.accessor.timeout,
+            retry_transient(self.accessor.max_retries, || {
+                self.accessor
+                    .client
+                    .pages
+                    .update_page_properties(&page_id, request.clone())
+            }),
+        )
+        .await
+        .map_err(|_| timeout_error(self.accessor.timeout))?
+        .map_err(map_notion_error);
+
+        // Deleting a missing page is not an error, matching OpenDAL's
+        // idempotent delete semantics.
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Split Markdown into paragraph blocks on blank lines. Each paragraph is kept
+/// verbatim as plain text, split into several rich text items when it is
+/// longer than Notion allows.
+fn markdown_to_paragraphs(content: &str) -> Vec<Block> {
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(current.join("\n"));
+                current.clear();
+            }
+        } else {
+            current.push(line);
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current.join("\n"));
+    }
+
+    paragraphs
+        .iter()
+        .map(|paragraph| {
+            let chars: Vec<char> = paragraph.chars().collect();
+            let rich_text = chars
+                .chunks(MAX_RICH_TEXT_CHARS)
+                .map(|chunk| RichText::Text {
+                    text: Text {
+                        content: chunk.iter().collect(),
+                        link: None,
+                    },
+                    annotations: None,
+                    plain_text: None,
+                    href: None,
+                })
+                .collect();
+
+            Block {
+                object: Some("block".to_string()),
+                block_type: BlockType::Paragraph {
+                    paragraph: ParagraphValue {
+                        rich_text,
+                        ..Default::default()
+                    },
+                },
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// Only existing pages can be written to, addressed as `{name}.md`.
+fn check_write_path(path: &str) -> Result<()> {
+    match path.strip_suffix(".md") {
+        Some(name) if !name.is_empty() && !name.ends_with('/') && !name.contains("..") => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            "only writes to {id}.md are supported",
+        )
+        .with_context("path", path)),
+    }
+}
+
+/// Page id or file name of a `{name}.md` path. Nested paths such as
+/// `{parent_id}/{child_id}.md` resolve to their last segment.
 fn parse_page_path(path: &str) -> Result<String> {
-    if path.contains("..") || path.contains('/') {
+    if path.contains("..") {
         return Err(Error::new(
             ErrorKind::NotFound,
-            "nested paths are not supported",
+            "parent directory segments are not supported",
         ));
     }
 
-    let trimmed = path.trim_end_matches(".md");
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let trimmed = name.trim_end_matches(".md");
     if trimmed.is_empty() {
         Err(Error::new(
             ErrorKind::NotFound,
@@ -275,6 +1393,17 @@ fn parse_page_path(path: &str) -> Result<String> {
     }
 }
 
+/// `notion`, `/notion/` and `notion/` all become `notion/`; an empty root or
+/// `/` becomes the empty string.
+fn normalize_root(root: &str) -> String {
+    let root = root.trim_matches('/');
+    if root.is_empty() {
+        String::new()
+    } else {
+        format!("{root}/")
+    }
+}
+
 fn is_root(path: &str) -> bool {
     path.is_empty() || path == "/"
 }
@@ -283,44 +1412,122 @@ fn is_root_dir(path: &str) -> bool {
     is_root(path) || path == "./" || path == "/."
 }
 
-async fn list_database_pages(client: NotionClient, database_id: &str) -> Result<Vec<String>> {
-    let mut cursor: Option<String> = None;
-    let mut pages: Vec<String> = Vec::new();
+/// Page id of a nested directory path such as `{page_id}/` or
+/// `{parent_id}/{page_id}/`, or `None` for the root directory.
+fn parse_dir_path(path: &str) -> Result<Option<String>> {
+    if is_root_dir(path) {
+        return Ok(None);
+    }
 
-    loop {
-        let request = QueryDatabaseRequest {
-            start_cursor: cursor.clone(),
-            page_size: Some(100),
-            ..Default::default()
-        };
+    let Some(dir) = path.strip_suffix('/') else {
+        return Err(
+            Error::new(ErrorKind::NotADirectory, "only directories are listable")
+                .with_context("path", path),
+        );
+    };
+    if dir.contains("..") {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "parent directory segments are not supported",
+        ));
+    }
 
-        let response = client
-            .databases
-            .query_a_database(database_id, request)
-            .await
-            .map_err(map_notion_error)?;
+    match dir.rsplit('/').next() {
+        Some(page_id) if is_valid_notion_id(page_id) => Ok(Some(normalize_notion_id(page_id))),
+        Some(page_id) if !page_id.is_empty() => Err(invalid_id_error(page_id)),
+        _ => Err(Error::new(
+            ErrorKind::NotFound,
+            "page id is required in path",
+        )),
+    }
+}
 
-        for page in response.results {
-            pages.push(page.id);
-        }
+fn invalid_id_error(id: &str) -> Error {
+    Error::new(ErrorKind::NotFound, "path does not name a notion page id").with_context("id", id)
+}
 
-        cursor = response.next_cursor;
-        if cursor.is_none() {
+/// Replace every `${NAME}` in `value` with the environment variable `NAME`.
+fn interpolate_env(value: &str) -> Result<String> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
             break;
-        }
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let resolved = std::env::var(name).map_err(|_| {
+            Error::new(
+                ErrorKind::ConfigInvalid,
+                "environment variable in notion config is not set",
+            )
+            .with_context("variable", name)
+        })?;
+        output.push_str(&rest[..start]);
+        output.push_str(&resolved);
+        rest = &rest[start + 3 + len..];
     }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Serve `range` of rendered content. The page is fully rendered in memory
+/// anyway, so ranges are served by slicing it; ranges past the end are
+/// clamped.
+fn slice_range(content: String, range: BytesRange) -> Buffer {
+    let len = content.len();
+    let start = usize::try_from(range.offset())
+        .unwrap_or(usize::MAX)
+        .min(len);
+    let end = match range.size() {
+        Some(size) => start
+            .saturating_add(usize::try_from(size).unwrap_or(usize::MAX))
+            .min(len),
+        None => len,
+    };
+    Buffer::from(content.into_bytes()).slice(start..end)
+}
+
+fn timeout_error(timeout: Duration) -> Error {
+    Error::new(ErrorKind::Unexpected, "notion request timed out")
+        .with_context("timeout", format!("{}s", timeout.as_secs()))
+        .set_temporary()
+}
 
-    Ok(pages)
+fn map_render_error(err: anyhow::Error) -> Error {
+    match err.downcast::<NotionClientError>() {
+        Ok(err) => map_notion_error(err),
+        Err(err) => Error::new(ErrorKind::Unexpected, "failed to render notion page")
+            .with_context("source", err.to_string()),
+    }
 }
 
 fn map_notion_error(err: NotionClientError) -> Error {
     match err {
-        NotionClientError::InvalidStatusCode { error } => match error.status {
-            400 => Error::new(ErrorKind::Unexpected, error.message),
-            401 | 403 => Error::new(ErrorKind::PermissionDenied, error.message),
-            404 => Error::new(ErrorKind::NotFound, error.message),
-            _ => Error::new(ErrorKind::Unexpected, error.message),
-        },
+        NotionClientError::InvalidStatusCode { error } => {
+            let mapped = match error.status {
+                400 => Error::new(ErrorKind::Unexpected, error.message),
+                // OpenDAL has a single kind for both, so the context tells a
+                // bad token apart from an integration without access.
+                401 => Error::new(ErrorKind::PermissionDenied, error.message)
+                    .with_context("reason", "invalid token"),
+                403 => Error::new(ErrorKind::PermissionDenied, error.message)
+                    .with_context("reason", "integration lacks access"),
+                404 => Error::new(ErrorKind::NotFound, error.message),
+                429 => Error::new(ErrorKind::RateLimited, error.message),
+                _ => Error::new(ErrorKind::Unexpected, error.message),
+            };
+            // Notion support asks for this ID when investigating a failed call.
+            match error.request_id.filter(|id| !id.is_empty()) {
+                Some(request_id) => {
+                    warn!(
+                        "notion returned {} ({}), request_id {request_id}",
+                        error.status, error.code
+                    );
+                    mapped.with_context("notion_request_id", request_id)
+                }
+                None => mapped,
+            }
+        }
         NotionClientError::InvalidHeader { source } => Error::new(
             ErrorKind::ConfigInvalid,
             format!("invalid notion header: {source}"),
@@ -331,3 +1538,419 @@ fn map_notion_error(err: NotionClientError) -> Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    const CONTENT: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+    const PAGE_ID: &str = "1a2b3c4d-5e6f-7081-9a2b-3c4d5e6f7081";
+
+    fn accessor(builder: NotionServiceBuilder) -> NotionAccessor {
+        builder
+            .token("secret")
+            .build_accessor()
+            .expect("valid notion config")
+    }
+
+    fn page() -> NotionPage {
+        serde_json::from_value(serde_json::json!({
+            "object": "page",
+            "id": PAGE_ID,
+            "created_time": "2024-01-01T00:00:00.000Z",
+            "created_by": { "object": "user", "id": "user" },
+            "last_edited_time": "2024-01-02T00:00:00.000Z",
+            "last_edited_by": { "object": "user", "id": "user" },
+            "archived": false,
+            "icon": null,
+            "cover": null,
+            "properties": {},
+            "parent": { "type": "workspace", "workspace": true },
+            "url": "https://www.notion.so/1a2b3c4d5e6f70819a2b3c4d5e6f7081",
+            "public_url": null,
+        }))
+        .expect("valid notion page")
+    }
+
+    fn properties() -> HashMap<String, PropertyValue> {
+        HashMap::from([(
+            "title".to_string(),
+            PropertyValue::String("Hello".to_string()),
+        )])
+    }
+
+    #[test]
+    fn markdown_mode_reads_markdown_with_frontmatter() {
+        let frontmatter = accessor(NotionServiceBuilder::default().frontmatter(true));
+        assert_eq!(frontmatter.content_mode.content_type(), "text/markdown");
+        let content = frontmatter
+            .page_content(&page(), properties(), "# Hello\n".to_string())
+            .unwrap();
+        assert_eq!(content, "---\ntitle: \"Hello\"\n---\n\n# Hello\n");
+
+        let plain = accessor(NotionServiceBuilder::default());
+        let content = plain
+            .page_content(&page(), properties(), "# Hello\n".to_string())
+            .unwrap();
+        assert_eq!(content, "# Hello\n");
+    }
+
+    #[test]
+    fn json_mode_reads_a_page_document() {
+        // Frontmatter is left out in JSON mode even when enabled.
+        let accessor = accessor(
+            NotionServiceBuilder::default()
+                .frontmatter(true)
+                .content_mode(ContentMode::Json),
+        );
+        assert_eq!(accessor.content_mode.content_type(), "application/json");
+        let content = accessor
+            .page_content(&page(), properties(), "# Hello\n".to_string())
+            .unwrap();
+        let document: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(
+            document,
+            serde_json::json!({
+                "id": PAGE_ID,
+                "properties": { "title": "Hello" },
+                "content": "# Hello\n",
+            })
+        );
+    }
+
+    #[test]
+    fn ranges_slice_the_rendered_content() {
+        let buffer = slice_range(CONTENT.to_string(), BytesRange::new(10, Some(10)));
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(buffer.to_vec(), b"abcdefghij");
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_clamped() {
+        let cases = [
+            (BytesRange::new(0, None), CONTENT.len()),
+            (BytesRange::new(30, Some(100)), 6),
+            (BytesRange::new(30, None), 6),
+            (BytesRange::new(100, Some(10)), 0),
+            (BytesRange::new(u64::MAX, Some(u64::MAX)), 0),
+        ];
+        for (range, len) in cases {
+            assert_eq!(
+                slice_range(CONTENT.to_string(), range).len(),
+                len,
+                "{range}"
+            );
+        }
+    }
+
+    fn notion_error(status: u32) -> NotionClientError {
+        let error = serde_json::from_value(serde_json::json!({
+            "object": "error",
+            "status": status,
+            "code": "error",
+            "message": "Notion failed",
+            "request_id": null,
+        }))
+        .expect("valid notion error");
+        NotionClientError::InvalidStatusCode { error }
+    }
+
+    #[test]
+    fn notion_statuses_map_to_error_kinds() {
+        let cases = [
+            (400, ErrorKind::Unexpected),
+            (401, ErrorKind::PermissionDenied),
+            (403, ErrorKind::PermissionDenied),
+            (404, ErrorKind::NotFound),
+            (429, ErrorKind::RateLimited),
+            (500, ErrorKind::Unexpected),
+        ];
+        for (status, kind) in cases {
+            assert_eq!(
+                map_notion_error(notion_error(status)).kind(),
+                kind,
+                "{status}"
+            );
+        }
+
+        // The context tells a bad token apart from missing access.
+        let unauthorized = map_notion_error(notion_error(401)).to_string();
+        assert!(unauthorized.contains("invalid token"), "{unauthorized}");
+        let forbidden = map_notion_error(notion_error(403)).to_string();
+        assert!(
+            forbidden.contains("integration lacks access"),
+            "{forbidden}"
+        );
+    }
+
+    #[test]
+    fn paths_are_resolved_under_the_root() {
+        let rooted = accessor(NotionServiceBuilder::default().root("/notion/"));
+        assert_eq!(rooted.root, "notion/");
+        assert_eq!(&*rooted.info.root(), "/notion/");
+
+        let cases = [
+            ("notion/abc.md", "abc.md"),
+            ("/notion/abc.md", "abc.md"),
+            ("notion/parent/child.md", "parent/child.md"),
+            ("notion/", ""),
+            ("notion", ""),
+        ];
+        for (path, relative) in cases {
+            assert_eq!(rooted.relative_path(path).unwrap(), relative, "{path}");
+        }
+        for path in ["abc.md", "other/abc.md", "notionx/abc.md"] {
+            let err = rooted.relative_path(path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound, "{path}");
+        }
+
+        let unrooted = accessor(NotionServiceBuilder::default());
+        assert_eq!(unrooted.relative_path("abc.md").unwrap(), "abc.md");
+    }
+
+    #[tokio::test]
+    async fn several_databases_are_listed_as_directories() {
+        const OTHER_ID: &str = "ffffffff-ffff-ffff-ffff-ffffffffffff";
+        let layout = accessor(
+            NotionServiceBuilder::default()
+                .root("notion")
+                .database_id(PAGE_ID)
+                .database_ids(vec![OTHER_ID.replace('-', ""), PAGE_ID.to_string()]),
+        );
+        // Both spellings of an ID name the same database.
+        assert_eq!(layout.database_ids[..], [PAGE_ID, OTHER_ID]);
+
+        let (_, mut lister) = layout.list("notion/", OpList::default()).await.unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = oio::List::next(&mut lister).await.unwrap() {
+            entries.push((entry.path().to_string(), entry.mode()));
+        }
+        assert_eq!(
+            entries,
+            [
+                (format!("notion/{PAGE_ID}/"), EntryMode::DIR),
+                (format!("notion/{OTHER_ID}/"), EntryMode::DIR),
+            ]
+        );
+
+        assert_eq!(
+            layout.listed_database(&OTHER_ID.replace('-', "")),
+            Some(OTHER_ID)
+        );
+        assert_eq!(layout.listed_database("not-a-database"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_are_split_into_chunks() {
+        let chunks = |buffer: Buffer, chunk_size: usize| async move {
+            let mut reader = NotionReader::new(buffer, chunk_size);
+            let mut chunks = Vec::new();
+            loop {
+                let chunk = oio::Read::read(&mut reader).await.unwrap();
+                if chunk.is_empty() {
+                    break chunks;
+                }
+                chunks.push(String::from_utf8(chunk.to_vec()).unwrap());
+            }
+        };
+
+        let content = Buffer::from(CONTENT.as_bytes().to_vec());
+        assert_eq!(
+            chunks(content.clone(), 16).await,
+            ["0123456789abcdef", "ghijklmnopqrstuv", "wxyz"]
+        );
+        assert_eq!(chunks(content.clone(), 64).await, [CONTENT]);
+        assert_eq!(chunks(content, 36).await, [CONTENT]);
+
+        // A range keeps its own chunk boundaries, starting at its offset.
+        let range = slice_range(CONTENT.to_string(), BytesRange::new(10, Some(20)));
+        assert_eq!(chunks(range, 8).await, ["abcdefgh", "ijklmnop", "qrst"]);
+    }
+
+    #[test]
+    fn stat_reports_the_size_only_when_rendered() {
+        let page = page();
+        let fast = accessor(NotionServiceBuilder::default()).page_metadata(&page, None);
+        assert_eq!(fast.content_length(), 0);
+        assert_eq!(fast.content_type(), Some("text/markdown"));
+        assert_eq!(fast.last_modified(), Some(page.last_edited_time));
+
+        let exact = accessor(
+            NotionServiceBuilder::default()
+                .stat_exact_size(true)
+                .content_mode(ContentMode::Json),
+        );
+        let meta = exact.page_metadata(&page, Some("{\"id\":\"page\"}"));
+        assert_eq!(meta.content_length(), 13);
+        assert_eq!(meta.content_type(), Some("application/json"));
+        assert_eq!(meta.last_modified(), Some(page.last_edited_time));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn appends_are_retried_through_bursts_of_errors() {
+        let blocks = markdown_to_paragraphs(&["paragraph"; 150].join("\n\n"));
+        assert_eq!(blocks.len(), 150);
+
+        let mut responses =
+            VecDeque::from([Some(429), Some(429), Some(429), None, Some(503), None]);
+        let mut batches = Vec::new();
+        append_blocks(&blocks, Duration::from_secs(30), 3, |request| {
+            batches.push(request.children.len());
+            let status = responses.pop_front().expect("no more calls than responses");
+            async move {
+                match status {
+                    Some(status) => Err(notion_error(status)),
+                    None => Ok(()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(batches, [100, 100, 100, 100, 50, 50]);
+
+        let err = append_blocks(&blocks, Duration::from_secs(30), 3, |_| async {
+            Err::<(), _>(notion_error(429))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::RateLimited);
+    }
+
+    #[test]
+    fn untitled_pages_are_listed_by_the_template() {
+        let templated =
+            accessor(NotionServiceBuilder::default().untitled_template("untitled-{short_id}"));
+        assert_eq!(templated.page_slug(&page()), "untitled-1a2b3c4d");
+        assert_eq!(
+            accessor(NotionServiceBuilder::default()).page_slug(&page()),
+            PAGE_ID
+        );
+    }
+
+    /// Write `text` to a config file named after the test, in the temp dir.
+    fn config_file(name: &str, text: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("notion-opendal-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, text).expect("writable temp dir");
+        path
+    }
+
+    #[test]
+    fn config_files_are_loaded_with_the_token_from_the_environment() {
+        std::env::set_var("NOTION_OPENDAL_TEST_TOKEN", "secret_from_env");
+        let path = config_file(
+            "loaded",
+            r#"
+token = "ntn_${NOTION_OPENDAL_TEST_TOKEN}"
+database_id = "1a2b3c4d5e6f70819a2b3c4d5e6f7081"
+frontmatter = true
+link_mode = "slug"
+max_retries = 5
+"#,
+        );
+
+        let builder = NotionServiceBuilder::from_config_file(&path).expect("valid config");
+        std::fs::remove_file(&path).expect("removable config");
+
+        let config = &builder.config;
+        assert_eq!(config.token.as_deref(), Some("ntn_secret_from_env"));
+        assert_eq!(
+            config.database_id.as_deref(),
+            Some("1a2b3c4d5e6f70819a2b3c4d5e6f7081")
+        );
+        assert!(config.frontmatter);
+        assert_eq!(config.link_mode, LinkMode::Slug);
+        assert_eq!(config.max_retries, Some(5));
+        // Keys missing from the file keep their defaults.
+        assert!(!config.slug);
+        assert_eq!(config.timeout_secs, None);
+    }
+
+    #[test]
+    fn config_files_with_unset_variables_are_rejected() {
+        std::env::remove_var("NOTION_OPENDAL_TEST_UNSET");
+        let path = config_file("unset", r#"token = "${NOTION_OPENDAL_TEST_UNSET}""#);
+
+        let err = NotionServiceBuilder::from_config_file(&path).expect_err("unset variable");
+        std::fs::remove_file(&path).expect("removable config");
+
+        assert_eq!(err.kind(), ErrorKind::ConfigInvalid);
+        assert!(err.to_string().contains("NOTION_OPENDAL_TEST_UNSET"));
+    }
+
+    #[test]
+    fn missing_and_malformed_config_files_are_rejected() {
+        let missing = std::env::temp_dir().join("notion-opendal-missing.toml");
+        let err = NotionServiceBuilder::from_config_file(&missing).expect_err("missing file");
+        assert_eq!(err.kind(), ErrorKind::ConfigInvalid);
+
+        let path = config_file("malformed", "frontmatter = \"yes\"");
+        let err = NotionServiceBuilder::from_config_file(&path).expect_err("malformed config");
+        std::fs::remove_file(&path).expect("removable config");
+        assert_eq!(err.kind(), ErrorKind::ConfigInvalid);
+    }
+
+    #[test]
+    fn notion_request_ids_reach_the_error_context() {
+        let error = |request_id: &str| {
+            let error = serde_json::from_value(serde_json::json!({
+                "object": "error",
+                "status": 404,
+                "code": "object_not_found",
+                "message": "Could not find page",
+                "request_id": request_id,
+            }))
+            .expect("valid notion error");
+            map_notion_error(NotionClientError::InvalidStatusCode { error })
+        };
+
+        let err = error("abc");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(
+            err.to_string()
+                .contains("context: { notion_request_id: abc }"),
+            "{err}"
+        );
+        assert!(!error("").to_string().contains("notion_request_id"));
+        assert!(!map_notion_error(notion_error(404))
+            .to_string()
+            .contains("notion_request_id"));
+    }
+
+    #[test]
+    fn json_content_omits_a_missing_icon_and_cover() {
+        let json = accessor(NotionServiceBuilder::default().content_mode(ContentMode::Json));
+        let document = |page: &NotionPage| {
+            let content = json
+                .page_content(page, HashMap::new(), "Hello".to_string())
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&content).unwrap()
+        };
+
+        let mut page = page();
+        assert_eq!(
+            document(&page),
+            serde_json::json!({ "id": PAGE_ID, "properties": {}, "content": "Hello" })
+        );
+
+        page.icon = serde_json::from_value(serde_json::json!({ "type": "emoji", "emoji": "🚀" }))
+            .expect("valid icon");
+        page.cover = serde_json::from_value(serde_json::json!({
+            "type": "external",
+            "external": { "url": "https://example.com/cover.png" },
+        }))
+        .expect("valid cover");
+        assert_eq!(
+            document(&page),
+            serde_json::json!({
+                "id": PAGE_ID,
+                "icon": "🚀",
+                "cover": "https://example.com/cover.png",
+                "properties": {},
+                "content": "Hello",
+            })
+        );
+    }
+}
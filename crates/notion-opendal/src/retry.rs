@@ -0,0 +1,212 @@
+use std::future::Future;
+use std::time::Duration;
+
+use log::warn;
+use notion_client::NotionClientError;
+
+/// Number of retries used when the caller does not configure one.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Delay before the first retry; doubled on every following attempt.
+///
+/// `notion_client` does not expose response headers, so the `Retry-After`
+/// value Notion sends with a 429 cannot be read. Notion's documented average
+/// limit is three requests per second, so backing off from one second keeps
+/// us comfortably below it.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Errors that may carry a Notion `rate_limited` response.
+pub trait RateLimited {
+    fn is_rate_limited(&self) -> bool;
+
+    /// Whether the error is worth retrying: a rate limit or a 5xx from
+    /// Notion.
+    fn is_transient(&self) -> bool {
+        self.is_rate_limited()
+    }
+}
+
+impl RateLimited for NotionClientError {
+    fn is_rate_limited(&self) -> bool {
+        matches!(self, NotionClientError::InvalidStatusCode { error } if error.status == 429)
+    }
+
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            NotionClientError::InvalidStatusCode { error }
+                if error.status == 429 || error.status >= 500
+        )
+    }
+}
+
+impl RateLimited for anyhow::Error {
+    fn is_rate_limited(&self) -> bool {
+        self.downcast_ref::<NotionClientError>()
+            .is_some_and(RateLimited::is_rate_limited)
+    }
+
+    fn is_transient(&self) -> bool {
+        self.downcast_ref::<NotionClientError>()
+            .is_some_and(RateLimited::is_transient)
+    }
+}
+
+/// Run `op`, retrying up to `max_retries` times with exponential backoff
+/// while Notion answers with HTTP 429. The last error is returned once the
+/// retries are exhausted.
+pub async fn retry_rate_limited<T, E, F, Fut>(max_retries: u32, op: F) -> Result<T, E>
+where
+    E: RateLimited,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_while(max_retries, op, RateLimited::is_rate_limited).await
+}
+
+/// Like [`retry_rate_limited`], but also retries Notion 5xx responses.
+pub async fn retry_transient<T, E, F, Fut>(max_retries: u32, op: F) -> Result<T, E>
+where
+    E: RateLimited,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_while(max_retries, op, RateLimited::is_transient).await
+}
+
+async fn retry_while<T, E, F, Fut>(
+    max_retries: u32,
+    mut op: F,
+    retryable: fn(&E) -> bool,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(err) if retryable(&err) && attempt < max_retries => {
+                let delay = BASE_DELAY * 2_u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "notion request failed, retrying in {}ms (attempt {attempt}/{max_retries})",
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn notion_error(status: u32) -> NotionClientError {
+        let error = serde_json::from_value(serde_json::json!({
+            "object": "error",
+            "status": status,
+            "code": "rate_limited",
+            "message": "Rate limited",
+            "request_id": null,
+        }))
+        .expect("valid notion error");
+        NotionClientError::InvalidStatusCode { error }
+    }
+
+    fn status(err: &NotionClientError) -> Option<u32> {
+        match err {
+            NotionClientError::InvalidStatusCode { error } => Some(error.status),
+            _ => None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_rate_limits_until_success() {
+        let calls = AtomicU32::new(0);
+        let result = retry_rate_limited(3, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(notion_error(429)),
+                _ => Ok("page"),
+            }
+        })
+        .await;
+
+        assert_eq!(result.ok(), Some("page"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_retries() {
+        let calls = AtomicU32::new(0);
+        let started = tokio::time::Instant::now();
+        let result: Result<(), _> = retry_rate_limited(2, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(notion_error(429))
+        })
+        .await;
+
+        assert_eq!(result.as_ref().err().and_then(status), Some(429));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // Backed off 1s, then 2s.
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_retry_other_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_rate_limited(3, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(notion_error(404))
+        })
+        .await;
+
+        assert_eq!(result.as_ref().err().and_then(status), Some(404));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_server_errors_until_success() {
+        let calls = AtomicU32::new(0);
+        let result = retry_transient(3, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(notion_error(502)),
+                _ => Ok("page"),
+            }
+        })
+        .await;
+
+        assert_eq!(result.ok(), Some("page"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_retry_client_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_transient(3, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(notion_error(400))
+        })
+        .await;
+
+        assert_eq!(result.as_ref().err().and_then(status), Some(400));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_persistent_server_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_transient(2, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(notion_error(503))
+        })
+        .await;
+
+        assert_eq!(result.as_ref().err().and_then(status), Some(503));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,57 +1,678 @@
-use std::{collections::HashMap, net::SocketAddr, time::Instant};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    future::Future,
+    io::Write,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 use axum::{
-    Json, Router,
+    Extension, Json, Router, ServiceExt,
     body::Body,
-    extract::{FromRequestParts, Path, Query},
-    http::{HeaderMap, Request, StatusCode, header, request::Parts},
+    extract::{FromRequestParts, MatchedPath, Path, Query, RawQuery, State},
+    http::{
+        Extensions, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Version,
+        header, request::Parts,
+    },
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::get,
 };
 use axum_extra::headers::authorization::Bearer;
-use axum_extra::headers::{Authorization, HeaderMapExt};
-use log::{error, info, warn};
-use logforth::{filter::env_filter::EnvFilterBuilder, starter_log};
+use axum_extra::headers::{Authorization, HeaderMapExt, IfModifiedSince, LastModified};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture};
+use futures::stream::{self, Stream};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use logforth::{filter::env_filter::EnvFilterBuilder, layout::JsonLayout, starter_log};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use moka::sync::Cache;
 use notion_client::NotionClientError;
 use notion_client::endpoints::Client as NotionClient;
-use notion_client::endpoints::databases::query::request::QueryDatabaseRequest;
-use notion_opendal::notion::{PropertyValue, apply_frontmatter, notion_page_to_properties};
-use notion2md::builder::NotionToMarkdownBuilder;
+use notion_client::endpoints::databases::query::request::{
+    CheckBoxCondition, Filter, FilterType, MultiSelectCondition, NumberCondition,
+    PropertyCondition, QueryDatabaseRequest, RichTextCondition, SelectCondition, Sort,
+    SortDirection, StatusCondition, Timestamp,
+};
+use notion_client::endpoints::databases::query::response::QueryDatabaseResponse;
+use notion_client::objects::block::{Block, BlockType};
+use notion_client::objects::database::{Database, DatabaseProperty, NumberFormat};
+use notion_client::objects::file::{ExternalFile, File};
+use notion_client::objects::page::Page as NotionPage;
+use notion_client::objects::parent::Parent;
+use notion_opendal::markdown::{
+    CalloutStyle, LinkMode, RenderOptions, linked_page_ids, offset_headings, rewrite_page_links,
+    strip_comments,
+};
+use notion_opendal::notion::{
+    ClosingFence, FrontmatterFormat, FrontmatterStyle, LocalizedNames, MAX_PAGE_SIZE,
+    PropertyValue, alias_properties, apply_frontmatter, clamp_page_size, detail_people,
+    exclude_properties, format_numbers, include_properties, insert_timestamps, is_valid_notion_id,
+    new_notion_client, normalize_notion_id, notion_page_to_properties, number_formats, page_cover,
+    page_icon, page_id_from_url, page_slug, page_title, parent_database_id, property_is_true,
+    property_value_to_string,
+};
+use notion_opendal::retry::{DEFAULT_MAX_RETRIES, retry_rate_limited};
+use notion2md::notion_to_md::{BlockWithChildren, NotionToMarkdown};
+use pulldown_cmark::{Options, Parser};
+use schemars::{JsonSchema, Schema, schema_for};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::normalize_path::NormalizePath;
+use zip::result::ZipResult;
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
 
-struct MaybeBearerToken(Option<String>);
+#[derive(Clone)]
+struct AppState {
+    /// Rendered Markdown keyed by page ID and render options; `None` when
+    /// caching is disabled.
+    page_cache: Option<Cache<(String, PageRender), CachedPage>>,
+    /// Notion clients keyed by a SHA-256 hash of their token, so connection
+    /// pools are reused across requests without keeping raw tokens around.
+    client_cache: Cache<[u8; 32], NotionClient>,
+    /// How many times a Notion call is retried after an HTTP 429.
+    notion_max_retries: u32,
+    /// Upper bound for a single Notion call, including rate-limit retries.
+    notion_timeout: Duration,
+    /// How many pages each database query asks Notion for, within `1..=100`.
+    notion_page_size: u32,
+    /// Token used when a request carries none of its own; `None` requires
+    /// every request to bring a token.
+    default_token: Option<String>,
+    /// Token used by `/readyz` to check that Notion is reachable.
+    readiness_token: Option<String>,
+    /// Normalized IDs of the databases that may be listed; `None` allows
+    /// every database.
+    allowed_database_ids: Option<HashSet<String>>,
+    /// Permits for requests that call Notion; `None` when unlimited.
+    request_permits: Option<Arc<Semaphore>>,
+    /// How long a request waits for a permit before giving up with a 503.
+    request_queue_timeout: Duration,
+    /// Deadline for a whole request; `None` when unbounded.
+    request_timeout: Option<Duration>,
+    /// How many pages bulk endpoints render at once.
+    render_concurrency: usize,
+    /// Frontmatter keys written first, in this order; the rest follow
+    /// alphabetically.
+    frontmatter_key_order: Arc<[String]>,
+    /// Frontmatter key renames, from property name to the key written.
+    frontmatter_aliases: Arc<HashMap<String, String>>,
+    /// Per-token request limit; `None` when unlimited.
+    rate_limiter: Option<RateLimiter>,
+    /// Scheme and host prefixed to image proxy links, without a trailing
+    /// slash; empty for root-relative links.
+    public_base_url: Arc<str>,
+    /// Client for fetching files from Notion's file storage.
+    http_client: reqwest::Client,
+    /// `User-Agent` sent to Notion and its file storage.
+    user_agent: Arc<str>,
+    /// Header checked for a raw token when there is no bearer token.
+    token_header: HeaderName,
+    /// Slug template for untitled pages; `None` names them by their ID.
+    untitled_template: Option<Arc<str>>,
+    /// Largest rendered page served, in bytes; `None` when unlimited.
+    max_markdown_bytes: Option<usize>,
+    /// Naming of per-language property copies; `None` when properties
+    /// aren't localized.
+    localized_names: Option<LocalizedNames>,
+    /// Language used when none from `Accept-Language` is available.
+    default_language: Arc<str>,
+}
+
+/// A page rendered to more Markdown than `MAX_MARKDOWN_BYTES` allows.
+#[derive(Debug)]
+struct MarkdownTooLarge {
+    size: usize,
+    limit: usize,
+}
+
+impl std::fmt::Display for MarkdownTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rendered page is {} bytes, over the limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MarkdownTooLarge {}
+
+/// Everything that changes the Markdown rendered from a page revision.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
+struct PageRender {
+    options: RenderOptions,
+    /// Point Notion-hosted images at `/image/{page_id}/{block_id}` instead
+    /// of their expiring signed URLs. Only honoured with a `NOTION_API_TOKEN`,
+    /// since browsers load the links without a token of their own.
+    refresh_images: bool,
+    /// Render only this many levels of nested blocks, counting the page's
+    /// own blocks as the first; `None` renders everything.
+    max_depth: Option<usize>,
+}
+
+#[derive(Clone)]
+struct CachedPage {
+    last_edited_time: DateTime<Utc>,
+    markdown: String,
+}
+
+/// Token buckets keyed by a SHA-256 hash of the Notion token. Each bucket
+/// holds up to `per_minute` requests and refills continuously at that rate.
+#[derive(Clone)]
+struct RateLimiter {
+    per_minute: u32,
+    /// A bucket left idle for a minute is full again, so evicting it then
+    /// loses nothing.
+    buckets: Cache<[u8; 32], Arc<Mutex<RateBucket>>>,
+}
+
+struct RateBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Buckets kept at most; the least recently used are evicted beyond it.
+    const MAX_BUCKETS: u64 = 10_000;
+
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Cache::builder()
+                .max_capacity(Self::MAX_BUCKETS)
+                .time_to_idle(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    /// Take one request from the bucket of `token`, or return how long until
+    /// the next one is available.
+    fn acquire(&self, token: &str) -> Result<(), Duration> {
+        let capacity = f64::from(self.per_minute);
+        let per_second = capacity / 60.0;
+        let bucket = self.buckets.get_with(token_key(token), || {
+            Arc::new(Mutex::new(RateBucket {
+                tokens: capacity,
+                refilled_at: Instant::now(),
+            }))
+        });
+        let mut bucket = bucket.lock().unwrap_or_else(|err| err.into_inner());
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+impl AppState {
+    fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let cache_size: u64 = env_or("CACHE_SIZE", 1000)?;
+        let page_cache = (cache_size > 0).then(|| Cache::new(cache_size));
+        let client_cache = Cache::new(env_or("CLIENT_CACHE_SIZE", 100)?);
+        let notion_max_retries = env_or("NOTION_MAX_RETRIES", DEFAULT_MAX_RETRIES)?;
+        let notion_timeout = Duration::from_secs(env_or("NOTION_TIMEOUT_SECS", 30)?);
+        let notion_page_size = clamp_page_size(env_or("NOTION_PAGE_SIZE", MAX_PAGE_SIZE)?);
+        let default_token = std::env::var("NOTION_API_TOKEN")
+            .ok()
+            .filter(|token| !token.trim().is_empty());
+        let readiness_token = std::env::var("NOTION_READINESS_TOKEN")
+            .ok()
+            .filter(|token| !token.trim().is_empty())
+            .or_else(|| default_token.clone());
+        let allowed_database_ids = allowed_database_ids_from_env()?;
+        let max_concurrent_requests: usize = env_or("MAX_CONCURRENT_REQUESTS", 64)?;
+        let request_permits = (max_concurrent_requests > 0)
+            .then(|| Arc::new(Semaphore::new(max_concurrent_requests)));
+        let request_queue_timeout = Duration::from_secs(env_or("REQUEST_QUEUE_TIMEOUT_SECS", 10)?);
+        let request_timeout_secs: u64 = env_or("REQUEST_TIMEOUT_SECS", 0)?;
+        let request_timeout =
+            (request_timeout_secs > 0).then(|| Duration::from_secs(request_timeout_secs));
+        let render_concurrency = env_or("RENDER_CONCURRENCY", 3usize)?.max(1);
+        let frontmatter_key_order = std::env::var("FRONTMATTER_KEY_ORDER")
+            .map(|keys| comma_separated(&keys).map(str::to_string).collect())
+            .unwrap_or_default();
+        let frontmatter_aliases = match std::env::var("FRONTMATTER_KEY_ALIASES") {
+            Ok(aliases) => serde_json::from_str(&aliases)
+                .map_err(|err| format!("invalid FRONTMATTER_KEY_ALIASES value: {err}"))?,
+            Err(_) => HashMap::new(),
+        };
+        let rate_limit_per_minute: u32 = env_or("RATE_LIMIT_PER_MINUTE", 0)?;
+        let rate_limiter =
+            (rate_limit_per_minute > 0).then(|| RateLimiter::new(rate_limit_per_minute));
+        let token_header = token_header_from_env()?;
+        let user_agent = user_agent_from_env()?;
+        let http_client = reqwest::Client::builder()
+            .user_agent(user_agent.as_str())
+            .build()?;
+        let public_base_url = std::env::var("PUBLIC_BASE_URL")
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        let max_markdown_bytes: usize = env_or("MAX_MARKDOWN_BYTES", 0)?;
+        let localized_names = match std::env::var("LOCALIZED_PROPERTY_PATTERN") {
+            Ok(template) => Some(LocalizedNames::new(&template).ok_or_else(|| {
+                format!(
+                    "invalid LOCALIZED_PROPERTY_PATTERN value {template:?}: expected {{name}} and {{lang}}"
+                )
+            })?),
+            Err(_) => None,
+        };
+        let default_language = std::env::var("DEFAULT_LANGUAGE")
+            .map(|lang| lang.trim().to_string())
+            .unwrap_or_else(|_| "en".to_string());
+        let untitled_template = std::env::var("UNTITLED_SLUG_TEMPLATE")
+            .ok()
+            .filter(|template| !template.trim().is_empty())
+            .map(Into::into);
+
+        Ok(Self {
+            page_cache,
+            client_cache,
+            notion_max_retries,
+            notion_timeout,
+            notion_page_size,
+            default_token,
+            readiness_token,
+            allowed_database_ids,
+            request_permits,
+            request_queue_timeout,
+            request_timeout,
+            render_concurrency,
+            frontmatter_key_order,
+            frontmatter_aliases: Arc::new(frontmatter_aliases),
+            rate_limiter,
+            public_base_url: public_base_url.into(),
+            http_client,
+            user_agent: user_agent.into(),
+            token_header,
+            untitled_template,
+            max_markdown_bytes: (max_markdown_bytes > 0).then_some(max_markdown_bytes),
+            localized_names,
+            default_language: default_language.into(),
+        })
+    }
+
+    /// Languages to pick localized properties in, from `Accept-Language`
+    /// and then `DEFAULT_LANGUAGE`. Empty when localization is off.
+    fn request_languages(&self, headers: &HeaderMap) -> Vec<String> {
+        if self.localized_names.is_none() {
+            return Vec::new();
+        }
+        let mut languages = accept_languages(headers);
+        languages.push(self.default_language.to_string());
+        languages
+    }
+
+    /// Mark responses that depend on `Accept-Language` as such for caches.
+    fn vary_language(&self, response: &mut Response) {
+        if self.localized_names.is_some() {
+            response
+                .headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-language"));
+        }
+    }
+
+    /// The property titles are taken from: `title_property` when given,
+    /// otherwise the localized copy of the title when there is one.
+    fn title_property(&self, page: &NotionPage, params: &GetPageParams) -> Option<String> {
+        params.title_property.clone().or_else(|| {
+            self.localized_names
+                .as_ref()?
+                .title_property(page, &params.languages)
+        })
+    }
+
+    /// Slug for `page`, naming untitled pages with `UNTITLED_SLUG_TEMPLATE`.
+    fn page_slug(&self, page: &NotionPage, title_property: Option<&str>) -> String {
+        page_slug(page, title_property, self.untitled_template.as_deref())
+    }
+
+    /// Pick the token for a request: the one from its headers, or the
+    /// configured `NOTION_API_TOKEN` when the request has none.
+    fn request_token(&self, header_token: Option<String>) -> Result<String, ApiError> {
+        header_token
+            .or_else(|| self.default_token.clone())
+            .ok_or_else(|| {
+                warn!("missing Notion token in request headers");
+                ApiError::new(StatusCode::UNAUTHORIZED, "missing Notion token")
+            })
+    }
+
+    /// Reject databases outside `ALLOWED_DATABASE_IDS` with a 403. `id` must
+    /// already be normalized.
+    fn check_database_allowed(&self, id: &str) -> Result<(), ApiError> {
+        match &self.allowed_database_ids {
+            Some(allowed) if !allowed.contains(&id.to_ascii_lowercase()) => {
+                warn!("database {id} is not in ALLOWED_DATABASE_IDS");
+                Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    format!("database {id} is not allowed on this server"),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the Notion client for `token`, building and caching it on first
+    /// use.
+    fn notion_client(&self, token: &str) -> Result<NotionClient, ApiError> {
+        self.client_cache
+            .try_get_with(token_key(token), || {
+                new_notion_client(token.to_string(), &self.user_agent)
+            })
+            .map_err(|err| {
+                error!("failed to create notion client from header token: {err:?}");
+                ApiError::new(StatusCode::UNAUTHORIZED, "invalid Notion token")
+            })
+    }
+
+    /// Render `id` to Markdown, reusing the cached render when the page has
+    /// not been edited since.
+    ///
+    /// Notion reports `last_edited_time` at minute granularity, so edits made
+    /// within the same minute as the cached render are not picked up until the
+    /// next change.
+    async fn render_markdown(
+        &self,
+        client: &NotionClient,
+        render: PageRender,
+        id: &str,
+        last_edited_time: DateTime<Utc>,
+    ) -> anyhow::Result<String> {
+        if let Some(markdown) = self.cached_markdown(id, render, last_edited_time) {
+            debug!("serving notion page {id} from cache");
+            return Ok(markdown);
+        }
+
+        let converter = render.options.converter(client.clone());
+        let proxy_images = render.refresh_images && self.default_token.is_some();
+        let markdown = if proxy_images || render.max_depth.is_some() {
+            // The converter neither sees block IDs nor limits how deep it
+            // descends, so for these the block tree is fetched here, cut at
+            // `max_depth`, and image URLs are swapped before converting.
+            let blocks = fetch_block_tree(self, client, id, render.max_depth).await?;
+            let blocks = self.prepare_blocks(id, blocks, proxy_images);
+            converter.convert_blocks_to_markdown(&blocks)?
+        } else {
+            retry_rate_limited(self.notion_max_retries, || {
+                timed_notion_call("convert_page", converter.convert_page(id))
+            })
+            .await?
+        };
+        self.check_markdown_size(&markdown)?;
+        self.cache_markdown(id, render, last_edited_time, &markdown);
+        Ok(markdown)
+    }
+
+    /// Reject Markdown larger than `MAX_MARKDOWN_BYTES`.
+    fn check_markdown_size(&self, markdown: &str) -> Result<(), MarkdownTooLarge> {
+        match self.max_markdown_bytes {
+            Some(limit) if markdown.len() > limit => Err(MarkdownTooLarge {
+                size: markdown.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Prepare a fetched block tree for conversion. With `proxy_images`,
+    /// Notion-hosted images are pointed at this server's
+    /// `/image/{page_id}/{block_id}` route; external images don't expire and
+    /// keep their URLs.
+    fn prepare_blocks(
+        &self,
+        page_id: &str,
+        nodes: Vec<BlockNode>,
+        proxy_images: bool,
+    ) -> Vec<BlockWithChildren> {
+        nodes
+            .into_iter()
+            .map(|node| {
+                let mut block = node.block;
+                if let (true, BlockType::Image { image }, Some(block_id)) =
+                    (proxy_images, &mut block.block_type, &block.id)
+                    && matches!(image.file_type, File::File { .. })
+                {
+                    let url = format!("{}/image/{page_id}/{block_id}", self.public_base_url);
+                    image.file_type = File::External {
+                        external: ExternalFile { url },
+                    };
+                }
+                BlockWithChildren {
+                    block,
+                    children: self.prepare_blocks(page_id, node.children, proxy_images),
+                }
+            })
+            .collect()
+    }
+
+    /// Render `pages` (ID and `last_edited_time`) concurrently, at most
+    /// `render_concurrency` at a time so bulk reads stay within Notion's rate
+    /// limit. Results arrive in completion order, each with its page ID, and
+    /// a page that fails doesn't stop the others.
+    fn render_pages<'a>(
+        &'a self,
+        client: &'a NotionClient,
+        render: PageRender,
+        pages: Vec<(String, DateTime<Utc>)>,
+    ) -> impl Stream<Item = (String, Result<String, ApiError>)> + 'a {
+        stream::iter(pages)
+            .map(move |(id, last_edited_time)| async move {
+                let markdown = self.render_markdown(client, render, &id, last_edited_time);
+                let result = match timeout(self.notion_timeout, markdown).await {
+                    Ok(Ok(markdown)) => Ok(markdown),
+                    Ok(Err(err)) => {
+                        error!("failed to render notion page {id}: {err:?}");
+                        Err(ApiError::render(&err))
+                    }
+                    Err(_) => {
+                        error!("timed out rendering notion page {id}");
+                        Err(ApiError::notion_timeout())
+                    }
+                };
+                (id, result)
+            })
+            .buffer_unordered(self.render_concurrency)
+    }
+
+    /// Return the cached Markdown for `id` if it was rendered with the same
+    /// options from the same revision of the page.
+    fn cached_markdown(
+        &self,
+        id: &str,
+        render: PageRender,
+        last_edited_time: DateTime<Utc>,
+    ) -> Option<String> {
+        let entry = self.page_cache.as_ref()?.get(&(id.to_string(), render))?;
+        (entry.last_edited_time == last_edited_time).then_some(entry.markdown)
+    }
 
-impl<S> FromRequestParts<S> for MaybeBearerToken
+    fn cache_markdown(
+        &self,
+        id: &str,
+        render: PageRender,
+        last_edited_time: DateTime<Utc>,
+        markdown: &str,
+    ) {
+        if let Some(cache) = &self.page_cache {
+            cache.insert(
+                (id.to_string(), render),
+                CachedPage {
+                    last_edited_time,
+                    markdown: markdown.to_string(),
+                },
+            );
+        }
+    }
+}
+
+/// SHA-256 hash of a Notion token, used to key per-token state without
+/// keeping raw tokens around.
+fn token_key(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+fn env_or<T>(name: &str, default: T) -> Result<T, Box<dyn std::error::Error>>
 where
-    S: Send + Sync,
+    T: FromStr,
+    T::Err: std::fmt::Display,
 {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid {name} value {value:?}: {err}").into()),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Read `ALLOWED_DATABASE_IDS`, a comma-separated list of database IDs with
+/// or without dashes. Unset means every database is allowed.
+fn allowed_database_ids_from_env() -> Result<Option<HashSet<String>>, Box<dyn std::error::Error>> {
+    let Ok(ids) = std::env::var("ALLOWED_DATABASE_IDS") else {
+        return Ok(None);
+    };
+
+    let allowed = comma_separated(&ids)
+        .map(|id| {
+            if is_valid_notion_id(id) {
+                Ok(normalize_notion_id(&id.to_ascii_lowercase()))
+            } else {
+                Err(format!("invalid ALLOWED_DATABASE_IDS entry {id:?}"))
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Some(allowed))
+}
+
+/// Read `NOTION_USER_AGENT`, defaulting to `notion2md-server/{version}`.
+fn user_agent_from_env() -> Result<String, Box<dyn std::error::Error>> {
+    match std::env::var("NOTION_USER_AGENT") {
+        Ok(user_agent) => {
+            let user_agent = user_agent.trim().to_string();
+            HeaderValue::from_str(&user_agent)
+                .map_err(|err| format!("invalid NOTION_USER_AGENT value {user_agent:?}: {err}"))?;
+            Ok(user_agent)
+        }
+        Err(_) => Ok(concat!("notion2md-server/", env!("CARGO_PKG_VERSION")).to_string()),
+    }
+}
+
+/// Read `TOKEN_HEADER_NAME`, the header carrying a raw token for clients or
+/// proxies that can't send `Authorization: Bearer`. Defaults to `Auth`.
+fn token_header_from_env() -> Result<HeaderName, Box<dyn std::error::Error>> {
+    match std::env::var("TOKEN_HEADER_NAME") {
+        Ok(name) => HeaderName::from_str(name.trim())
+            .map_err(|err| format!("invalid TOKEN_HEADER_NAME value {name:?}: {err}").into()),
+        Err(_) => Ok(HeaderName::from_static("auth")),
+    }
+}
+
+/// Build the CORS layer from `CORS_ALLOW_ORIGINS` (comma-separated origins or
+/// `*`) and `CORS_ALLOW_HEADERS` (extra request headers to allow). CORS stays
+/// disabled when `CORS_ALLOW_ORIGINS` is unset.
+fn cors_layer_from_env(
+    token_header: &HeaderName,
+) -> Result<Option<CorsLayer>, Box<dyn std::error::Error>> {
+    let Ok(origins) = std::env::var("CORS_ALLOW_ORIGINS") else {
+        return Ok(None);
+    };
+
+    let allow_origin = if origins.trim() == "*" {
+        AllowOrigin::any()
+    } else {
+        let origins = comma_separated(&origins)
+            .map(HeaderValue::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid CORS_ALLOW_ORIGINS value {origins:?}: {err}"))?;
+        AllowOrigin::list(origins)
+    };
+
+    // The token headers must always be allowed, or browsers can't send them.
+    let mut allow_headers = vec![
+        header::AUTHORIZATION,
+        token_header.clone(),
+        header::ACCEPT,
+        header::CONTENT_TYPE,
+        HeaderName::from_static("x-notion-version"),
+        REQUEST_ID_HEADER,
+    ];
+    if let Ok(extra) = std::env::var("CORS_ALLOW_HEADERS") {
+        for name in comma_separated(&extra) {
+            let name = HeaderName::from_str(name)
+                .map_err(|err| format!("invalid CORS_ALLOW_HEADERS value {extra:?}: {err}"))?;
+            allow_headers.push(name);
+        }
+    }
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::HEAD])
+            .allow_headers(allow_headers)
+            .expose_headers([header::ETAG, header::LAST_MODIFIED, REQUEST_ID_HEADER]),
+    ))
+}
+
+fn comma_separated(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// The Notion token sent with a request: a bearer token, or else the raw
+/// value of the `TOKEN_HEADER_NAME` header.
+struct MaybeBearerToken(Option<String>);
+
+impl FromRequestParts<AppState> for MaybeBearerToken {
     type Rejection = StatusCode;
 
     fn from_request_parts(
         parts: &mut Parts,
-        _state: &S,
+        state: &AppState,
     ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
-        let headers = parts.headers.clone();
+        let headers = &parts.headers;
 
         let token = headers
             .typed_get::<Authorization<Bearer>>()
             .map(|Authorization(bearer)| bearer.token().to_string())
             .or_else(|| {
-                headers.get("Auth").and_then(|value| match value.to_str() {
-                    Ok(value) => {
-                        let trimmed = value.trim();
-                        if trimmed.is_empty() {
+                headers
+                    .get(&state.token_header)
+                    .and_then(|value| match value.to_str() {
+                        Ok(value) => {
+                            let trimmed = value.trim();
+                            if trimmed.is_empty() {
+                                None
+                            } else {
+                                Some(trimmed.to_string())
+                            }
+                        }
+                        Err(_) => {
+                            warn!("failed to read {} header as UTF-8", state.token_header);
                             None
-                        } else {
-                            Some(trimmed.to_string())
                         }
-                    }
-                    Err(_) => {
-                        warn!("failed to read Auth header as UTF-8");
-                        None
-                    }
-                })
+                    })
             });
 
         async move { Ok(MaybeBearerToken(token)) }
@@ -60,264 +681,4197 @@ where
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    starter_log::stdout()
-        .filter(EnvFilterBuilder::from_default_env_or("info").build())
-        .apply();
+    let logger =
+        starter_log::stdout().filter(EnvFilterBuilder::from_default_env_or("info").build());
+    let log_format = match std::env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => LogFormat::Text,
+        Ok("json") => LogFormat::Json,
+        Ok(other) => {
+            return Err(
+                format!("invalid LOG_FORMAT value {other:?}: expected text or json").into(),
+            );
+        }
+    };
+    match log_format {
+        LogFormat::Text => logger.apply(),
+        LogFormat::Json => logger.layout(JsonLayout::default()).apply(),
+    }
+
+    let state = AppState::from_env()?;
+    let cors = cors_layer_from_env(&state.token_header)?;
+    let compression: bool = env_or("COMPRESSION", true)?;
+    let trim_trailing_slash: bool = env_or("TRIM_TRAILING_SLASH", true)?;
+    let metrics = PrometheusBuilder::new().install_recorder()?;
 
-    let app = Router::new()
-        .route("/page/{id}", get(get_page))
-        .route("/database/{id}", get(list_database_pages))
-        .layer(middleware::from_fn(log_requests));
+    let app = app(state, log_format, metrics);
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+    let app = if compression {
+        app.layer(
+            CompressionLayer::new()
+                .gzip(true)
+                .br(true)
+                .compress_when(compression_predicate()),
+        )
+    } else {
+        app
+    };
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    let addr: SocketAddr = env_or("LISTEN_ADDR", SocketAddr::from(([0, 0, 0, 0], 3000)))?;
     info!("listening on {addr}");
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // Paths have to be rewritten before routing, so the router is wrapped
+    // rather than given another layer.
+    if trim_trailing_slash {
+        let app = NormalizePath::trim_trailing_slash(app);
+        axum::serve(
+            listener,
+            ServiceExt::<Request<Body>>::into_make_service(app),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    } else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+    }
     Ok(())
 }
 
+/// The routes of the server, with everything but CORS, compression and
+/// trailing-slash handling, which depend on configuration.
+fn app(state: AppState, log_format: LogFormat, metrics: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(move || async move { metrics.render() }))
+        .route("/schema/page", get(page_schema))
+        .route("/resolve", get(resolve_url))
+        .merge(
+            Router::new()
+                .route("/page/{id}", get(get_page).head(head_page))
+                .route("/page/{id}/blocks", get(get_page_blocks))
+                .route("/page/{id}/properties", get(get_page_properties))
+                .route("/page/{id}/raw", get(get_page_raw))
+                .route("/image/{page_id}/{block_id}", get(get_image))
+                .route("/file/{page_id}/{block_id}", get(get_file))
+                .route("/pages", get(get_pages))
+                .route("/database/{id}", get(list_database_pages))
+                .route("/database/{id}/pages", get(stream_database_pages))
+                .route("/database/{id}/schema", get(get_database_schema))
+                .route("/database/{id}/markdown", get(get_database_markdown))
+                .route("/database/{id}/export.zip", get(export_database_zip))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    limit_concurrency,
+                ))
+                .route_layer(middleware::from_fn_with_state(state.clone(), limit_rate))
+                // Outermost, so time spent queued for a request slot counts
+                // toward the deadline.
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    limit_duration,
+                )),
+        )
+        .layer(middleware::from_fn_with_state(log_format, log_requests))
+        .with_state(state)
+}
+
+/// Resolve on SIGINT or SIGTERM so in-flight requests can finish before the
+/// process exits.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for SIGINT: {err:?}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                error!("failed to listen for SIGTERM: {err:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("shutting down, waiting for in-flight requests to finish");
+}
+
 async fn get_page(
+    State(state): State<AppState>,
     Path(id): Path<String>,
     headers: HeaderMap,
-    Query(params): Query<GetPageParams>,
+    Query(mut params): Query<GetPageParams>,
+    RawQuery(query): RawQuery,
     MaybeBearerToken(token): MaybeBearerToken,
-) -> Result<Response, StatusCode> {
-    if id.contains('/') || id.contains("..") {
-        warn!("invalid page id: {id}");
-        return Err(StatusCode::BAD_REQUEST);
-    }
+) -> Result<Response, ApiError> {
+    let PageRequest {
+        id,
+        client,
+        format,
+        page: notion_page,
+    } = page_request(&state, &id, &headers, &mut params, token).await?;
 
-    let token = notion_token_from_header(token)?;
-    let client = notion_client_from_token(&token)?;
-    let converter = NotionToMarkdownBuilder::new(client.clone()).build();
-    let format = page_response_format(&headers);
-
-    let notion_page = client
-        .pages
-        .retrieve_a_page(&id, None)
-        .await
-        .map_err(|err| {
-            let status = map_notion_error(&err);
-            error!("failed to retrieve notion page {id}: {err:?}");
-            status
-        })?;
+    let requirement_met = requirement_met(&notion_page, &params);
+    if !requirement_met && params.require_action.unwrap_or_default() == RequireAction::NoContent {
+        debug!("notion page {id} does not meet require_property");
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
 
-    let properties = notion_page_to_properties(&notion_page);
+    let etag = page_etag(
+        &id,
+        notion_page.last_edited_time,
+        format,
+        query.as_deref(),
+        &params.languages,
+    );
+    let last_modified = LastModified::from(SystemTime::from(notion_page.last_edited_time));
+    if is_not_modified(&headers, &etag, notion_page.last_edited_time) {
+        debug!("notion page {id} not modified");
+        return Ok(not_modified(etag, last_modified));
+    }
 
-    let markdown = converter.convert_page(&id).await.map_err(|err| {
-        error!("failed to render notion page {id}: {err:?}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let formats =
+        database_number_formats(&state, &client, &params, parent_database_id(&notion_page)).await;
+    let properties = page_properties(&state, &notion_page, &params, &formats);
 
-    match format {
+    let mut response = match format {
+        PageResponseFormat::Yaml => {
+            // Only the properties are returned, so the page is never rendered.
+            let properties: BTreeMap<_, _> = properties.into_iter().collect();
+            let yaml = serde_yaml::to_string(&properties).map_err(|err| {
+                error!("failed to serialize properties of notion page {id}: {err:?}");
+                ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to serialize page properties",
+                )
+            })?;
+            ([(header::CONTENT_TYPE, format.content_type())], yaml).into_response()
+        }
         PageResponseFormat::Json => {
+            let markdown = page_markdown(&state, &client, &notion_page, &params).await?;
             let response = PageJsonResponse {
                 id: notion_page.id.clone(),
+                icon: page_icon(&notion_page),
+                cover: page_cover(&notion_page),
                 properties,
                 content: markdown,
             };
-            Ok(Json(response).into_response())
+            Json(response).into_response()
         }
         PageResponseFormat::Markdown => {
-            let content = if params.frontmatter.unwrap_or(false) {
-                apply_frontmatter(&properties, &markdown)
+            let markdown = page_markdown(&state, &client, &notion_page, &params).await?;
+            let content = if params.frontmatter.unwrap_or(false) && requirement_met {
+                with_frontmatter(&state, properties, &markdown, &params)
             } else {
                 markdown
             };
-            Ok((
-                [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")],
-                content,
-            )
-                .into_response())
+            ([(header::CONTENT_TYPE, format.content_type())], content).into_response()
         }
+        PageResponseFormat::Html => {
+            let markdown = page_markdown(&state, &client, &notion_page, &params).await?;
+            // Frontmatter has no place in HTML, so `frontmatter` asks for the
+            // properties as `<meta>` tags instead.
+            let meta = (params.frontmatter.unwrap_or(false) && requirement_met)
+                .then(|| alias_properties(properties, &state.frontmatter_aliases));
+            let title = page_title(
+                &notion_page,
+                state.title_property(&notion_page, &params).as_deref(),
+            );
+            let html = markdown_to_html(&markdown, title.as_deref(), meta.as_ref());
+            ([(header::CONTENT_TYPE, format.content_type())], html).into_response()
+        }
+    };
+
+    if let Some(disposition) = download_disposition(&state, &notion_page, &params, format) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
     }
+
+    let mut response = ([(header::ETAG, etag)], response).into_response();
+    response.headers_mut().typed_insert(last_modified);
+    state.vary_language(&mut response);
+    Ok(response)
 }
 
-#[derive(Deserialize)]
-struct GetPageParams {
-    frontmatter: Option<bool>,
+/// Answer `HEAD /page/{id}` from the page metadata alone: the headers match
+/// those of `GET` (apart from `Content-Length`), but the page is never
+/// rendered.
+async fn head_page(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Query(mut params): Query<GetPageParams>,
+    RawQuery(query): RawQuery,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Response, ApiError> {
+    let PageRequest {
+        id,
+        client: _,
+        format,
+        page: notion_page,
+    } = page_request(&state, &id, &headers, &mut params, token).await?;
+
+    if !requirement_met(&notion_page, &params)
+        && params.require_action.unwrap_or_default() == RequireAction::NoContent
+    {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    let etag = page_etag(
+        &id,
+        notion_page.last_edited_time,
+        format,
+        query.as_deref(),
+        &params.languages,
+    );
+    let last_modified = LastModified::from(SystemTime::from(notion_page.last_edited_time));
+    if is_not_modified(&headers, &etag, notion_page.last_edited_time) {
+        return Ok(not_modified(etag, last_modified));
+    }
+
+    let mut response = ([
+        (header::ETAG, etag),
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+    ],)
+        .into_response();
+    if let Some(disposition) = download_disposition(&state, &notion_page, &params, format) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    response.headers_mut().typed_insert(last_modified);
+    state.vary_language(&mut response);
+    Ok(response)
 }
 
-#[derive(Serialize)]
-struct PageJsonResponse {
+/// A `/page/{id}` request checked against everything `GET` and `HEAD`
+/// reject, with the page it asks for.
+struct PageRequest {
     id: String,
-    properties: HashMap<String, PropertyValue>,
-    content: String,
+    client: NotionClient,
+    format: PageResponseFormat,
+    page: NotionPage,
 }
 
-#[derive(Deserialize)]
-struct ListDatabaseParams {
-    offset: Option<usize>,
-    limit: Option<usize>,
+/// Validate a `/page/{id}` request and retrieve the page. `GET` and `HEAD`
+/// both start here, so they fail the same way.
+async fn page_request(
+    state: &AppState,
+    id: &str,
+    headers: &HeaderMap,
+    params: &mut GetPageParams,
+    token: Option<String>,
+) -> Result<PageRequest, ApiError> {
+    // `/page/{id}.md` and `/page/{id}.json` land here too: the router can't
+    // match a static suffix after a parameter, so the extension is split off
+    // the id and overrides content negotiation.
+    let (id, forced_format) = split_format_extension(id);
+    if !is_valid_notion_id(id) {
+        warn!("invalid page id: {id}");
+        return Err(ApiError::bad_request(format!("invalid page id: {id}")));
+    }
+    let id = normalize_notion_id(id);
+
+    check_notion_version(headers)?;
+    page_render(params)?;
+    params.languages = state.request_languages(headers);
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+    let format = if params.properties_only.unwrap_or(false) {
+        PageResponseFormat::Yaml
+    } else {
+        forced_format.unwrap_or_else(|| page_response_format(headers))
+    };
+
+    let page = retrieve_page(state, &client, &id).await?;
+    check_archived(&page, params)?;
+    Ok(PageRequest {
+        id,
+        client,
+        format,
+        page,
+    })
 }
 
-#[derive(Serialize)]
-struct ListDatabasePagesResponse {
-    total: usize,
-    offset: usize,
-    limit: usize,
-    pages: Vec<String>,
+/// `304 Not Modified` for a page whose `ETag` or edit time the client
+/// already has.
+fn not_modified(etag: String, last_modified: LastModified) -> Response {
+    let mut response = (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    response.headers_mut().typed_insert(last_modified);
+    response
 }
 
-async fn list_database_pages(
-    Path(id): Path<String>,
-    Query(params): Query<ListDatabaseParams>,
-    MaybeBearerToken(token): MaybeBearerToken,
-) -> Result<Json<ListDatabasePagesResponse>, StatusCode> {
-    if id.contains('/') || id.contains("..") {
-        warn!("invalid database id: {id}");
-        return Err(StatusCode::BAD_REQUEST);
+/// `Content-Disposition` asking clients to save the page as `{slug}.md` or
+/// `{slug}.html`, when `download` is set. JSON and YAML are always inline.
+fn download_disposition(
+    state: &AppState,
+    page: &NotionPage,
+    params: &GetPageParams,
+    format: PageResponseFormat,
+) -> Option<HeaderValue> {
+    let extension = match format {
+        PageResponseFormat::Markdown => "md",
+        PageResponseFormat::Html => "html",
+        PageResponseFormat::Json | PageResponseFormat::Yaml => return None,
+    };
+    if !params.download.unwrap_or(false) {
+        return None;
     }
+    let slug = state.page_slug(page, state.title_property(page, params).as_deref());
+    Some(attachment(&format!("{slug}.{extension}")))
+}
 
-    let token = notion_token_from_header(token)?;
-    let notion_client = notion_client_from_token(&token)?;
-    let offset = params.offset.unwrap_or(0);
-    let limit = params.limit.unwrap_or(20);
-    if limit == 0 {
-        warn!("limit of zero requested for database {id}");
-        return Err(StatusCode::BAD_REQUEST);
+/// `Content-Disposition` for downloading a file named `filename`. Slugs may
+/// contain non-ASCII letters, so the name is also given RFC 5987-encoded in
+/// `filename*`, with an ASCII fallback in `filename` for older clients.
+fn attachment(filename: &str) -> HeaderValue {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
     }
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}"
+    ))
+    .expect("the disposition is printable ASCII")
+}
 
-    let mut cursor: Option<String> = None;
-    let mut skipped = 0_usize;
-    let mut total = 0_usize;
-    let mut pages: Vec<String> = Vec::with_capacity(limit);
+/// Retrieve a page, mapping failures to the status to answer with.
+async fn retrieve_page(
+    state: &AppState,
+    client: &NotionClient,
+    id: &str,
+) -> Result<NotionPage, ApiError> {
+    retrieve(state, "retrieve_a_page", "page", id, || {
+        client.pages.retrieve_a_page(id, None)
+    })
+    .await
+}
 
-    loop {
-        let request = QueryDatabaseRequest {
-            start_cursor: cursor.clone(),
-            page_size: Some(100),
-            ..Default::default()
-        };
+/// Make the Notion call retrieving the `what` (`page`, `database`, `block`)
+/// with ID `id`, retrying rate limits, and give up with a 504 once
+/// `NOTION_TIMEOUT_SECS` have passed.
+async fn retrieve<T, F, Fut>(
+    state: &AppState,
+    operation: &'static str,
+    what: &str,
+    id: &str,
+    mut call: F,
+) -> Result<T, ApiError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, NotionClientError>>,
+{
+    timeout(
+        state.notion_timeout,
+        retry_rate_limited(state.notion_max_retries, || {
+            timed_notion_call(operation, call())
+        }),
+    )
+    .await
+    .map_err(|_| {
+        error!("timed out retrieving notion {what} {id}");
+        ApiError::notion_timeout()
+    })?
+    .map_err(|err| {
+        let api_error = ApiError::notion(&err, what);
+        error!("failed to retrieve notion {what} {id}: {err:?}");
+        api_error
+    })
+}
 
-        let response = notion_client
-            .databases
-            .query_a_database(&id, request)
-            .await
-            .map_err(|err| {
-                let status = map_notion_error(&err);
-                error!("failed to query notion database {id}: {err:?}");
-                status
-            })?;
+/// Retrieve a database, mapping failures to the status to answer with.
+async fn retrieve_database(
+    state: &AppState,
+    client: &NotionClient,
+    id: &str,
+) -> Result<Database, ApiError> {
+    retrieve(state, "retrieve_a_database", "database", id, || {
+        client.databases.retrieve_a_database(id)
+    })
+    .await
+}
 
-        let next_cursor = response.next_cursor.clone();
-        total += response.results.len();
+/// Display formats of number properties, by database ID and then property
+/// name.
+type NumberFormats = HashMap<String, HashMap<String, NumberFormat>>;
 
-        for page in response.results {
-            if skipped < offset {
-                skipped += 1;
-                continue;
-            }
+/// Retrieve the number formats of `database_ids` when `format_numbers` is
+/// set, once per database, so bulk requests don't fetch the same schema for
+/// every page. Number formats live in the database schema, not on the page;
+/// pages whose database can't be retrieved keep plain numbers.
+async fn database_number_formats<'a>(
+    state: &AppState,
+    client: &NotionClient,
+    params: &GetPageParams,
+    database_ids: impl IntoIterator<Item = &'a str>,
+) -> NumberFormats {
+    let mut formats = NumberFormats::new();
+    if !params.format_numbers.unwrap_or(false) {
+        return formats;
+    }
+    let database_ids: HashSet<&str> = database_ids.into_iter().collect();
+    for database_id in database_ids {
+        if let Ok(database) = retrieve_database(state, client, database_id).await {
+            formats.insert(database_id.to_string(), number_formats(&database));
+        }
+    }
+    formats
+}
 
-            if pages.len() < limit {
-                pages.push(page.id);
-            }
+/// The page's properties plus the `slug` and timestamp keys when requested,
+/// narrowed to the included ones and then without the excluded ones.
+fn page_properties(
+    state: &AppState,
+    page: &NotionPage,
+    params: &GetPageParams,
+    formats: &NumberFormats,
+) -> HashMap<String, PropertyValue> {
+    let mut properties = notion_page_to_properties(page);
+    if let Some(names) = &state.localized_names {
+        names.localize(&mut properties, &params.languages);
+    }
+    if params.people_detailed.unwrap_or(false) {
+        detail_people(&mut properties, page);
+    }
+    if let Some(formats) = parent_database_id(page).and_then(|id| formats.get(id)) {
+        format_numbers(&mut properties, formats);
+    }
+    if params.slug.unwrap_or(false) {
+        let slug = state.page_slug(page, state.title_property(page, params).as_deref());
+        properties.insert("slug".to_string(), PropertyValue::String(slug));
+    }
+    if params.timestamps.unwrap_or(false) {
+        insert_timestamps(&mut properties, page);
+    }
+    if let Some(included) = &params.include {
+        let included: Vec<String> = comma_separated(included).map(str::to_string).collect();
+        include_properties(&mut properties, &included);
+    }
+    if let Some(excluded) = &params.exclude {
+        let excluded: Vec<String> = comma_separated(excluded).map(str::to_string).collect();
+        exclude_properties(&mut properties, &excluded);
+    }
+    properties
+}
+
+/// Prepend `properties` to `markdown` as frontmatter in the format and style
+/// requested, after applying `FRONTMATTER_KEY_ALIASES`.
+fn with_frontmatter(
+    state: &AppState,
+    properties: HashMap<String, PropertyValue>,
+    markdown: &str,
+    params: &GetPageParams,
+) -> String {
+    apply_frontmatter(
+        &alias_properties(properties, &state.frontmatter_aliases),
+        markdown,
+        params.format.unwrap_or_default(),
+        &state.frontmatter_key_order,
+        FrontmatterStyle {
+            closing_fence: params.closing_fence.unwrap_or_default(),
+            blank_line: params.blank_line.unwrap_or(true),
+        },
+    )
+}
+
+/// Convert rendered page Markdown to a standalone HTML document titled
+/// `title`. `meta` properties, when given, become `<meta name content>` tags
+/// in the head, the HTML counterpart of frontmatter.
+fn markdown_to_html(
+    markdown: &str,
+    title: Option<&str>,
+    meta: Option<&HashMap<String, PropertyValue>>,
+) -> String {
+    // GFM covers the tables, task lists, strikethrough and alerts notion2md
+    // writes.
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_GFM;
+    let mut body = String::with_capacity(markdown.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut body, Parser::new_ext(markdown, options));
+
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    if let Some(title) = title {
+        html.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    }
+    if let Some(meta) = meta {
+        let meta: BTreeMap<_, _> = meta.iter().collect();
+        for (name, value) in meta {
+            html.push_str(&format!(
+                "<meta name=\"{}\" content=\"{}\">\n",
+                escape_html(name),
+                escape_html(&property_value_to_string(value)),
+            ));
         }
+    }
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&body);
+    html.push_str("</body>\n</html>\n");
+    html
+}
 
-        if next_cursor.is_none() {
-            break;
+/// Escape text for use in HTML content and quoted attribute values.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
         }
+    }
+    escaped
+}
 
-        cursor = next_cursor;
+/// The rendering options from the query.
+fn page_render(params: &GetPageParams) -> Result<PageRender, ApiError> {
+    if params.max_depth == Some(0) {
+        return Err(ApiError::bad_request("max_depth must be at least 1"));
     }
+    Ok(PageRender {
+        options: RenderOptions {
+            code_lang: params.code_lang.unwrap_or(false),
+            callouts: params.callouts.unwrap_or_default(),
+        },
+        refresh_images: params.refresh_images.unwrap_or(false),
+        max_depth: params.max_depth,
+    })
+}
 
-    Ok(Json(ListDatabasePagesResponse {
-        total,
-        pages,
-        offset,
-        limit,
-    }))
+/// Render the page body with the rendering options from the query,
+/// optionally starting with the title as a heading.
+async fn page_markdown(
+    state: &AppState,
+    client: &NotionClient,
+    page: &NotionPage,
+    params: &GetPageParams,
+) -> Result<String, ApiError> {
+    let id = &page.id;
+    let render = page_render(params)?;
+    let markdown = timeout(
+        state.notion_timeout,
+        state.render_markdown(client, render, id, page.last_edited_time),
+    )
+    .await
+    .map_err(|_| {
+        error!("timed out rendering notion page {id}");
+        ApiError::notion_timeout()
+    })?
+    .map_err(|err| {
+        error!("failed to render notion page {id}: {err:?}");
+        ApiError::render(&err)
+    })?;
+    Ok(finish_markdown(state, client, page, params, markdown).await)
 }
 
-enum PageResponseFormat {
-    Json,
-    Markdown,
+/// Apply the query's post-processing to a page's rendered Markdown: link
+/// rewriting, comment stripping, the title heading and heading offsets.
+async fn finish_markdown(
+    state: &AppState,
+    client: &NotionClient,
+    page: &NotionPage,
+    params: &GetPageParams,
+    markdown: String,
+) -> String {
+    let title_property = state.title_property(page, params);
+    let markdown = rewrite_links(
+        state,
+        client,
+        params.link_mode.unwrap_or_default(),
+        title_property.as_deref(),
+        markdown,
+    )
+    .await;
+    let markdown = if params.strip_comments.unwrap_or(false) {
+        strip_comments(&markdown)
+    } else {
+        markdown
+    };
+
+    let markdown = match page_title(page, title_property.as_deref()) {
+        Some(title) if params.title_heading.unwrap_or(false) => {
+            format!("# {title}\n\n{markdown}")
+        }
+        _ => markdown,
+    };
+    offset_headings(&markdown, params.heading_offset.unwrap_or(0))
 }
 
-fn page_response_format(headers: &HeaderMap) -> PageResponseFormat {
-    let content_type = headers
-        .get(header::CONTENT_TYPE)
-        .and_then(|value| value.to_str().ok());
+/// Rewrite links to other Notion pages in `markdown`. In slug mode every
+/// linked page is retrieved once for its title; links to pages that can't be
+/// retrieved keep their Notion URL.
+async fn rewrite_links(
+    state: &AppState,
+    client: &NotionClient,
+    mode: LinkMode,
+    title_property: Option<&str>,
+    markdown: String,
+) -> String {
+    if mode == LinkMode::Notion {
+        return markdown;
+    }
 
-    if let Some(content_type) = content_type {
-        if content_type.starts_with("text/markdown") {
-            return PageResponseFormat::Markdown;
+    let mut slugs = HashMap::new();
+    if mode == LinkMode::Slug {
+        for id in linked_page_ids(&markdown) {
+            let page = timeout(
+                state.notion_timeout,
+                retry_rate_limited(state.notion_max_retries, || {
+                    timed_notion_call("retrieve_a_page", client.pages.retrieve_a_page(&id, None))
+                }),
+            )
+            .await;
+            match page {
+                Ok(Ok(page)) => {
+                    slugs.insert(id, state.page_slug(&page, title_property));
+                }
+                Ok(Err(err)) => warn!("failed to resolve linked notion page {id}: {err:?}"),
+                Err(_) => warn!("timed out resolving linked notion page {id}"),
+            }
         }
     }
+    rewrite_page_links(&markdown, mode, &slugs)
+}
 
-    let accept = headers
-        .get(header::ACCEPT)
-        .and_then(|value| value.to_str().ok());
+/// Evaluate the conditional request headers. `If-None-Match` takes precedence
+/// over `If-Modified-Since`; an unparseable `If-Modified-Since` is ignored.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_edited_time: DateTime<Utc>) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return etag_matches(headers, etag);
+    }
 
-    if let Some(value) = accept {
-        for item in value.split(',').map(str::trim) {
-            if item.starts_with("text/markdown") || item.starts_with("text/*") {
-                return PageResponseFormat::Markdown;
+    headers
+        .typed_get::<IfModifiedSince>()
+        .is_some_and(|since| !since.is_modified(SystemTime::from(last_edited_time)))
+}
+
+/// ETag for a rendered page. The rendering only changes when the page is
+/// edited or the caller asks for a different format or different options, so
+/// the tag is derived from those instead of hashing the (expensive) body.
+fn page_etag(
+    id: &str,
+    last_edited_time: DateTime<Utc>,
+    format: PageResponseFormat,
+    query: Option<&str>,
+    languages: &[String],
+) -> String {
+    let format = match format {
+        PageResponseFormat::Json => "json",
+        PageResponseFormat::Markdown => "markdown",
+        PageResponseFormat::Html => "html",
+        PageResponseFormat::Yaml => "yaml",
+    };
+    let digest = Sha256::new()
+        .chain_update(id)
+        .chain_update(last_edited_time.to_rfc3339())
+        .chain_update(format)
+        .chain_update(query.unwrap_or_default())
+        .chain_update(languages.join(","))
+        .finalize();
+    let hex: String = digest[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("\"{hex}\"")
+}
+
+/// Whether `If-None-Match` lists `etag` (or `*`). Weak validators compare
+/// equal to their strong counterpart, as RFC 9110 requires for this header.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[derive(Deserialize)]
+struct GetPageParams {
+    frontmatter: Option<bool>,
+    format: Option<FrontmatterFormat>,
+    slug: Option<bool>,
+    timestamps: Option<bool>,
+    title_heading: Option<bool>,
+    properties_only: Option<bool>,
+    code_lang: Option<bool>,
+    callouts: Option<CalloutStyle>,
+    link_mode: Option<LinkMode>,
+    strip_comments: Option<bool>,
+    /// Levels to demote every heading by, for embedding in another document.
+    heading_offset: Option<usize>,
+    /// Comma-separated property names to keep; all others are left out.
+    include: Option<String>,
+    /// Comma-separated property names to leave out.
+    exclude: Option<String>,
+    refresh_images: Option<bool>,
+    /// Property to take the title from instead of the `title` property.
+    title_property: Option<String>,
+    /// Ask clients to save Markdown as `{slug}.md` instead of showing it.
+    download: Option<bool>,
+    closing_fence: Option<ClosingFence>,
+    blank_line: Option<bool>,
+    people_detailed: Option<bool>,
+    format_numbers: Option<bool>,
+    require_property: Option<String>,
+    require_action: Option<RequireAction>,
+    max_depth: Option<usize>,
+    /// Render archived and trashed pages instead of answering `404`.
+    include_archived: Option<bool>,
+    /// Preferred languages for localized properties, filled in from the
+    /// request headers rather than the query.
+    #[serde(skip)]
+    languages: Vec<String>,
+}
+
+/// What `/page/{id}` does when `require_property` isn't checked.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RequireAction {
+    /// Answer `204 No Content` without rendering the page.
+    #[default]
+    NoContent,
+    /// Render the page, but leave out the frontmatter.
+    NoFrontmatter,
+}
+
+/// Reject archived and trashed pages with `404 Not Found`, as if Notion had
+/// deleted them, unless `include_archived` is set.
+fn check_archived(page: &NotionPage, params: &GetPageParams) -> Result<(), ApiError> {
+    if page.archived && !params.include_archived.unwrap_or(false) {
+        debug!("notion page {} is archived", page.id);
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "page is archived or in the trash",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the page passes `require_property`: the named checkbox is
+/// checked, or no property is required.
+fn requirement_met(page: &NotionPage, params: &GetPageParams) -> bool {
+    match &params.require_property {
+        Some(name) => property_is_true(&notion_page_to_properties(page), name),
+        None => true,
+    }
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+async fn healthz() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "ok" })
+}
+
+/// Report ready only when Notion answers a cheap call made with
+/// `NOTION_READINESS_TOKEN`, or `NOTION_API_TOKEN` when that is unset.
+/// Without either token the server cannot check Notion, so it always reports
+/// unavailable.
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    let unavailable = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(HealthResponse {
+            status: "unavailable",
+        }),
+    );
+
+    let Some(token) = &state.readiness_token else {
+        warn!(
+            "readiness check requested but neither NOTION_READINESS_TOKEN nor NOTION_API_TOKEN is set"
+        );
+        return unavailable;
+    };
+    let Ok(client) = state.notion_client(token) else {
+        return unavailable;
+    };
+
+    match timeout(
+        state.notion_timeout,
+        client.users.retrieve_your_tokens_bot_user(),
+    )
+    .await
+    {
+        Ok(Ok(_)) => (StatusCode::OK, Json(HealthResponse { status: "ok" })),
+        Ok(Err(err)) => {
+            warn!("readiness check failed: {err:?}");
+            unavailable
+        }
+        Err(_) => {
+            warn!("readiness check timed out");
+            unavailable
+        }
+    }
+}
+
+/// A page in the JSON format: its properties and rendered Markdown.
+#[derive(Serialize, JsonSchema)]
+struct PageJsonResponse {
+    /// The page ID, with dashes.
+    id: String,
+    /// The page icon: an emoji or an image URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    /// The cover image URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<String>,
+    /// Page properties by name.
+    properties: HashMap<String, PropertyValue>,
+    /// The page body in Markdown.
+    content: String,
+}
+
+/// JSON Schema of the `/page/{id}?format=json` response, for clients that
+/// generate typed bindings.
+async fn page_schema() -> Json<Schema> {
+    Json(schema_for!(PageJsonResponse))
+}
+
+/// Most page IDs accepted by one `/pages` request.
+const MAX_BULK_PAGE_IDS: usize = 50;
+
+#[derive(Deserialize)]
+struct GetPagesParams {
+    ids: String,
+}
+
+/// One element of the `/pages` response: the page, or why it failed.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BulkPageEntry {
+    Page(PageJsonResponse),
+    Error {
+        id: String,
+        status: u16,
+        error: String,
+        code: &'static str,
+    },
+}
+
+impl BulkPageEntry {
+    fn error(id: String, err: &ApiError) -> Self {
+        BulkPageEntry::Error {
+            id,
+            status: err.status.as_u16(),
+            code: err.code(),
+            error: err.message.clone(),
+        }
+    }
+}
+
+/// Render several pages in one request. Pages are fetched concurrently but
+/// returned in the order their IDs were given; a page that fails gets an
+/// error entry instead of failing the whole request.
+async fn get_pages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(bulk): Query<GetPagesParams>,
+    Query(mut params): Query<GetPageParams>,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Json<Vec<BulkPageEntry>>, ApiError> {
+    let ids: Vec<&str> = comma_separated(&bulk.ids).collect();
+    if ids.is_empty() || ids.len() > MAX_BULK_PAGE_IDS {
+        warn!(
+            "{} page ids requested, expected 1 to {MAX_BULK_PAGE_IDS}",
+            ids.len()
+        );
+        return Err(ApiError::bad_request(format!(
+            "expected 1 to {MAX_BULK_PAGE_IDS} page ids, got {}",
+            ids.len()
+        )));
+    }
+    if let Some(id) = ids.iter().find(|id| !is_valid_notion_id(id)) {
+        warn!("invalid page id: {id}");
+        return Err(ApiError::bad_request(format!("invalid page id: {id}")));
+    }
+    let ids: Vec<String> = ids.into_iter().map(normalize_notion_id).collect();
+
+    check_notion_version(&headers)?;
+    let render = page_render(&params)?;
+    params.languages = state.request_languages(&headers);
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    // Pages are retrieved and checked first, so only the ones that will be
+    // returned go through `render_pages`, like streamed database reads.
+    let retrieved: Vec<(String, Result<NotionPage, ApiError>)> = stream::iter(ids)
+        .map(|id| {
+            let (state, client, params) = (&state, &client, &params);
+            async move {
+                let page = retrieve_page(state, client, &id)
+                    .await
+                    .and_then(|page| check_archived(&page, params).map(|()| page));
+                (id, page)
             }
+        })
+        .buffered(state.render_concurrency)
+        .collect()
+        .await;
+    let skipped = |page: &NotionPage| {
+        !requirement_met(page, &params)
+            && params.require_action.unwrap_or_default() == RequireAction::NoContent
+    };
 
-            if item.starts_with("application/json") || item.starts_with("application/*") {
-                return PageResponseFormat::Json;
+    let pages = retrieved
+        .iter()
+        .filter_map(|(_, page)| page.as_ref().ok())
+        .filter(|page| !skipped(page))
+        .map(|page| (page.id.clone(), page.last_edited_time))
+        .collect();
+    let rendered: HashMap<String, Result<String, ApiError>> =
+        state.render_pages(&client, render, pages).collect().await;
+    let formats = database_number_formats(
+        &state,
+        &client,
+        &params,
+        retrieved
+            .iter()
+            .filter_map(|(_, page)| parent_database_id(page.as_ref().ok()?)),
+    )
+    .await;
+
+    let entries = stream::iter(retrieved)
+        .map(|(id, page)| {
+            let (state, client, params, rendered, formats) =
+                (&state, &client, &params, &rendered, &formats);
+            async move {
+                let page = match page {
+                    Ok(page) if skipped(&page) => return None,
+                    Ok(page) => page,
+                    Err(err) => return Some(BulkPageEntry::error(id, &err)),
+                };
+                let markdown = match &rendered[&page.id] {
+                    Ok(markdown) => markdown.clone(),
+                    Err(err) => return Some(BulkPageEntry::error(id, err)),
+                };
+                let content = finish_markdown(state, client, &page, params, markdown).await;
+                Some(BulkPageEntry::Page(PageJsonResponse {
+                    id: page.id.clone(),
+                    icon: page_icon(&page),
+                    cover: page_cover(&page),
+                    properties: page_properties(state, &page, params, formats),
+                    content,
+                }))
             }
+        })
+        .buffered(state.render_concurrency)
+        .filter_map(future::ready)
+        .collect()
+        .await;
 
-            if item == "*/*" {
-                return PageResponseFormat::Json;
+    Ok(Json(entries))
+}
+
+/// Return a page's properties as JSON without rendering its content.
+async fn get_page_properties(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Query(mut params): Query<GetPageParams>,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Json<HashMap<String, PropertyValue>>, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid page id: {id}");
+        return Err(ApiError::bad_request(format!("invalid page id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+
+    check_notion_version(&headers)?;
+    params.languages = state.request_languages(&headers);
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    let notion_page = retrieve_page(&state, &client, &id).await?;
+    check_archived(&notion_page, &params)?;
+
+    let formats =
+        database_number_formats(&state, &client, &params, parent_database_id(&notion_page)).await;
+    Ok(Json(page_properties(
+        &state,
+        &notion_page,
+        &params,
+        &formats,
+    )))
+}
+
+#[derive(Deserialize)]
+struct ResolveParams {
+    url: String,
+    redirect: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ResolveResponse {
+    id: String,
+}
+
+/// Turn a Notion page URL into the page ID, or redirect to `/page/{id}`.
+/// Only the URL is parsed; Notion is not asked whether the page exists.
+async fn resolve_url(Query(params): Query<ResolveParams>) -> Result<Response, ApiError> {
+    let Some(id) = page_id_from_url(&params.url) else {
+        warn!("no notion page id in url {:?}", params.url);
+        return Err(ApiError::bad_request(format!(
+            "no Notion page ID in url: {}",
+            params.url
+        )));
+    };
+
+    if params.redirect.unwrap_or(false) {
+        let location = format!("/page/{id}");
+        return Ok((StatusCode::FOUND, [(header::LOCATION, location)]).into_response());
+    }
+    Ok(Json(ResolveResponse { id }).into_response())
+}
+
+/// The page object exactly as Notion's API returns it, for debugging how
+/// properties are mapped.
+async fn get_page_raw(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Json<NotionPage>, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid page id: {id}");
+        return Err(ApiError::bad_request(format!("invalid page id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    Ok(Json(retrieve_page(&state, &client, &id).await?))
+}
+
+#[derive(Deserialize)]
+struct GetPageBlocksParams {
+    depth: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct BlockNode {
+    #[serde(flatten)]
+    block: Block,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    children: Vec<BlockNode>,
+}
+
+async fn get_page_blocks(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<GetPageBlocksParams>,
+    headers: HeaderMap,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Json<Vec<BlockNode>>, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid page id: {id}");
+        return Err(ApiError::bad_request(format!("invalid page id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+
+    if params.depth == Some(0) {
+        warn!("depth of zero requested for page {id}");
+        return Err(ApiError::bad_request("depth must be at least 1"));
+    }
+
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    let blocks = timeout(
+        state.notion_timeout,
+        fetch_block_tree(&state, &client, &id, params.depth),
+    )
+    .await
+    .map_err(|_| {
+        error!("timed out retrieving blocks for notion page {id}");
+        ApiError::notion_timeout()
+    })?
+    .map_err(|err| {
+        let api_error = ApiError::notion(&err, "page");
+        error!("failed to retrieve blocks for notion page {id}: {err:?}");
+        api_error
+    })?;
+
+    Ok(Json(blocks))
+}
+
+/// Fetch the children of `block_id`, descending into nested blocks until
+/// `depth` levels have been collected (or without limit when `None`). Each
+/// page of children is retried on its own when Notion rate limits it.
+fn fetch_block_tree<'a>(
+    state: &'a AppState,
+    client: &'a NotionClient,
+    block_id: &'a str,
+    depth: Option<usize>,
+) -> BoxFuture<'a, Result<Vec<BlockNode>, NotionClientError>> {
+    Box::pin(async move {
+        let mut cursor: Option<String> = None;
+        let mut nodes = Vec::new();
+
+        loop {
+            let response = retry_rate_limited(state.notion_max_retries, || {
+                timed_notion_call(
+                    "retrieve_block_children",
+                    client
+                        .blocks
+                        .retrieve_block_children(block_id, cursor.as_deref(), Some(100)),
+                )
+            })
+            .await?;
+
+            for block in response.results {
+                let descend = block.has_children.unwrap_or(false)
+                    && depth.is_none_or(|remaining| remaining > 1);
+                let children = match (&block.id, descend) {
+                    (Some(child_id), true) => {
+                        fetch_block_tree(
+                            state,
+                            client,
+                            child_id,
+                            depth.map(|remaining| remaining - 1),
+                        )
+                        .await?
+                    }
+                    _ => Vec::new(),
+                };
+                nodes.push(BlockNode { block, children });
+            }
+
+            if !response.has_more {
+                break;
             }
+
+            cursor = response.next_cursor;
+        }
+
+        Ok(nodes)
+    })
+}
+
+/// Redirect to the current signed URL of an image block. Notion's URLs for
+/// uploaded files expire after an hour, so Markdown rendered with
+/// `refresh_images` links here instead.
+async fn get_image(
+    State(state): State<AppState>,
+    Path((page_id, block_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Response, ApiError> {
+    let (page_id, block_id) = parse_block_path(&page_id, &block_id)?;
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+    let block = retrieve_block(&state, &client, &block_id).await?;
+    check_block_in_page(&state, &client, &page_id, &block_id, &block).await?;
+
+    let BlockType::Image { image } = block.block_type else {
+        warn!("notion block {block_id} is not an image");
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("block {block_id} is not an image"),
+        ));
+    };
+    let url = NotionToMarkdown::get_file_url(&image.file_type);
+    debug!("redirecting image block {block_id} of page {page_id}");
+    Ok((StatusCode::FOUND, [(header::LOCATION, url)]).into_response())
+}
+
+/// Stream the bytes of an image, file, PDF or video block through this
+/// server, for clients that can't reach Notion's file storage themselves.
+async fn get_file(
+    State(state): State<AppState>,
+    Path((page_id, block_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Response, ApiError> {
+    let (page_id, block_id) = parse_block_path(&page_id, &block_id)?;
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+    let block = retrieve_block(&state, &client, &block_id).await?;
+    check_block_in_page(&state, &client, &page_id, &block_id, &block).await?;
+
+    let Some(file) = block_file(&block.block_type) else {
+        warn!("notion block {block_id} has no file");
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("block {block_id} has no file"),
+        ));
+    };
+    let url = NotionToMarkdown::get_file_url(file);
+
+    // Only the response headers are bounded by the timeout; large files may
+    // take longer to stream.
+    let upstream = timeout(
+        state.notion_timeout,
+        timed_notion_call("fetch_file", state.http_client.get(&url).send()),
+    )
+    .await
+    .map_err(|_| {
+        error!("timed out fetching file of notion block {block_id}");
+        ApiError::bad_gateway("file storage did not answer in time")
+    })?
+    .and_then(reqwest::Response::error_for_status)
+    .map_err(|err| {
+        error!("failed to fetch file of notion block {block_id}: {err:?}");
+        ApiError::bad_gateway("failed to fetch the file from Notion's file storage")
+    })?;
+
+    debug!("proxying file of block {block_id} of page {page_id}");
+    let upstream_headers = upstream.headers().clone();
+    let mut response = Body::from_stream(upstream.bytes_stream()).into_response();
+    for name in [header::CONTENT_TYPE, header::CONTENT_LENGTH] {
+        // reqwest still uses the `http` 0.2 header types, unlike axum.
+        if let Some(value) = upstream_headers
+            .get(name.as_str())
+            .and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok())
+        {
+            response.headers_mut().insert(name, value);
         }
     }
+    Ok(response)
+}
 
-    PageResponseFormat::Json
+/// Validate the IDs of a `/{page_id}/{block_id}` path and return them
+/// normalized.
+fn parse_block_path(page_id: &str, block_id: &str) -> Result<(String, String), ApiError> {
+    for id in [page_id, block_id] {
+        if !is_valid_notion_id(id) {
+            warn!("invalid id in block path: {id}");
+            return Err(ApiError::bad_request(format!("invalid id: {id}")));
+        }
+    }
+    Ok((normalize_notion_id(page_id), normalize_notion_id(block_id)))
 }
 
-fn map_notion_error(err: &NotionClientError) -> StatusCode {
-    match err {
-        NotionClientError::InvalidStatusCode { error } => match error.status {
-            400 => StatusCode::BAD_REQUEST,
-            401 | 403 => StatusCode::UNAUTHORIZED,
-            404 => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        },
-        NotionClientError::InvalidHeader { .. } => StatusCode::UNAUTHORIZED,
-        NotionClientError::FailedToSerialize { .. }
-        | NotionClientError::FailedToDeserialize { .. }
-        | NotionClientError::FailedToRequest { .. }
-        | NotionClientError::FailedToText { .. }
-        | NotionClientError::FailedToBuildRequest { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+/// How many blocks [`check_block_in_page`] climbs through before giving up
+/// on reaching the page.
+const MAX_BLOCK_ANCESTORS: usize = 32;
+
+/// Reject a block that isn't part of page `page_id` with a 404, following
+/// its parents up through any blocks it is nested in. Otherwise the block
+/// routes would serve any block the token can read, under any page ID.
+async fn check_block_in_page(
+    state: &AppState,
+    client: &NotionClient,
+    page_id: &str,
+    block_id: &str,
+    block: &Block,
+) -> Result<(), ApiError> {
+    let mut parent = block.parent.clone();
+    for _ in 0..MAX_BLOCK_ANCESTORS {
+        match parent {
+            Some(Parent::PageId { page_id: parent_id })
+                if normalize_notion_id(&parent_id) == page_id =>
+            {
+                return Ok(());
+            }
+            Some(Parent::BlockId {
+                block_id: parent_id,
+            }) => {
+                let parent_id = normalize_notion_id(&parent_id);
+                parent = retrieve_block(state, client, &parent_id).await?.parent;
+            }
+            _ => break,
+        }
     }
+    warn!("notion block {block_id} is not in page {page_id}");
+    Err(ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("block {block_id} is not in page {page_id}"),
+    ))
 }
 
-fn notion_token_from_header(token: Option<String>) -> Result<String, StatusCode> {
-    token.ok_or_else(|| {
-        warn!("missing Notion token in request headers");
-        StatusCode::UNAUTHORIZED
+async fn retrieve_block(
+    state: &AppState,
+    client: &NotionClient,
+    block_id: &str,
+) -> Result<Block, ApiError> {
+    retrieve(state, "retrieve_a_block", "block", block_id, || {
+        client.blocks.retrieve_a_block(block_id)
     })
+    .await
 }
 
-fn notion_client_from_token(token: &str) -> Result<NotionClient, StatusCode> {
-    NotionClient::new(token.to_string(), None).map_err(|err| {
-        error!("failed to create notion client from header token: {err:?}");
-        StatusCode::UNAUTHORIZED
-    })
+/// The file behind a media block, or `None` for blocks without one.
+fn block_file(block_type: &BlockType) -> Option<&File> {
+    match block_type {
+        BlockType::Image { image } => Some(&image.file_type),
+        BlockType::File { file } => Some(&file.file_type),
+        BlockType::Pdf { pdf } => Some(&pdf.file_type),
+        BlockType::Video { video } => Some(&video.file_type),
+        _ => None,
+    }
 }
 
-async fn log_requests(req: Request<Body>, next: Next) -> Response {
-    let method = req.method().clone();
-    let path = req
-        .uri()
-        .path_and_query()
-        .map(|pq| pq.as_str().to_string())
-        .unwrap_or_else(|| req.uri().path().to_string());
-    let start = Instant::now();
+#[derive(Deserialize)]
+struct ListDatabaseParams {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    sort_by: Option<String>,
+    sort_direction: Option<String>,
+    filter_property: Option<String>,
+    filter_equals: Option<String>,
+    titles: Option<bool>,
+    count: Option<CountMode>,
+    cursor: Option<String>,
+}
 
-    let response = next.run(req).await;
-    let status = response.status();
-    let elapsed_ms = start.elapsed().as_millis();
+/// How `total` is computed when listing a database.
+///
+/// Notion has no count endpoint, so an exact total means paging through the
+/// whole database even when the requested window is filled after the first
+/// batch. `Page` stops as soon as the window is full and reports only the
+/// number of pages returned, trading the total for far fewer API calls on
+/// large databases.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum CountMode {
+    #[default]
+    Exact,
+    Page,
+}
+
+#[derive(Serialize)]
+struct ListDatabasePagesResponse {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    pages: Vec<DatabasePageEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DatabasePageEntry {
+    /// Bare page ID, returned when `titles=false`.
+    Id(String),
+    Page {
+        id: String,
+        title: Option<String>,
+    },
+}
 
-    info!(
-        "handled {method} {path} -> {} in {}ms",
-        status.as_u16(),
-        elapsed_ms
+/// The `offset`/`limit` window of a database listing, filled one query batch
+/// at a time.
+struct DatabaseWindow {
+    offset: usize,
+    limit: usize,
+    titles: bool,
+    /// Whether listing ends once the window is full, instead of paging
+    /// through the whole database to count it.
+    stops_at_window: bool,
+    skipped: usize,
+    /// Pages seen so far, including skipped ones.
+    seen: usize,
+    pages: Vec<DatabasePageEntry>,
+}
+
+impl DatabaseWindow {
+    fn new(offset: usize, limit: usize, titles: bool, stops_at_window: bool) -> Self {
+        Self {
+            offset,
+            limit,
+            titles,
+            stops_at_window,
+            skipped: 0,
+            seen: 0,
+            pages: Vec::with_capacity(limit),
+        }
+    }
+
+    /// How many pages to ask Notion for next. When listing stops at the end
+    /// of the window, Notion is asked for exactly what is still missing, so
+    /// its `next_cursor` points right after the last returned page.
+    fn page_size(&self, max_page_size: u32) -> u32 {
+        if self.stops_at_window {
+            self.offset
+                .saturating_add(self.limit)
+                .saturating_sub(self.seen)
+                .min(max_page_size as usize) as u32
+        } else {
+            max_page_size
+        }
+    }
+
+    fn push(&mut self, results: Vec<NotionPage>) {
+        self.seen += results.len();
+        for page in results {
+            if self.skipped < self.offset {
+                self.skipped += 1;
+                continue;
+            }
+
+            if self.pages.len() < self.limit {
+                let entry = if self.titles {
+                    DatabasePageEntry::Page {
+                        title: page_title(&page, None),
+                        id: page.id,
+                    }
+                } else {
+                    DatabasePageEntry::Id(page.id)
+                };
+                self.pages.push(entry);
+            }
+        }
+    }
+
+    /// Whether listing can stop. The window is only full once every offset
+    /// row has been skipped, so `pages.len() == limit` alone is enough to
+    /// know nothing is missing.
+    fn is_full(&self) -> bool {
+        self.stops_at_window && self.pages.len() == self.limit
+    }
+
+    /// The `total` reported: every page seen, or only the returned ones when
+    /// listing stops at the window.
+    fn total(&self) -> usize {
+        if self.stops_at_window {
+            self.pages.len()
+        } else {
+            self.seen
+        }
+    }
+}
+
+/// The database's properties by name, each with its Notion type (`title`,
+/// `select`, `number`, ...), so clients know which columns they can filter
+/// and sort on.
+async fn get_database_schema(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Json<BTreeMap<String, String>>, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid database id: {id}");
+        return Err(ApiError::bad_request(format!("invalid database id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+    state.check_database_allowed(&id)?;
+
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    let database = retrieve_database(&state, &client, &id).await?;
+    Ok(Json(database_schema(&database)))
+}
+
+/// Notion type of each property in `database`, by property name.
+fn database_schema(database: &Database) -> BTreeMap<String, String> {
+    database
+        .properties
+        .iter()
+        .map(|(name, property)| (name.clone(), database_property_type(property)))
+        .collect()
+}
+
+/// Notion's type name for a database property, as in its `type` field.
+fn database_property_type(property: &DatabaseProperty) -> String {
+    serde_json::to_value(property)
+        .ok()
+        .and_then(|value| value.get("type")?.as_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+async fn list_database_pages(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<ListDatabaseParams>,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Json<ListDatabasePagesResponse>, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid database id: {id}");
+        return Err(ApiError::bad_request(format!("invalid database id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+    state.check_database_allowed(&id)?;
+
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let notion_client = state.notion_client(&token)?;
+    let offset = params.offset.unwrap_or(0);
+    let limit = params.limit.unwrap_or(20);
+    if limit == 0 {
+        warn!("limit of zero requested for database {id}");
+        return Err(ApiError::bad_request("limit must be at least 1"));
+    }
+    // A cursor already marks where the window starts, so it can't be
+    // combined with an offset.
+    let cursor_mode = params.cursor.is_some();
+    if cursor_mode && params.offset.is_some() {
+        warn!("cursor and offset requested together for database {id}");
+        return Err(ApiError::bad_request(
+            "cursor and offset cannot be combined",
+        ));
+    }
+
+    let sorts = database_sorts(&params).inspect_err(|_| {
+        warn!(
+            "invalid sort direction {:?} requested for database {id}",
+            params.sort_direction
+        );
+    })?;
+
+    let filter = match (&params.filter_property, &params.filter_equals) {
+        (None, None) => None,
+        (Some(property), Some(value)) => {
+            let database = retrieve_database(&state, &notion_client, &id).await?;
+
+            let Some(schema) = database.properties.get(property) else {
+                warn!("unknown filter property {property:?} for database {id}");
+                return Err(ApiError::bad_request(format!(
+                    "unknown filter property {property:?}"
+                )));
+            };
+
+            let Some(filter) = property_filter(property, schema, value) else {
+                warn!("unsupported filter {property:?} = {value:?} for database {id}");
+                return Err(ApiError::bad_request(format!(
+                    "unsupported filter {property:?} = {value:?}"
+                )));
+            };
+
+            Some(filter)
+        }
+        _ => {
+            warn!("filter_property and filter_equals must be used together");
+            return Err(ApiError::bad_request(
+                "filter_property and filter_equals must be used together",
+            ));
+        }
+    };
+
+    // An empty `cursor=` starts cursor pagination at the first page.
+    let mut cursor = params.cursor.clone().filter(|cursor| !cursor.is_empty());
+    let mut next_page_cursor: Option<String> = None;
+    let count = params.count.unwrap_or_default();
+    let mut window = DatabaseWindow::new(
+        offset,
+        limit,
+        params.titles.unwrap_or(true),
+        cursor_mode || count == CountMode::Page,
     );
 
+    loop {
+        let request = QueryDatabaseRequest {
+            start_cursor: cursor.clone(),
+            page_size: Some(window.page_size(state.notion_page_size)),
+            sorts: sorts.clone(),
+            filter: filter.clone(),
+        };
+
+        let response = timeout(
+            state.notion_timeout,
+            retry_rate_limited(state.notion_max_retries, || {
+                timed_notion_call(
+                    "query_a_database",
+                    notion_client
+                        .databases
+                        .query_a_database(&id, request.clone()),
+                )
+            }),
+        )
+        .await
+        .map_err(|_| {
+            error!("timed out querying notion database {id}");
+            ApiError::notion_timeout()
+        })?
+        .map_err(|err| {
+            let api_error = ApiError::notion(&err, "database");
+            error!("failed to query notion database {id}: {err:?}");
+            api_error
+        })?;
+
+        let next_cursor = response.next_cursor.clone();
+        window.push(response.results);
+
+        if next_cursor.is_none() {
+            break;
+        }
+
+        if window.is_full() {
+            next_page_cursor = next_cursor;
+            break;
+        }
+
+        cursor = next_cursor;
+    }
+
+    Ok(Json(ListDatabasePagesResponse {
+        total: window.total(),
+        pages: window.pages,
+        offset,
+        limit,
+        next_cursor: next_page_cursor,
+    }))
+}
+
+/// One NDJSON line: the rendered page, or the reason it couldn't be rendered.
+#[derive(Serialize)]
+struct PageContentLine<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Stream every page of a database as newline-delimited JSON, rendering a
+/// few pages at a time so the whole export never has to sit in memory.
+///
+/// The first batch is queried before responding so that auth and lookup
+/// failures still map to a proper status code. A page that fails to render
+/// gets an error line; failing to query the next batch can only end the
+/// stream early, which is logged and truncates the response.
+async fn stream_database_pages(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Response, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid database id: {id}");
+        return Err(ApiError::bad_request(format!("invalid database id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+    state.check_database_allowed(&id)?;
+
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    let first_batch = first_database_batch(&state, &client, &id).await?;
+
+    let (mut sender, receiver) = mpsc::channel::<Result<String, Infallible>>(16);
+
+    tokio::spawn(async move {
+        let mut batch = first_batch;
+
+        loop {
+            let pages = batch
+                .results
+                .iter()
+                .map(|page| (page.id.clone(), page.last_edited_time))
+                .collect();
+            let mut rendered = state.render_pages(&client, PageRender::default(), pages);
+
+            while let Some((page_id, result)) = rendered.next().await {
+                let line = match &result {
+                    Ok(markdown) => PageContentLine {
+                        id: &page_id,
+                        content: Some(markdown),
+                        error: None,
+                    },
+                    Err(err) => PageContentLine {
+                        id: &page_id,
+                        content: None,
+                        error: Some(&err.message),
+                    },
+                };
+                let mut line = match serde_json::to_string(&line) {
+                    Ok(line) => line,
+                    Err(err) => {
+                        error!("failed to serialize notion page {page_id}: {err:?}");
+                        return;
+                    }
+                };
+                line.push('\n');
+
+                if sender.send(Ok(line)).await.is_err() {
+                    debug!("client disconnected while streaming database {id}");
+                    return;
+                }
+            }
+
+            let Some(cursor) = batch.next_cursor else {
+                return;
+            };
+
+            let query = query_database_batch(&state, &client, &id, Some(cursor));
+            batch = match timeout(state.notion_timeout, query).await {
+                Ok(Ok(batch)) => batch,
+                Ok(Err(err)) => {
+                    error!("failed to query notion database {id}: {err:?}");
+                    return;
+                }
+                Err(_) => {
+                    error!("timed out querying notion database {id}");
+                    return;
+                }
+            };
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(receiver),
+    )
+        .into_response())
+}
+
+/// Which responses get compressed. Streamed NDJSON and Markdown are left
+/// alone so each page still reaches the client as soon as it is rendered,
+/// instead of waiting in the encoder; ZIP entries are already deflated.
+fn compression_predicate() -> impl Predicate {
+    DefaultPredicate::new()
+        .and(NotForContentType::const_new("application/x-ndjson"))
+        .and(NotForContentType::const_new("application/zip"))
+        .and(
+            |_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| {
+                extensions.get::<Streamed>().is_none()
+            },
+        )
+}
+
+/// Marks a streamed response whose content type doesn't tell it apart from
+/// buffered ones, so compression leaves it alone.
+#[derive(Clone, Copy)]
+struct Streamed;
+
+/// Separator written between pages of `/database/{id}/markdown` by default:
+/// a thematic break surrounded by blank lines.
+const DEFAULT_PAGE_SEPARATOR: &str = "\n---\n\n";
+
+#[derive(Deserialize)]
+struct DatabaseMarkdownParams {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    separator: Option<String>,
+    headings: Option<bool>,
+    heading_offset: Option<usize>,
+}
+
+/// How `/database/{id}/markdown` joins pages into one document.
+struct DocumentLayout {
+    separator: String,
+    headings: bool,
+    heading_offset: usize,
+}
+
+impl DocumentLayout {
+    /// The part of the document for `page`, given how it rendered: the
+    /// separator unless it comes `first`, its title heading, and its Markdown
+    /// or a comment naming it when it failed to render.
+    fn section(
+        &self,
+        page: &NotionPage,
+        rendered: &Result<String, ApiError>,
+        first: bool,
+    ) -> String {
+        let mut section = if first {
+            String::new()
+        } else {
+            self.separator.clone()
+        };
+        if self.headings {
+            let title = page_title(page, None).unwrap_or_else(|| "Untitled".to_string());
+            section.push_str(&format!("## {title}\n\n"));
+        }
+        match rendered {
+            Ok(markdown) => {
+                section.push_str(offset_headings(markdown, self.heading_offset).trim_end())
+            }
+            Err(err) => section.push_str(&format!("<!-- page {}: {} -->", page.id, err.message)),
+        }
+        section.push('\n');
+        section
+    }
+}
+
+/// Render the pages of a database, in query order, into one Markdown
+/// document: each page under a `##` heading with its title, separated by
+/// `separator`. The document is streamed a batch of pages at a time, the
+/// same way as [`stream_database_pages`]; a page that fails to render is
+/// replaced by an HTML comment naming it.
+async fn get_database_markdown(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<DatabaseMarkdownParams>,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Response, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid database id: {id}");
+        return Err(ApiError::bad_request(format!("invalid database id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+    state.check_database_allowed(&id)?;
+    if params.limit == Some(0) {
+        warn!("limit of zero requested for database {id}");
+        return Err(ApiError::bad_request("limit must be at least 1"));
+    }
+
+    check_notion_version(&headers)?;
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    let first_batch = first_database_batch(&state, &client, &id).await?;
+
+    let layout = DocumentLayout {
+        separator: params
+            .separator
+            .unwrap_or_else(|| DEFAULT_PAGE_SEPARATOR.to_string()),
+        headings: params.headings.unwrap_or(true),
+        heading_offset: params.heading_offset.unwrap_or(0),
+    };
+    let mut skip = params.offset.unwrap_or(0);
+    let mut remaining = params.limit.unwrap_or(usize::MAX);
+    let (mut sender, receiver) = mpsc::channel::<Result<String, Infallible>>(16);
+
+    tokio::spawn(async move {
+        let mut batch = first_batch;
+        let mut first = true;
+
+        loop {
+            let skipped = skip.min(batch.results.len());
+            skip -= skipped;
+            let pages: Vec<&NotionPage> = batch.results[skipped..].iter().take(remaining).collect();
+            remaining -= pages.len();
+
+            // Pages finish rendering in any order but are written in query
+            // order, so each batch is collected before it is sent.
+            let rendered: HashMap<String, Result<String, ApiError>> = state
+                .render_pages(
+                    &client,
+                    PageRender::default(),
+                    pages
+                        .iter()
+                        .map(|page| (page.id.clone(), page.last_edited_time))
+                        .collect(),
+                )
+                .collect()
+                .await;
+
+            for page in pages {
+                let section = layout.section(page, &rendered[&page.id], first);
+                first = false;
+
+                if sender.send(Ok(section)).await.is_err() {
+                    debug!("client disconnected while rendering database {id}");
+                    return;
+                }
+            }
+
+            let Some(cursor) = batch.next_cursor.filter(|_| remaining > 0) else {
+                return;
+            };
+
+            let query = query_database_batch(&state, &client, &id, Some(cursor));
+            batch = match timeout(state.notion_timeout, query).await {
+                Ok(Ok(batch)) => batch,
+                Ok(Err(err)) => {
+                    error!("failed to query notion database {id}: {err:?}");
+                    return;
+                }
+                Err(_) => {
+                    error!("timed out querying notion database {id}");
+                    return;
+                }
+            };
+        }
+    });
+
+    Ok((
+        Extension(Streamed),
+        [(
+            header::CONTENT_TYPE,
+            PageResponseFormat::Markdown.content_type(),
+        )],
+        Body::from_stream(receiver),
+    )
+        .into_response())
+}
+
+/// Query the first batch of a database for a streamed response, before the
+/// response starts, so auth and lookup failures still get their status.
+async fn first_database_batch(
+    state: &AppState,
+    client: &NotionClient,
+    id: &str,
+) -> Result<QueryDatabaseResponse, ApiError> {
+    timeout(
+        state.notion_timeout,
+        query_database_batch(state, client, id, None),
+    )
+    .await
+    .map_err(|_| {
+        error!("timed out querying notion database {id}");
+        ApiError::notion_timeout()
+    })?
+    .map_err(|err| {
+        let api_error = ApiError::notion(&err, "database");
+        error!("failed to query notion database {id}: {err:?}");
+        api_error
+    })
+}
+
+/// Bytes written by a [`ZipWriter`] that haven't been sent to the client yet.
+#[derive(Clone, Default)]
+struct ZipBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl ZipBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+}
+
+impl Write for ZipBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A ZIP archive of exported pages, streamed a page at a time.
+struct ZipExport {
+    buffer: ZipBuffer,
+    zip: ZipWriter<StreamWriter<ZipBuffer>>,
+    options: SimpleFileOptions,
+    /// How many pages have used each slug so far.
+    names: HashMap<String, usize>,
+    /// Lines of `errors.txt`.
+    errors: Vec<String>,
+}
+
+impl ZipExport {
+    fn new() -> Self {
+        let buffer = ZipBuffer::default();
+        Self {
+            zip: ZipWriter::new_stream(buffer.clone()),
+            buffer,
+            options: SimpleFileOptions::default().compression_method(CompressionMethod::Deflated),
+            names: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Add a page as `{slug}.md`. Pages sharing a slug get `-2`, `-3`, ...
+    /// like the OpenDAL listing.
+    fn add_page(&mut self, slug: &str, content: &str) -> ZipResult<()> {
+        let count = self.names.entry(slug.to_string()).or_insert(0);
+        *count += 1;
+        let name = if *count == 1 {
+            format!("{slug}.md")
+        } else {
+            format!("{slug}-{count}.md")
+        };
+        self.zip.start_file(name, self.options)?;
+        Ok(self.zip.write_all(content.as_bytes())?)
+    }
+
+    /// Bytes written since the last call.
+    fn take(&self) -> Vec<u8> {
+        self.buffer.take()
+    }
+
+    /// Write `errors.txt` when anything failed and end the archive,
+    /// returning its last bytes.
+    fn finish(mut self) -> ZipResult<Vec<u8>> {
+        if !self.errors.is_empty() {
+            let mut report = self.errors.join("\n");
+            report.push('\n');
+            self.zip.start_file("errors.txt", self.options)?;
+            self.zip.write_all(report.as_bytes())?;
+        }
+        self.zip.finish()?;
+        Ok(self.buffer.take())
+    }
+}
+
+/// Export a database as a ZIP archive of `{slug}.md` files, each holding a
+/// page with its frontmatter. Takes the same options as `/page/{id}`, with
+/// frontmatter always on.
+///
+/// The archive is streamed as pages finish rendering. Pages that fail are
+/// listed in an `errors.txt` entry at the end instead of aborting the
+/// export; so is a failure to query the next batch, which ends the export
+/// early.
+async fn export_database_zip(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Query(mut params): Query<GetPageParams>,
+    MaybeBearerToken(token): MaybeBearerToken,
+) -> Result<Response, ApiError> {
+    if !is_valid_notion_id(&id) {
+        warn!("invalid database id: {id}");
+        return Err(ApiError::bad_request(format!("invalid database id: {id}")));
+    }
+    let id = normalize_notion_id(&id);
+    state.check_database_allowed(&id)?;
+
+    check_notion_version(&headers)?;
+    // Checked before streaming; a page failing on it would only show up in
+    // `errors.txt`, once per page.
+    page_render(&params)?;
+    params.languages = state.request_languages(&headers);
+    let token = state.request_token(token)?;
+    let client = state.notion_client(&token)?;
+
+    let first_batch = first_database_batch(&state, &client, &id).await?;
+    let formats = database_number_formats(&state, &client, &params, [id.as_str()]).await;
+
+    let filename = format!("{id}.zip");
+    let (mut sender, receiver) = mpsc::channel::<Result<Vec<u8>, Infallible>>(16);
+
+    tokio::spawn(async move {
+        let mut export = ZipExport::new();
+        let mut batch = first_batch;
+
+        loop {
+            let (state, client, params, formats) = (&state, &client, &params, &formats);
+            let mut exported = stream::iter(std::mem::take(&mut batch.results))
+                .map(|page| async move {
+                    let result = export_page(state, client, &page, params, formats).await;
+                    (page, result)
+                })
+                .buffer_unordered(state.render_concurrency);
+
+            while let Some((page, result)) = exported.next().await {
+                let content = match result {
+                    Ok(Some(content)) => content,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        export.errors.push(format!("{}: {}", page.id, err.message));
+                        continue;
+                    }
+                };
+
+                let slug = state.page_slug(&page, state.title_property(&page, params).as_deref());
+                if let Err(err) = export.add_page(&slug, &content) {
+                    error!(
+                        "failed to write zip entry for notion page {}: {err:?}",
+                        page.id
+                    );
+                    return;
+                }
+
+                if sender.send(Ok(export.take())).await.is_err() {
+                    debug!("client disconnected while exporting database {id}");
+                    return;
+                }
+            }
+            let Some(cursor) = batch.next_cursor.take() else {
+                break;
+            };
+            let query = query_database_batch(state, client, &id, Some(cursor));
+            batch = match timeout(state.notion_timeout, query).await {
+                Ok(Ok(batch)) => batch,
+                Ok(Err(err)) => {
+                    error!("failed to query notion database {id}: {err:?}");
+                    export.errors.push(format!(
+                        "export ended early: {}",
+                        ApiError::notion(&err, "database").message
+                    ));
+                    break;
+                }
+                Err(_) => {
+                    error!("timed out querying notion database {id}");
+                    export
+                        .errors
+                        .push("export ended early: Notion did not answer in time".to_string());
+                    break;
+                }
+            };
+        }
+
+        match export.finish() {
+            Ok(rest) => {
+                let _ = sender.send(Ok(rest)).await;
+            }
+            Err(err) => error!("failed to finish zip export of database {id}: {err:?}"),
+        }
+    });
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/zip"),
+            ),
+            (header::CONTENT_DISPOSITION, attachment(&filename)),
+        ],
+        Body::from_stream(receiver),
+    )
+        .into_response())
+}
+
+/// Render one page for an export, frontmatter included. `None` when the
+/// page is skipped by `require_property`.
+async fn export_page(
+    state: &AppState,
+    client: &NotionClient,
+    page: &NotionPage,
+    params: &GetPageParams,
+    formats: &NumberFormats,
+) -> Result<Option<String>, ApiError> {
+    let requirement_met = requirement_met(page, params);
+    if !requirement_met && params.require_action.unwrap_or_default() == RequireAction::NoContent {
+        return Ok(None);
+    }
+
+    let markdown = page_markdown(state, client, page, params).await?;
+    if !requirement_met {
+        return Ok(Some(markdown));
+    }
+    let properties = page_properties(state, page, params, formats);
+    Ok(Some(with_frontmatter(state, properties, &markdown, params)))
+}
+
+async fn query_database_batch(
+    state: &AppState,
+    client: &NotionClient,
+    id: &str,
+    cursor: Option<String>,
+) -> Result<QueryDatabaseResponse, NotionClientError> {
+    let request = QueryDatabaseRequest {
+        start_cursor: cursor,
+        page_size: Some(state.notion_page_size),
+        ..Default::default()
+    };
+
+    retry_rate_limited(state.notion_max_retries, || {
+        timed_notion_call(
+            "query_a_database",
+            client.databases.query_a_database(id, request.clone()),
+        )
+    })
+    .await
+}
+
+/// Record how long a single Notion call took in the
+/// `notion_request_duration_seconds` histogram.
+async fn timed_notion_call<F: Future>(operation: &'static str, call: F) -> F::Output {
+    let start = Instant::now();
+    let output = call.await;
+    metrics::histogram!("notion_request_duration_seconds", "operation" => operation)
+        .record(start.elapsed().as_secs_f64());
+    output
+}
+
+/// Build the Notion sort list from `sort_by`/`sort_direction`. The special
+/// names `created_time` and `last_edited_time` sort by the page timestamps
+/// rather than by a property.
+fn database_sorts(params: &ListDatabaseParams) -> Result<Option<Vec<Sort>>, ApiError> {
+    let direction = match params.sort_direction.as_deref().map(str::trim) {
+        None | Some("asc") | Some("ascending") => SortDirection::Ascending,
+        Some("desc") | Some("descending") => SortDirection::Descending,
+        Some(_) => {
+            return Err(ApiError::bad_request("sort_direction must be asc or desc"));
+        }
+    };
+
+    let Some(property) = params.sort_by.as_deref().map(str::trim) else {
+        return Ok(None);
+    };
+
+    let sort = match property {
+        "created_time" => Sort::Timestamp {
+            timestamp: Timestamp::CreatedTime,
+            direction,
+        },
+        "last_edited_time" => Sort::Timestamp {
+            timestamp: Timestamp::LastEditedTime,
+            direction,
+        },
+        _ => Sort::Property {
+            property: property.to_string(),
+            direction,
+        },
+    };
+
+    Ok(Some(vec![sort]))
+}
+
+/// Build an equality filter for `property` based on its type in the database
+/// schema. Returns `None` for property types without an equality condition or
+/// values that don't parse for the type.
+fn property_filter(property: &str, schema: &DatabaseProperty, value: &str) -> Option<Filter> {
+    let condition = match schema {
+        DatabaseProperty::Checkbox { .. } => {
+            PropertyCondition::Checkbox(CheckBoxCondition::Equals(value.parse().ok()?))
+        }
+        DatabaseProperty::Select { .. } => {
+            PropertyCondition::Select(SelectCondition::Equals(value.to_string()))
+        }
+        DatabaseProperty::Status { .. } => {
+            PropertyCondition::Status(StatusCondition::Equals(value.to_string()))
+        }
+        DatabaseProperty::MultiSelect { .. } => {
+            PropertyCondition::MultiSelect(MultiSelectCondition::Contains(value.to_string()))
+        }
+        DatabaseProperty::Number { .. } => {
+            PropertyCondition::Number(NumberCondition::Equals(value.parse().ok()?))
+        }
+        DatabaseProperty::RichText { .. } => {
+            PropertyCondition::RichText(RichTextCondition::Equals(value.to_string()))
+        }
+        _ => return None,
+    };
+
+    Some(Filter::Value {
+        filter_type: FilterType::Property {
+            property: property.to_string(),
+            condition,
+        },
+    })
+}
+
+/// Split a `.md`, `.html` or `.json` extension off a page id, returning the
+/// format it asks for.
+fn split_format_extension(id: &str) -> (&str, Option<PageResponseFormat>) {
+    if let Some(id) = id.strip_suffix(".md") {
+        (id, Some(PageResponseFormat::Markdown))
+    } else if let Some(id) = id.strip_suffix(".html") {
+        (id, Some(PageResponseFormat::Html))
+    } else if let Some(id) = id.strip_suffix(".json") {
+        (id, Some(PageResponseFormat::Json))
+    } else {
+        (id, None)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PageResponseFormat {
+    Json,
+    Markdown,
+    /// The rendered Markdown converted to an HTML document.
+    Html,
+    /// Properties only, without rendering the page.
+    Yaml,
+}
+
+impl PageResponseFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            PageResponseFormat::Json => "application/json",
+            PageResponseFormat::Markdown => "text/markdown; charset=utf-8",
+            PageResponseFormat::Html => "text/html; charset=utf-8",
+            PageResponseFormat::Yaml => "application/yaml",
+        }
+    }
+}
+
+/// Language ranges from `Accept-Language`, most preferred first. Ranges with
+/// `q=0` and the `*` wildcard are dropped.
+fn accept_languages(headers: &HeaderMap) -> Vec<String> {
+    let mut ranges: Vec<(f32, String)> = headers
+        .get_all(header::ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let lang = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!lang.is_empty() && lang != "*" && quality > 0.0).then(|| (quality, lang.to_string()))
+        })
+        .collect();
+    // Stable, so ranges of equal quality keep the client's order.
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranges.into_iter().map(|(_, lang)| lang).collect()
+}
+
+fn page_response_format(headers: &HeaderMap) -> PageResponseFormat {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+
+    if let Some(content_type) = content_type
+        && content_type.starts_with("text/markdown")
+    {
+        return PageResponseFormat::Markdown;
+    }
+
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    if let Some(value) = accept {
+        for item in value.split(',').map(str::trim) {
+            if item.starts_with("text/markdown") || item.starts_with("text/*") {
+                return PageResponseFormat::Markdown;
+            }
+
+            if item.starts_with("text/html") {
+                return PageResponseFormat::Html;
+            }
+
+            if item.starts_with("application/yaml") || item.starts_with("application/x-yaml") {
+                return PageResponseFormat::Yaml;
+            }
+
+            if item.starts_with("application/json") || item.starts_with("application/*") {
+                return PageResponseFormat::Json;
+            }
+
+            if item == "*/*" {
+                return PageResponseFormat::Json;
+            }
+        }
+    }
+
+    PageResponseFormat::Json
+}
+
+/// Error returned by handlers: the status plus a JSON body such as
+/// `{"error": "page not found, or not shared with the integration", "code": "not_found"}`.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    /// Sent as `Retry-After` when set.
+    retry_after: Option<Duration>,
+    /// The `request_id` of the Notion error this one was mapped from.
+    notion_request_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody<'a> {
+    error: &'a str,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notion_request_id: Option<&'a str>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            retry_after: None,
+            notion_request_id: None,
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// The request waited too long for a free slot.
+    fn busy() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many requests in flight, try again later",
+        )
+    }
+
+    /// The token used up its `RATE_LIMIT_PER_MINUTE` budget.
+    fn rate_limited(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many requests for this token, try again later",
+            )
+        }
+    }
+
+    /// A service behind Notion, such as its file storage, failed.
+    fn bad_gateway(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, message)
+    }
+
+    fn notion_timeout() -> Self {
+        Self::new(StatusCode::GATEWAY_TIMEOUT, "Notion did not answer in time")
+    }
+
+    fn request_timeout() -> Self {
+        Self::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "the request did not finish in time",
+        )
+    }
+
+    /// Describe a failed Notion call about `what` (`page`, `database`).
+    fn notion(err: &NotionClientError, what: &str) -> Self {
+        let status = map_notion_error(err);
+        let message = match (status, err) {
+            (StatusCode::BAD_REQUEST, NotionClientError::InvalidStatusCode { error }) => {
+                format!("Notion rejected the request: {}", error.message)
+            }
+            (StatusCode::UNAUTHORIZED, _) => "Notion rejected the token".to_string(),
+            (StatusCode::FORBIDDEN, _) => {
+                format!("the integration does not have access to this {what}")
+            }
+            (StatusCode::NOT_FOUND, _) => {
+                format!("{what} not found, or not shared with the integration")
+            }
+            (StatusCode::TOO_MANY_REQUESTS, _) => "rate limited by Notion".to_string(),
+            _ => format!("failed to load {what} from Notion"),
+        };
+        // Notion support asks for this ID when investigating a failed call.
+        let notion_request_id = match err {
+            NotionClientError::InvalidStatusCode { error } => {
+                let request_id = error.request_id.clone().filter(|id| !id.is_empty());
+                if let Some(request_id) = &request_id {
+                    warn!(
+                        "Notion returned {} ({}) for {what}, request_id {request_id}",
+                        error.status, error.code
+                    );
+                }
+                request_id
+            }
+            _ => None,
+        };
+        Self {
+            notion_request_id,
+            ..Self::new(status, message)
+        }
+    }
+
+    /// Describe a failed page render, which is a Notion failure unless the
+    /// conversion itself broke.
+    fn render(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<MarkdownTooLarge>() {
+            return Self::new(StatusCode::PAYLOAD_TOO_LARGE, err.to_string());
+        }
+        match err.downcast_ref::<NotionClientError>() {
+            Some(err) => Self::notion(err, "page"),
+            None => Self::new(StatusCode::INTERNAL_SERVER_ERROR, "failed to render page"),
+        }
+    }
+
+    /// Stable, machine-readable name for the status.
+    fn code(&self) -> &'static str {
+        match self.status {
+            StatusCode::BAD_REQUEST => "bad_request",
+            StatusCode::UNAUTHORIZED => "unauthorized",
+            StatusCode::FORBIDDEN => "forbidden",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+            StatusCode::PAYLOAD_TOO_LARGE => "too_large",
+            StatusCode::BAD_GATEWAY => "bad_gateway",
+            StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+            StatusCode::GATEWAY_TIMEOUT => "timeout",
+            _ => "internal",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ApiErrorBody {
+            error: &self.message,
+            code: self.code(),
+            notion_request_id: self.notion_request_id.as_deref(),
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            // Round up so clients never retry before a request is available.
+            let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+fn map_notion_error(err: &NotionClientError) -> StatusCode {
+    match err {
+        NotionClientError::InvalidStatusCode { error } => match error.status {
+            400 => StatusCode::BAD_REQUEST,
+            401 => StatusCode::UNAUTHORIZED,
+            403 => StatusCode::FORBIDDEN,
+            404 => StatusCode::NOT_FOUND,
+            429 => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        NotionClientError::InvalidHeader { .. } => StatusCode::UNAUTHORIZED,
+        NotionClientError::FailedToSerialize { .. }
+        | NotionClientError::FailedToDeserialize { .. }
+        | NotionClientError::FailedToRequest { .. }
+        | NotionClientError::FailedToText { .. }
+        | NotionClientError::FailedToBuildRequest { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The `Notion-Version` that `notion_client` sends. The client sets this
+/// header itself after applying any caller-provided default headers, so it
+/// cannot be overridden per request and the response types are written
+/// against this version anyway.
+const NOTION_API_VERSION: &str = "2022-06-28";
+
+/// Validate the optional `X-Notion-Version` request header. Malformed values
+/// and versions other than [`NOTION_API_VERSION`] are rejected rather than
+/// silently answered with a different API version.
+fn check_notion_version(headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(value) = headers.get("X-Notion-Version") else {
+        return Ok(());
+    };
+
+    let Ok(version) = value.to_str().map(str::trim) else {
+        warn!("failed to read X-Notion-Version header as UTF-8");
+        return Err(ApiError::bad_request("X-Notion-Version is not valid UTF-8"));
+    };
+
+    if NaiveDate::parse_from_str(version, "%Y-%m-%d").is_err() {
+        warn!("malformed X-Notion-Version header: {version:?}");
+        return Err(ApiError::bad_request(format!(
+            "malformed X-Notion-Version: {version:?}"
+        )));
+    }
+
+    if version != NOTION_API_VERSION {
+        warn!("unsupported notion version {version}, only {NOTION_API_VERSION} is available");
+        return Err(ApiError::bad_request(format!(
+            "unsupported X-Notion-Version {version}, only {NOTION_API_VERSION} is available"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Cap the number of requests talking to Notion at once. Requests past the
+/// limit wait up to `REQUEST_QUEUE_TIMEOUT_SECS` for a slot, then get a 503.
+///
+/// The permit is released once the handler returns, so the background part of
+/// a streamed response does not count towards the limit.
+async fn limit_concurrency(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(permits) = &state.request_permits else {
+        return Ok(next.run(req).await);
+    };
+
+    let _permit = timeout(state.request_queue_timeout, permits.acquire())
+        .await
+        .map_err(|_| {
+            warn!("no request slot became free within the queue timeout");
+            ApiError::busy()
+        })?
+        .map_err(|_| ApiError::busy())?;
+
+    Ok(next.run(req).await)
+}
+
+/// Answer with a 504 when a request runs longer than `REQUEST_TIMEOUT_SECS`,
+/// including the time it waited for a request slot. The handler future is
+/// dropped at the deadline, which cancels the Notion calls still in flight.
+/// Streamed responses only count until their headers are sent.
+async fn limit_duration(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(deadline) = state.request_timeout else {
+        return Ok(next.run(req).await);
+    };
+
+    let path = req.uri().path().to_string();
+    timeout(deadline, next.run(req)).await.map_err(|_| {
+        warn!("request to {path} did not finish within {deadline:?}");
+        ApiError::request_timeout()
+    })
+}
+
+/// Reject requests over `RATE_LIMIT_PER_MINUTE` for their token with a 429.
+/// Requests without a token share the budget of `NOTION_API_TOKEN`, and are
+/// passed through when neither is set so the handler can answer with a 401.
+async fn limit_rate(
+    State(state): State<AppState>,
+    MaybeBearerToken(token): MaybeBearerToken,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let (Some(limiter), Some(token)) = (
+        &state.rate_limiter,
+        token.or_else(|| state.default_token.clone()),
+    ) else {
+        return Ok(next.run(req).await);
+    };
+
+    if let Err(retry_after) = limiter.acquire(&token) {
+        warn!("rate limit exceeded for token, retry after {retry_after:?}");
+        return Err(ApiError::rate_limited(retry_after));
+    }
+    Ok(next.run(req).await)
+}
+
+/// Header carrying the ID that ties a response to its log lines.
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Layout of log lines, from `LOG_FORMAT`.
+#[derive(Clone, Copy)]
+enum LogFormat {
+    /// Human-readable lines, the default.
+    Text,
+    /// One JSON object per line, with request details as separate keys.
+    Json,
+}
+
+async fn log_requests(
+    State(log_format): State<LogFormat>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    // Reuse the caller's request ID so their traces line up with ours, and
+    // put it back on the request so handlers see the same value.
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&uuid::Uuid::new_v4().to_string())
+                .expect("a UUID is a valid header value")
+        });
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let method = req.method().clone();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    // Label metrics with the route template rather than the raw path so
+    // page and database IDs don't each create their own series.
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched| matched.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let start = Instant::now();
+
+    let mut response = next.run(req).await;
+    let status = response.status();
+    let elapsed = start.elapsed();
+    let elapsed_ms = elapsed.as_millis();
+
+    metrics::counter!(
+        "http_requests_total",
+        "method" => method.to_string(),
+        "route" => route.clone(),
+        "status" => status.as_u16().to_string(),
+    )
+    .increment(1);
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        "method" => method.to_string(),
+        "route" => route,
+    )
+    .record(elapsed.as_secs_f64());
+
+    let request_id_str = request_id.to_str().unwrap_or_default();
+    match log_format {
+        LogFormat::Text => info!(
+            request_id = request_id_str;
+            "handled {method} {path} -> {} in {elapsed_ms}ms",
+            status.as_u16()
+        ),
+        // Discrete keys let log pipelines filter on them without parsing.
+        LogFormat::Json => info!(
+            request_id = request_id_str,
+            method:% = method,
+            path:% = path,
+            status = status.as_u16(),
+            elapsed_ms = elapsed_ms;
+            "handled request"
+        ),
+    }
+
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use tower::ServiceExt as _;
+
+    const PAGE_ID: &str = "59833787-2cf9-4fdf-8782-e53db20768a5";
+    const BLOCK_ID: &str = "c02fc1d3-db8b-45c5-a222-27595b15aea7";
+
+    fn state() -> AppState {
+        AppState {
+            page_cache: None,
+            client_cache: Cache::new(10),
+            notion_max_retries: DEFAULT_MAX_RETRIES,
+            notion_timeout: Duration::from_secs(30),
+            notion_page_size: MAX_PAGE_SIZE,
+            default_token: None,
+            readiness_token: None,
+            allowed_database_ids: None,
+            request_permits: None,
+            request_queue_timeout: Duration::from_secs(10),
+            request_timeout: None,
+            render_concurrency: 3,
+            frontmatter_key_order: Arc::default(),
+            frontmatter_aliases: Arc::default(),
+            rate_limiter: None,
+            public_base_url: "".into(),
+            http_client: reqwest::Client::new(),
+            user_agent: "notion2md-server-test".into(),
+            token_header: HeaderName::from_static("auth"),
+            untitled_template: None,
+            max_markdown_bytes: None,
+            localized_names: None,
+            default_language: "en".into(),
+        }
+    }
+
+    async fn send(state: AppState, request: Request<Body>) -> Response {
+        let metrics = PrometheusBuilder::new().build_recorder().handle();
+        app(state, LogFormat::Text, metrics)
+            .oneshot(request)
+            .await
+            .expect("infallible")
+    }
+
+    fn authorized(uri: &str) -> axum::http::request::Builder {
+        Request::get(uri).header(header::AUTHORIZATION, "Bearer secret")
+    }
+
+    fn page(id: &str, properties: serde_json::Value) -> NotionPage {
+        serde_json::from_value(page_json(id, properties)).expect("valid notion page")
+    }
+
+    /// A page with the given `properties`, as Notion returns it.
+    fn page_json(id: &str, properties: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "object": "page",
+            "id": id,
+            "created_time": "2024-01-01T00:00:00.000Z",
+            "created_by": { "object": "user", "id": "user" },
+            "last_edited_time": "2024-01-02T00:00:00.000Z",
+            "last_edited_by": { "object": "user", "id": "user" },
+            "archived": false,
+            "icon": null,
+            "cover": null,
+            "properties": properties,
+            "parent": { "type": "database_id", "database_id": "ffffffff-ffff-ffff-ffff-ffffffffffff" },
+            "url": "https://www.notion.so/page",
+            "public_url": null,
+        })
+    }
+
+    /// Rich text holding `content`, unformatted.
+    fn rich_text(content: &str) -> serde_json::Value {
+        serde_json::json!([{
+            "type": "text",
+            "text": { "content": content, "link": null },
+            "annotations": {
+                "bold": false,
+                "italic": false,
+                "strikethrough": false,
+                "underline": false,
+                "code": false,
+                "color": "default",
+            },
+            "plain_text": content,
+            "href": null,
+        }])
+    }
+
+    /// Stands in for the Notion API in tests. Clients reach it through it as
+    /// their HTTPS proxy: each `CONNECT` is acknowledged and the tunnel is
+    /// terminated with a self-signed certificate for `api.notion.com`, which
+    /// the clients are told to accept.
+    struct MockNotion {
+        listener: tokio::net::TcpListener,
+        tls: tokio_native_tls::TlsAcceptor,
+    }
+
+    impl MockNotion {
+        async fn tunnel(
+            &self,
+            mut stream: tokio::net::TcpStream,
+        ) -> std::io::Result<tokio_native_tls::TlsStream<tokio::net::TcpStream>> {
+            use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await?);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            self.tls.accept(stream).await.map_err(std::io::Error::other)
+        }
+    }
+
+    impl axum::serve::Listener for MockNotion {
+        type Io = tokio_native_tls::TlsStream<tokio::net::TcpStream>;
+        type Addr = SocketAddr;
+
+        async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+            loop {
+                let Ok((stream, addr)) = self.listener.accept().await else {
+                    continue;
+                };
+                if let Ok(stream) = self.tunnel(stream).await {
+                    return (stream, addr);
+                }
+            }
+        }
+
+        fn local_addr(&self) -> std::io::Result<Self::Addr> {
+            self.listener.local_addr()
+        }
+    }
+
+    /// [`state`] with the token `secret` talking to a [`MockNotion`] that
+    /// answers with `routes`, given without the `/v1` prefix.
+    async fn with_notion(routes: Router) -> AppState {
+        use tokio_native_tls::native_tls;
+
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("testdata/notion.crt"),
+            include_bytes!("testdata/notion.key"),
+        )
+        .expect("valid test certificate");
+        let tls = native_tls::TlsAcceptor::new(identity)
+            .expect("tls acceptor")
+            .into();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("free local port");
+        let proxy = format!("http://{}", listener.local_addr().expect("bound address"));
+        let mock = MockNotion { listener, tls };
+        tokio::spawn(async move { axum::serve(mock, Router::new().nest("/v1", routes)).await });
+
+        let builder = reqwest::ClientBuilder::new()
+            .proxy(reqwest::Proxy::https(proxy).expect("valid proxy url"))
+            .danger_accept_invalid_certs(true);
+        let client = NotionClient::new("secret".to_string(), Some(builder)).expect("notion client");
+        let state = state();
+        state.client_cache.insert(token_key("secret"), client);
+        state
+    }
+
+    /// `blocks` as `retrieve_block_children` returns them, in one batch.
+    fn children(blocks: Vec<serde_json::Value>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "object": "list",
+            "results": blocks,
+            "next_cursor": null,
+            "has_more": false,
+        }))
+    }
+
+    /// Routes serving any page as "Hello", holding one paragraph. Only
+    /// rendering fetches the blocks, so `block_fetches` counts renders.
+    fn hello_page(block_fetches: Arc<AtomicUsize>) -> Router {
+        Router::new()
+            .route(
+                "/pages/{id}",
+                get(|Path(id): Path<String>| async move {
+                    let title = serde_json::json!({
+                        "Name": { "id": "title", "type": "title", "title": rich_text("Hello") },
+                    });
+                    Json(page_json(&id, title))
+                }),
+            )
+            .route(
+                "/blocks/{id}/children",
+                get(move || async move {
+                    block_fetches.fetch_add(1, Ordering::SeqCst);
+                    children(vec![serde_json::json!({
+                        "object": "block",
+                        "id": BLOCK_ID,
+                        "type": "paragraph",
+                        "paragraph": { "rich_text": rich_text("Hello, world"), "color": "default" },
+                        "has_children": false,
+                    })])
+                }),
+            )
+    }
+
+    fn pages(ids: &[&str]) -> Vec<NotionPage> {
+        ids.iter()
+            .map(|id| page(id, serde_json::json!({})))
+            .collect()
+    }
+
+    fn notion_error(status: u32) -> NotionClientError {
+        let error = serde_json::from_value(serde_json::json!({
+            "object": "error",
+            "status": status,
+            "code": "rate_limited",
+            "message": "Rate limited",
+            "request_id": null,
+        }))
+        .expect("valid notion error");
+        NotionClientError::InvalidStatusCode { error }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exhausted_rate_limit_retries_answer_too_many_requests() {
+        let result: Result<(), _> =
+            retry_rate_limited(2, || async { Err(notion_error(429)) }).await;
+        let err = result.expect_err("retries run out");
+        assert_eq!(
+            ApiError::notion(&err, "page").status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn streamed_markdown_is_not_compressed() {
+        let markdown = [(
+            header::CONTENT_TYPE,
+            PageResponseFormat::Markdown.content_type(),
+        )];
+        let page = (markdown.clone(), "# Page\n".repeat(100)).into_response();
+        let streamed = (Extension(Streamed), markdown, "# Page\n".repeat(100)).into_response();
+
+        let predicate = compression_predicate();
+        assert!(predicate.should_compress(&page));
+        assert!(!predicate.should_compress(&streamed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_requests_count_toward_the_deadline() {
+        let state = AppState {
+            request_permits: Some(Arc::new(Semaphore::new(0))),
+            request_queue_timeout: Duration::from_secs(60),
+            request_timeout: Some(Duration::from_secs(5)),
+            ..state()
+        };
+        let started = tokio::time::Instant::now();
+        let request = authorized(&format!("/page/{PAGE_ID}/raw"))
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state, request).await;
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn status_filters_match_the_option_name() {
+        let schema = serde_json::from_value(serde_json::json!({
+            "type": "status",
+            "status": { "options": [{ "name": "Published" }], "groups": [] },
+        }))
+        .unwrap();
+        let filter = property_filter("Status", &schema, "Published").unwrap();
+        assert_eq!(
+            serde_json::to_value(filter).unwrap(),
+            serde_json::json!({ "property": "Status", "status": { "equals": "Published" } })
+        );
+    }
+
+    #[test]
+    fn exact_count_pages_through_the_whole_database() {
+        let mut window = DatabaseWindow::new(1, 2, false, false);
+        assert_eq!(window.page_size(100), 100);
+        window.push(pages(&["a", "b", "c"]));
+        assert!(!window.is_full());
+        window.push(pages(&["d", "e"]));
+
+        assert_eq!(window.total(), 5);
+        assert_eq!(
+            serde_json::to_value(&window.pages).unwrap(),
+            serde_json::json!(["b", "c"])
+        );
+    }
+
+    #[test]
+    fn page_count_stops_at_the_window() {
+        let mut window = DatabaseWindow::new(1, 2, false, true);
+        assert_eq!(window.page_size(100), 3);
+        window.push(pages(&["a", "b"]));
+        assert!(!window.is_full());
+        assert_eq!(window.page_size(100), 1);
+        window.push(pages(&["c"]));
+
+        assert!(window.is_full());
+        assert_eq!(window.total(), 2);
+        assert_eq!(
+            serde_json::to_value(&window.pages).unwrap(),
+            serde_json::json!(["b", "c"])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_notion_calls_time_out() {
+        let state = AppState {
+            notion_timeout: Duration::from_secs(5),
+            ..state()
+        };
+        let slow_call = || async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        };
+        let result: Result<(), _> =
+            retrieve(&state, "retrieve_a_page", "page", PAGE_ID, slow_call).await;
+        let err = result.expect_err("the call outlives the timeout");
+        assert_eq!(err.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.message, ApiError::notion_timeout().message);
+    }
+
+    fn conditional(name: HeaderName, value: &str) -> HeaderMap {
+        HeaderMap::from_iter([(name, HeaderValue::from_str(value).unwrap())])
+    }
+
+    #[test]
+    fn unchanged_pages_are_not_modified() {
+        let edited: DateTime<Utc> = "2024-01-02T00:00:00Z".parse().unwrap();
+        let etag = page_etag(PAGE_ID, edited, PageResponseFormat::Markdown, None, &[]);
+        let not_modified = |headers: HeaderMap| is_not_modified(&headers, &etag, edited);
+
+        assert!(not_modified(conditional(header::IF_NONE_MATCH, &etag)));
+        let weak = format!("\"other\", W/{etag}");
+        assert!(not_modified(conditional(header::IF_NONE_MATCH, &weak)));
+        assert!(not_modified(conditional(header::IF_NONE_MATCH, "*")));
+        let since = "Tue, 02 Jan 2024 00:00:00 GMT";
+        assert!(not_modified(conditional(header::IF_MODIFIED_SINCE, since)));
+    }
+
+    #[test]
+    fn changed_pages_are_modified() {
+        let edited: DateTime<Utc> = "2024-01-02T00:00:00Z".parse().unwrap();
+        let etag = page_etag(PAGE_ID, edited, PageResponseFormat::Markdown, None, &[]);
+
+        // Editing the page, or asking for another format, changes the tag.
+        let later = edited + chrono::Duration::hours(1);
+        let later_etag = page_etag(PAGE_ID, later, PageResponseFormat::Markdown, None, &[]);
+        assert_ne!(later_etag, etag);
+        assert_ne!(
+            page_etag(PAGE_ID, edited, PageResponseFormat::Json, None, &[]),
+            etag
+        );
+        let stale = conditional(header::IF_NONE_MATCH, &etag);
+        assert!(!is_not_modified(&stale, &later_etag, later));
+
+        let since = conditional(header::IF_MODIFIED_SINCE, "Mon, 01 Jan 2024 00:00:00 GMT");
+        assert!(!is_not_modified(&since, &etag, edited));
+        // If-None-Match wins over If-Modified-Since.
+        let mut headers = conditional(header::IF_NONE_MATCH, "\"other\"");
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Tue, 02 Jan 2024 00:00:00 GMT"),
+        );
+        assert!(!is_not_modified(&headers, &etag, edited));
+        assert!(!is_not_modified(&HeaderMap::new(), &etag, edited));
+    }
+
+    #[test]
+    fn environment_values_override_defaults() {
+        // SAFETY: no other test reads or writes these variables.
+        unsafe {
+            std::env::set_var("NOTION2MD_TEST_RETRIES", " 7 ");
+            std::env::set_var("NOTION2MD_TEST_INVALID", "many");
+        }
+        assert_eq!(env_or("NOTION2MD_TEST_RETRIES", 3u32).unwrap(), 7);
+        assert_eq!(env_or("NOTION2MD_TEST_UNSET", 3u32).unwrap(), 3);
+        assert!(env_or("NOTION2MD_TEST_INVALID", 3u32).is_err());
+    }
+
+    #[tokio::test]
+    async fn responses_carry_a_request_id() {
+        let request = Request::get("/healthz").body(Body::empty()).unwrap();
+        let response = send(state(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let request_id = response.headers()[&REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(request_id).is_ok(), "{request_id}");
+    }
+
+    #[tokio::test]
+    async fn client_request_ids_are_echoed() {
+        let with_id = |uri: String| {
+            Request::get(uri)
+                .header(&REQUEST_ID_HEADER, "trace-1234")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = send(state(), with_id("/healthz".to_string())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[&REQUEST_ID_HEADER], "trace-1234");
+
+        // Errors carry it too, so a failed call can be found in the logs.
+        let response = send(state(), with_id(format!("/page/{PAGE_ID}"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[&REQUEST_ID_HEADER], "trace-1234");
+    }
+
+    #[tokio::test]
+    async fn database_allowlist_is_checked_before_notion() {
+        let database = |state: AppState, id: &str| {
+            let request = Request::get(format!("/database/{id}"))
+                .body(Body::empty())
+                .unwrap();
+            send(state, request)
+        };
+        let restricted = || AppState {
+            allowed_database_ids: Some(HashSet::from([PAGE_ID.to_string()])),
+            ..state()
+        };
+
+        // Requests that pass the check fail later for want of a token.
+        let undashed = PAGE_ID.replace('-', "");
+        let allowed = database(restricted(), &undashed).await;
+        assert_eq!(allowed.status(), StatusCode::UNAUTHORIZED);
+        let denied = database(restricted(), BLOCK_ID).await;
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        let unset = database(state(), BLOCK_ID).await;
+        assert_eq!(unset.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn notion_statuses_keep_their_meaning() {
+        let cases = [
+            (400, StatusCode::BAD_REQUEST, "bad_request"),
+            (401, StatusCode::UNAUTHORIZED, "unauthorized"),
+            (403, StatusCode::FORBIDDEN, "forbidden"),
+            (404, StatusCode::NOT_FOUND, "not_found"),
+            (429, StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            (500, StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        ];
+        for (notion_status, status, code) in cases {
+            let err = ApiError::notion(&notion_error(notion_status), "page");
+            assert_eq!(err.status, status, "{notion_status}");
+            assert_eq!(err.code(), code, "{notion_status}");
+        }
+
+        let forbidden = ApiError::notion(&notion_error(403), "database");
+        assert_eq!(
+            forbidden.message,
+            "the integration does not have access to this database"
+        );
+        let unauthorized = ApiError::notion(&notion_error(401), "database");
+        assert_eq!(unauthorized.message, "Notion rejected the token");
+    }
+
+    #[test]
+    fn download_names_are_escaped() {
+        let cases = [
+            (
+                "hello.md",
+                "attachment; filename=\"hello.md\"; filename*=UTF-8''hello.md",
+            ),
+            (
+                "say \"hi\".md",
+                "attachment; filename=\"say__hi_.md\"; filename*=UTF-8''say%20%22hi%22.md",
+            ),
+            (
+                "café.md",
+                "attachment; filename=\"caf_.md\"; filename*=UTF-8''caf%C3%A9.md",
+            ),
+            (
+                "back\\slash.md",
+                "attachment; filename=\"back_slash.md\"; filename*=UTF-8''back%5Cslash.md",
+            ),
+        ];
+        for (filename, disposition) in cases {
+            assert_eq!(attachment(filename), disposition, "{filename}");
+        }
+    }
+
+    /// Whether `value` matches `schema`, for the subset of JSON Schema that
+    /// schemars generates: types, properties, items, `anyOf` and `$defs`.
+    fn validates(
+        schema: &serde_json::Value,
+        root: &serde_json::Value,
+        value: &serde_json::Value,
+    ) -> bool {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/$defs/");
+            return validates(&root["$defs"][name], root, value);
+        }
+        if let Some(variants) = schema["anyOf"]
+            .as_array()
+            .or_else(|| schema["oneOf"].as_array())
+        {
+            return variants
+                .iter()
+                .any(|variant| validates(variant, root, value));
+        }
+
+        let has_type = |name: &str| match name {
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            "null" => value.is_null(),
+            _ => false,
+        };
+        let typed = match &schema["type"] {
+            serde_json::Value::String(name) => has_type(name),
+            serde_json::Value::Array(names) => {
+                names.iter().filter_map(|name| name.as_str()).any(has_type)
+            }
+            _ => true,
+        };
+        if !typed {
+            return false;
+        }
+
+        if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+            return items.iter().all(|item| validates(item_schema, root, item));
+        }
+        if let Some(object) = value.as_object() {
+            let mut required = schema["required"].as_array().into_iter().flatten();
+            if !required.all(|key| key.as_str().is_some_and(|key| object.contains_key(key))) {
+                return false;
+            }
+            return object.iter().all(|(key, field)| {
+                match (
+                    schema["properties"].get(key),
+                    schema.get("additionalProperties"),
+                ) {
+                    (Some(field_schema), _) => validates(field_schema, root, field),
+                    (None, Some(serde_json::Value::Bool(allowed))) => *allowed,
+                    (None, Some(extra)) => validates(extra, root, field),
+                    (None, None) => true,
+                }
+            });
+        }
+        true
+    }
+
+    #[tokio::test]
+    async fn page_schema_validates_a_page_response() {
+        let response = send(
+            state(),
+            Request::get("/schema/page").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let schema = json_body(response).await;
+
+        let page = PageJsonResponse {
+            id: PAGE_ID.to_string(),
+            icon: Some("📝".to_string()),
+            cover: None,
+            properties: HashMap::from([
+                (
+                    "Name".to_string(),
+                    PropertyValue::String("Hello".to_string()),
+                ),
+                ("Count".to_string(), PropertyValue::Number(42.0)),
+                ("Ratio".to_string(), PropertyValue::Number(0.25)),
+                ("Done".to_string(), PropertyValue::Boolean(true)),
+                (
+                    "Tags".to_string(),
+                    PropertyValue::StringArray(vec!["rust".to_string()]),
+                ),
+                (
+                    "Day".to_string(),
+                    PropertyValue::Date(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap()),
+                ),
+                (
+                    "Edited".to_string(),
+                    PropertyValue::DateTime("2024-03-01T08:30:00Z".parse().unwrap()),
+                ),
+                (
+                    "Authors".to_string(),
+                    PropertyValue::People(vec![notion_opendal::notion::Person {
+                        id: "user-1".to_string(),
+                        name: None,
+                    }]),
+                ),
+            ]),
+            content: "# Hello\n".to_string(),
+        };
+        let page = serde_json::to_value(page).unwrap();
+        assert!(validates(&schema, &schema, &page), "{schema:#}");
+
+        // The schema isn't so loose that anything passes.
+        let mut missing_content = page.clone();
+        missing_content.as_object_mut().unwrap().remove("content");
+        assert!(!validates(&schema, &schema, &missing_content));
+        let mut nested = page;
+        nested["properties"]["Name"] = serde_json::json!({ "nested": "object" });
+        assert!(!validates(&schema, &schema, &nested));
+    }
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).expect("JSON body")
+    }
+
+    async fn text_body(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).expect("UTF-8 body")
+    }
+
+    #[tokio::test]
+    async fn page_urls_resolve_to_ids() {
+        let resolve = |query: String| {
+            send(
+                state(),
+                Request::get(format!("/resolve?{query}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let undashed = PAGE_ID.replace('-', "");
+
+        let workspace = resolve(format!("url=https://www.notion.so/acme/Roadmap-{undashed}")).await;
+        assert_eq!(workspace.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(workspace).await,
+            serde_json::json!({ "id": PAGE_ID })
+        );
+        let peek = resolve(format!(
+            "url=https://www.notion.so/acme/{}%3Fv%3Dview%26p%3D{undashed}",
+            BLOCK_ID.replace('-', "")
+        ))
+        .await;
+        assert_eq!(json_body(peek).await, serde_json::json!({ "id": PAGE_ID }));
+
+        let redirect = resolve(format!(
+            "url=https://acme.notion.site/Roadmap-{undashed}&redirect=true"
+        ))
+        .await;
+        assert_eq!(redirect.status(), StatusCode::FOUND);
+        assert_eq!(
+            redirect.headers()[header::LOCATION],
+            format!("/page/{PAGE_ID}").as_str()
+        );
+
+        let unparseable = resolve("url=https://example.com/about".to_string()).await;
+        assert_eq!(unparseable.status(), StatusCode::BAD_REQUEST);
+        let missing = resolve(String::new()).await;
+        assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_are_trimmed() {
+        let send_trimmed = |uri: String| {
+            let metrics = PrometheusBuilder::new().build_recorder().handle();
+            let app = NormalizePath::trim_trailing_slash(app(state(), LogFormat::Text, metrics));
+            app.oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        for path in ["/healthz".to_string(), format!("/page/{PAGE_ID}/raw")] {
+            let plain = send_trimmed(path.clone()).await.unwrap();
+            let slashed = send_trimmed(format!("{path}/")).await.unwrap();
+            assert_eq!(plain.status(), slashed.status(), "{path}");
+            assert_eq!(json_body(plain).await, json_body(slashed).await, "{path}");
+        }
+
+        // Without trimming the slashed form doesn't match any route.
+        let untrimmed = send(
+            state(),
+            Request::get("/healthz/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(untrimmed.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn params(query: serde_json::Value) -> GetPageParams {
+        serde_json::from_value(query).expect("valid page params")
+    }
+
+    #[test]
+    fn required_properties_must_be_checked() {
+        let published = |checked: bool| {
+            page(
+                PAGE_ID,
+                serde_json::json!({
+                    "Published": { "id": "p", "type": "checkbox", "checkbox": checked },
+                }),
+            )
+        };
+        let required = params(serde_json::json!({ "require_property": "published" }));
+
+        assert!(requirement_met(&published(true), &required));
+        assert!(!requirement_met(&published(false), &required));
+        assert!(!requirement_met(
+            &page(PAGE_ID, serde_json::json!({})),
+            &required
+        ));
+        // Without a required property every page passes.
+        let unrestricted = params(serde_json::json!({}));
+        assert!(requirement_met(&published(false), &unrestricted));
+    }
+
+    fn database(properties: serde_json::Value) -> Database {
+        serde_json::from_value(serde_json::json!({
+            "object": "database",
+            "id": "ffffffff-ffff-ffff-ffff-ffffffffffff",
+            "created_time": "2024-01-01T00:00:00.000Z",
+            "last_edited_time": "2024-01-02T00:00:00.000Z",
+            "title": [],
+            "description": [],
+            "properties": properties,
+            "parent": { "type": "workspace", "workspace": true },
+            "url": "https://www.notion.so/database",
+            "archived": false,
+            "is_inline": false,
+        }))
+        .expect("valid notion database")
+    }
+
+    #[test]
+    fn database_schema_lists_property_types() {
+        let database = database(serde_json::json!({
+            "Name": { "id": "title", "name": "Name", "type": "title", "title": {} },
+            "Tags": {
+                "id": "tags",
+                "name": "Tags",
+                "type": "multi_select",
+                "multi_select": { "options": [] },
+            },
+            "Price": {
+                "id": "price",
+                "name": "Price",
+                "type": "number",
+                "number": { "format": "dollar" },
+            },
+            "Done": { "id": "done", "name": "Done", "type": "checkbox", "checkbox": {} },
+            "Status": {
+                "id": "status",
+                "name": "Status",
+                "type": "status",
+                "status": { "options": [], "groups": [] },
+            },
+        }));
+
+        assert_eq!(
+            serde_json::to_value(database_schema(&database)).unwrap(),
+            serde_json::json!({
+                "Done": "checkbox",
+                "Name": "title",
+                "Price": "number",
+                "Status": "status",
+                "Tags": "multi_select",
+            })
+        );
+    }
+
+    fn titled(id: &str, title: &str) -> NotionPage {
+        page(
+            id,
+            serde_json::json!({
+                "Name": {
+                    "id": "title",
+                    "type": "title",
+                    "title": rich_text(title),
+                },
+            }),
+        )
+    }
+
+    #[test]
+    fn database_documents_keep_page_order_and_headings() {
+        let pages = [
+            (titled("a", "First"), Ok("# Intro\n\nText\n".to_string())),
+            (
+                titled("b", "Second"),
+                Err(ApiError::new(StatusCode::NOT_FOUND, "page not found")),
+            ),
+            (page("c", serde_json::json!({})), Ok("Body\n\n".to_string())),
+        ];
+        let document = |layout: &DocumentLayout| {
+            pages
+                .iter()
+                .enumerate()
+                .map(|(index, (page, rendered))| layout.section(page, rendered, index == 0))
+                .collect::<String>()
+        };
+
+        let layout = DocumentLayout {
+            separator: DEFAULT_PAGE_SEPARATOR.to_string(),
+            headings: true,
+            heading_offset: 1,
+        };
+        assert_eq!(
+            document(&layout),
+            "## First\n\n## Intro\n\nText\n\
+             \n---\n\n## Second\n\n<!-- page b: page not found -->\n\
+             \n---\n\n## Untitled\n\nBody\n"
+        );
+
+        let bare = DocumentLayout {
+            separator: "\n".to_string(),
+            headings: false,
+            heading_offset: 0,
+        };
+        assert_eq!(
+            document(&bare),
+            "# Intro\n\nText\n\n<!-- page b: page not found -->\n\nBody\n"
+        );
+    }
+
+    #[test]
+    fn oversized_markdown_is_too_large() {
+        let limited = AppState {
+            max_markdown_bytes: Some(10),
+            ..state()
+        };
+        assert!(limited.check_markdown_size("# Page\n").is_ok());
+        assert!(limited.check_markdown_size(&"x".repeat(10)).is_ok());
+        let err = limited
+            .check_markdown_size(&"x".repeat(11))
+            .expect_err("over the limit");
+
+        let err = ApiError::render(&err.into());
+        assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(err.code(), "too_large");
+        assert_eq!(
+            err.message,
+            "rendered page is 11 bytes, over the limit of 10 bytes"
+        );
+        assert!(state().check_markdown_size(&"x".repeat(1 << 20)).is_ok());
+    }
+
+    #[test]
+    fn languages_follow_accept_language_then_the_default() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static("fr;q=0.5, zh-TW, *;q=0.1, de;q=0, en;q=0.8"),
+        );
+        assert_eq!(accept_languages(&headers), ["zh-TW", "en", "fr"]);
+
+        // Localization off: no languages, so properties are left as is.
+        assert!(state().request_languages(&headers).is_empty());
+
+        let localized = AppState {
+            localized_names: LocalizedNames::new("{name} ({lang})"),
+            default_language: "ja".into(),
+            ..state()
+        };
+        assert_eq!(
+            localized.request_languages(&headers),
+            ["zh-TW", "en", "fr", "ja"]
+        );
+        assert_eq!(localized.request_languages(&HeaderMap::new()), ["ja"]);
+    }
+
+    #[test]
+    fn zip_exports_unzip_to_pages_and_errors() {
+        let mut export = ZipExport::new();
+        export
+            .add_page("hello", "---\ntitle: Hello\n---\n# Hello\n")
+            .expect("first page");
+        let mut archive = export.take();
+        export
+            .add_page("hello", "# Hello again\n")
+            .expect("second page");
+        export.add_page("notes", "# Notes\n").expect("third page");
+        export.errors.push(format!("{PAGE_ID}: page not found"));
+        archive.extend(export.take());
+        archive.extend(export.finish().expect("finished archive"));
+
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(archive)).expect("readable archive");
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names.len(), 4);
+        let mut entry = |name: &str| {
+            let mut content = String::new();
+            std::io::Read::read_to_string(
+                &mut archive.by_name(name).expect("entry exists"),
+                &mut content,
+            )
+            .expect("utf-8 entry");
+            content
+        };
+        assert_eq!(entry("hello.md"), "---\ntitle: Hello\n---\n# Hello\n");
+        assert_eq!(entry("hello-2.md"), "# Hello again\n");
+        assert_eq!(entry("notes.md"), "# Notes\n");
+        assert_eq!(entry("errors.txt"), format!("{PAGE_ID}: page not found\n"));
+    }
+
+    #[test]
+    fn zip_exports_without_errors_leave_out_errors_txt() {
+        let mut export = ZipExport::new();
+        export.add_page("hello", "# Hello\n").expect("page");
+        let mut archive = export.take();
+        archive.extend(export.finish().expect("finished archive"));
+
+        let archive =
+            zip::ZipArchive::new(std::io::Cursor::new(archive)).expect("readable archive");
+        assert_eq!(archive.file_names().collect::<Vec<_>>(), ["hello.md"]);
+    }
+
+    /// A bulleted list item reading `content`, as Notion returns it. Unlike
+    /// paragraphs, list items render their children, indented.
+    fn bullet(id: &str, content: &str, has_children: bool) -> serde_json::Value {
+        serde_json::json!({
+            "object": "block",
+            "id": id,
+            "type": "bulleted_list_item",
+            "bulleted_list_item": { "rich_text": rich_text(content), "color": "default" },
+            "has_children": has_children,
+        })
+    }
+
+    /// Routes serving `PAGE_ID` with list items nested four deep, `level-1`
+    /// holding `level-2` and so on, recording whose children are fetched.
+    fn nested_page(fetched: Arc<Mutex<Vec<String>>>) -> Router {
+        Router::new()
+            .route(
+                "/pages/{id}",
+                get(|Path(id): Path<String>| async move { Json(page_json(&id, serde_json::json!({}))) }),
+            )
+            .route(
+                "/blocks/{id}/children",
+                get(move |Path(id): Path<String>| async move {
+                    fetched.lock().unwrap().push(id.clone());
+                    let level = match id.strip_prefix("level-") {
+                        Some(level) => level.parse::<usize>().expect("level block") + 1,
+                        None => 1,
+                    };
+                    let blocks = (level <= 4)
+                        .then(|| {
+                            bullet(&format!("level-{level}"), &format!("Level {level}"), level < 4)
+                        })
+                        .into_iter()
+                        .collect();
+                    children(blocks)
+                }),
+            )
+    }
+
+    #[tokio::test]
+    async fn block_trees_stop_at_max_depth() {
+        let fetched = Arc::default();
+        let state = with_notion(nested_page(Arc::clone(&fetched))).await;
+        let client = state.notion_client("secret").expect("mock client");
+
+        let tree = fetch_block_tree(&state, &client, PAGE_ID, Some(2))
+            .await
+            .expect("block tree");
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].block.id.as_deref(), Some("level-1"));
+        assert_eq!(tree[0].children.len(), 1);
+        assert_eq!(tree[0].children[0].block.id.as_deref(), Some("level-2"));
+        assert!(tree[0].children[0].children.is_empty());
+        // `level-2` has children of its own, but they are never fetched.
+        assert_eq!(*fetched.lock().unwrap(), [PAGE_ID, "level-1"]);
+
+        let markdown = |query: &'static str| {
+            let state = state.clone();
+            async move {
+                let request = authorized(&format!("/page/{PAGE_ID}{query}"))
+                    .header(header::ACCEPT, "text/markdown")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = send(state, request).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                text_body(response).await
+            }
+        };
+        let shallow = markdown("?max_depth=2").await;
+        assert!(
+            shallow.contains("Level 1") && shallow.contains("Level 2"),
+            "{shallow:?}"
+        );
+        assert!(!shallow.contains("Level 3"), "{shallow}");
+        let full = markdown("").await;
+        assert!(full.contains("Level 4"), "{full}");
+    }
+
+    #[test]
+    fn max_depth_must_be_positive() {
+        let err = page_render(&params(serde_json::json!({ "max_depth": 0 })))
+            .err()
+            .expect("max_depth of zero");
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.message, "max_depth must be at least 1");
+
+        let render =
+            page_render(&params(serde_json::json!({ "max_depth": 2 }))).expect("max_depth of two");
+        assert_eq!(render.max_depth, Some(2));
+        let render = page_render(&params(serde_json::json!({}))).expect("default depth");
+        assert_eq!(render.max_depth, None);
+    }
+
+    #[test]
+    fn archived_pages_are_hidden_unless_included() {
+        let live = page(PAGE_ID, serde_json::json!({}));
+        let mut archived = page(PAGE_ID, serde_json::json!({}));
+        archived.archived = true;
+        let included = params(serde_json::json!({ "include_archived": true }));
+        let excluded = params(serde_json::json!({ "include_archived": false }));
+        let default = params(serde_json::json!({}));
+
+        for params in [&default, &excluded] {
+            let err = check_archived(&archived, params).expect_err("archived page");
+            assert_eq!(err.status, StatusCode::NOT_FOUND);
+            assert_eq!(err.message, "page is archived or in the trash");
+        }
+        assert!(check_archived(&archived, &included).is_ok());
+        for params in [&default, &excluded, &included] {
+            assert!(check_archived(&live, params).is_ok());
+        }
+    }
+
+    #[test]
+    fn html_is_negotiated_from_accept() {
+        let format = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+            page_response_format(&headers)
+        };
+        assert!(matches!(format("text/html"), PageResponseFormat::Html));
+        assert!(matches!(
+            format("text/html;q=0.9, application/json"),
+            PageResponseFormat::Html
+        ));
+        assert!(matches!(
+            format("application/json, text/html"),
+            PageResponseFormat::Json
+        ));
+        assert!(matches!(format("text/*"), PageResponseFormat::Markdown));
+    }
+
+    #[test]
+    fn markdown_is_converted_to_an_html_document() {
+        let markdown =
+            "# Hello\n\n- [x] done\n- [ ] todo\n\n| a | b |\n| - | - |\n| 1 | 2 |\n\n~~old~~\n";
+        let html = markdown_to_html(markdown, Some("Q&A <draft>"), None);
+
+        assert!(html.starts_with("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n"));
+        assert!(
+            html.contains(
+                "<title>Q&amp;A &lt;draft&gt;</title>\n</head>\n<body>\n<h1>Hello</h1>\n"
+            )
+        );
+        assert!(html.contains("type=\"checkbox\" checked=\"\""));
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<del>old</del>"));
+        assert!(!html.contains("<meta name="));
+        assert!(html.ends_with("</body>\n</html>\n"));
+
+        let untitled = markdown_to_html("Text\n", None, None);
+        assert!(!untitled.contains("<title>"));
+        assert!(untitled.contains("<body>\n<p>Text</p>\n</body>"));
+    }
+
+    #[test]
+    fn html_frontmatter_becomes_sorted_meta_tags() {
+        let meta = HashMap::from([
+            (
+                "title".to_string(),
+                PropertyValue::String("Say \"hi\"".to_string()),
+            ),
+            (
+                "tags".to_string(),
+                PropertyValue::StringArray(vec!["a".to_string(), "b".to_string()]),
+            ),
+        ]);
+
+        let html = markdown_to_html("Text\n", None, Some(&meta));
+
+        assert!(html.contains(&format!(
+            "<meta name=\"tags\" content=\"{}\">\n<meta name=\"title\" content=\"Say &quot;hi&quot;\">\n</head>",
+            escape_html(&property_value_to_string(&meta["tags"])),
+        )));
+    }
+
+    #[test]
+    fn html_special_characters_are_escaped() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain"), "plain");
+    }
+
+    #[tokio::test]
+    async fn bursts_past_the_rate_limit_answer_too_many_requests() {
+        let state = AppState {
+            rate_limiter: Some(RateLimiter::new(3)),
+            ..state()
+        };
+        let request = |token: &str| {
+            Request::get("/page/not-a-page-id")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Within the limit, requests reach the handler, which rejects the ID.
+        for _ in 0..3 {
+            let response = send(state.clone(), request("secret")).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let limited = send(state.clone(), request("secret")).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        // The bucket refills at three requests a minute, one every 20s.
+        assert_eq!(limited.headers()[header::RETRY_AFTER], "20");
+        assert_eq!(json_body(limited).await["code"], "rate_limited");
+
+        // Other tokens have buckets of their own.
+        let response = send(state, request("other")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn notion_request_ids_reach_the_error_body() {
+        let routes = Router::new().route(
+            "/pages/{id}",
+            get(|| async {
+                let error = serde_json::json!({
+                    "object": "error",
+                    "status": 404,
+                    "code": "object_not_found",
+                    "message": "Could not find page",
+                    "request_id": "abc",
+                });
+                (StatusCode::NOT_FOUND, Json(error))
+            }),
+        );
+        let state = with_notion(routes).await;
+
+        let request = authorized(&format!("/page/{PAGE_ID}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            json_body(response).await,
+            serde_json::json!({
+                "error": "page not found, or not shared with the integration",
+                "code": "not_found",
+                "notion_request_id": "abc",
+            })
+        );
+
+        // Errors Notion gave no ID for leave the key out.
+        let body = json_body(ApiError::notion(&notion_error(404), "page").into_response()).await;
+        assert_eq!(body.get("notion_request_id"), None);
+    }
+
+    #[tokio::test]
+    async fn format_extensions_override_accept() {
+        let state = with_notion(hello_page(Arc::default())).await;
+        let page = |path: String, accept: &'static str| {
+            let request = authorized(&path)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            send(state.clone(), request)
+        };
+
+        let markdown = page(format!("/page/{PAGE_ID}.md"), "application/json").await;
+        assert_eq!(markdown.status(), StatusCode::OK);
+        assert_eq!(
+            markdown.headers()[header::CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(text_body(markdown).await.trim_end(), "Hello, world");
+
+        let json = page(format!("/page/{PAGE_ID}.json"), "text/markdown").await;
+        assert_eq!(json.status(), StatusCode::OK);
+        assert_eq!(json.headers()[header::CONTENT_TYPE], "application/json");
+        let json = json_body(json).await;
+        assert_eq!(json["id"], PAGE_ID);
+        assert_eq!(
+            json["content"].as_str().map(str::trim_end),
+            Some("Hello, world")
+        );
+
+        let invalid = page("/page/not-a-page.md".to_string(), "text/markdown").await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(invalid).await["error"],
+            "invalid page id: not-a-page"
+        );
+    }
+
+    #[tokio::test]
+    async fn properties_route_skips_rendering() {
+        let block_fetches = Arc::new(AtomicUsize::new(0));
+        let state = with_notion(hello_page(block_fetches.clone())).await;
+
+        let request = authorized(&format!("/page/{PAGE_ID}/properties"))
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["Name"], "Hello");
+        assert_eq!(block_fetches.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn unsupported_notion_version_is_rejected_on_file_routes() {
+        let request = authorized(&format!("/file/{PAGE_ID}/{BLOCK_ID}"))
+            .header("X-Notion-Version", "2021-05-13")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state(), request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn properties_only_returns_yaml_without_rendering() {
+        let block_fetches = Arc::new(AtomicUsize::new(0));
+        let state = with_notion(hello_page(block_fetches.clone())).await;
+
+        let request = authorized(&format!("/page/{PAGE_ID}.md?properties_only=true"))
+            .header(header::ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state.clone(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/yaml");
+        assert_eq!(text_body(response).await, "Name: Hello\n");
+        assert_eq!(block_fetches.load(Ordering::SeqCst), 0);
+
+        let request = authorized(&format!("/page/{PAGE_ID}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(state, request).await.status(), StatusCode::OK);
+        assert_eq!(block_fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn tokens_are_read_from_the_configured_header() {
+        let state = AppState {
+            token_header: HeaderName::from_static("x-notion-token"),
+            ..state()
+        };
+        let token = |headers: &[(&str, &str)]| {
+            let mut request = Request::builder();
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            let (mut parts, ()) = request.body(()).unwrap().into_parts();
+            let state = state.clone();
+            async move {
+                let MaybeBearerToken(token) =
+                    MaybeBearerToken::from_request_parts(&mut parts, &state)
+                        .await
+                        .unwrap();
+                token
+            }
+        };
+
+        assert_eq!(
+            token(&[("x-notion-token", " secret ")]).await.as_deref(),
+            Some("secret")
+        );
+        assert_eq!(
+            token(&[
+                ("x-notion-token", "secret"),
+                ("authorization", "Bearer bearer-secret"),
+            ])
+            .await
+            .as_deref(),
+            Some("bearer-secret")
+        );
+        assert_eq!(token(&[("x-notion-token", "  ")]).await, None);
+        assert_eq!(token(&[("auth", "secret")]).await, None);
+    }
+
+    #[tokio::test]
+    async fn json_pages_carry_the_icon_and_cover() {
+        let routes = Router::new()
+            .route(
+                "/pages/{id}",
+                get(|Path(id): Path<String>| async move {
+                    let mut page = page_json(&id, serde_json::json!({}));
+                    if id == PAGE_ID {
+                        page["icon"] = serde_json::json!({ "type": "emoji", "emoji": "🚀" });
+                        page["cover"] = serde_json::json!({
+                            "type": "external",
+                            "external": { "url": "https://example.com/cover.png" },
+                        });
+                    }
+                    Json(page)
+                }),
+            )
+            .route("/blocks/{id}/children", get(|| async { children(vec![]) }));
+        let state = with_notion(routes).await;
+        let page = |id: &str| {
+            let request = authorized(&format!("/page/{id}.json"))
+                .body(Body::empty())
+                .unwrap();
+            send(state.clone(), request)
+        };
+
+        let decorated = json_body(page(PAGE_ID).await).await;
+        assert_eq!(decorated["icon"], "🚀");
+        assert_eq!(decorated["cover"], "https://example.com/cover.png");
+
+        let plain = json_body(page(BLOCK_ID).await).await;
+        assert_eq!(plain["id"], BLOCK_ID);
+        assert!(plain.get("icon").is_none());
+        assert!(plain.get("cover").is_none());
+    }
+
+    /// Routes serving page [`PAGE_ID`] holding an uploaded image, nested in a
+    /// toggle so its parent is a block rather than the page.
+    fn image_page() -> Router {
+        const TOGGLE_ID: &str = "0f1e2d3c-4b5a-4968-8776-5a4b3c2d1e0f";
+        let image = serde_json::json!({
+            "object": "block",
+            "id": BLOCK_ID,
+            "parent": { "type": "block_id", "block_id": TOGGLE_ID },
+            "type": "image",
+            "image": {
+                "type": "file",
+                "file": {
+                    "url": "https://files.example.com/hello.png",
+                    "expiry_time": "2024-01-01T01:00:00.000Z",
+                },
+                "caption": [],
+            },
+            "has_children": false,
+        });
+        let toggle = serde_json::json!({
+            "object": "block",
+            "id": TOGGLE_ID,
+            "parent": { "type": "page_id", "page_id": PAGE_ID },
+            "type": "bulleted_list_item",
+            "bulleted_list_item": { "rich_text": rich_text("Photos"), "color": "default" },
+            "has_children": true,
+        });
+        let blocks = HashMap::from([(BLOCK_ID, image.clone()), (TOGGLE_ID, toggle.clone())]);
+        let nested = HashMap::from([(PAGE_ID, vec![toggle]), (TOGGLE_ID, vec![image])]);
+        Router::new()
+            .route(
+                "/pages/{id}",
+                get(|Path(id): Path<String>| async move {
+                    Json(page_json(&id, serde_json::json!({})))
+                }),
+            )
+            .route(
+                "/blocks/{id}",
+                get(move |Path(id): Path<String>| async move {
+                    match blocks.get(id.as_str()) {
+                        Some(block) => Json(block.clone()).into_response(),
+                        None => StatusCode::NOT_FOUND.into_response(),
+                    }
+                }),
+            )
+            .route(
+                "/blocks/{id}/children",
+                get(move |Path(id): Path<String>| async move {
+                    children(nested.get(id.as_str()).cloned().unwrap_or_default())
+                }),
+            )
+    }
+
+    #[tokio::test]
+    async fn images_are_served_only_for_their_own_page() {
+        let state = with_notion(image_page()).await;
+        let image = |page_id: &str| {
+            let request = authorized(&format!("/image/{page_id}/{BLOCK_ID}"))
+                .body(Body::empty())
+                .unwrap();
+            send(state.clone(), request)
+        };
+
+        let found = image(PAGE_ID).await;
+        assert_eq!(found.status(), StatusCode::FOUND);
+        assert_eq!(
+            found.headers()[header::LOCATION],
+            "https://files.example.com/hello.png"
+        );
+
+        let other_page = "ffffffff-ffff-ffff-ffff-ffffffffffff";
+        let missing = image(other_page).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            json_body(missing).await["error"],
+            format!("block {BLOCK_ID} is not in page {other_page}")
+        );
+    }
+
+    #[tokio::test]
+    async fn images_are_proxied_only_with_a_server_token() {
+        let request = || {
+            authorized(&format!("/page/{PAGE_ID}.md?refresh_images=true"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let without_token = with_notion(image_page()).await;
+        let markdown = text_body(send(without_token, request()).await).await;
+        assert!(
+            markdown.contains("(https://files.example.com/hello.png)"),
+            "{markdown}"
+        );
+
+        let with_token = AppState {
+            default_token: Some("secret".into()),
+            ..with_notion(image_page()).await
+        };
+        let markdown = text_body(send(with_token, request()).await).await;
+        assert!(
+            markdown.contains(&format!("(/image/{PAGE_ID}/{BLOCK_ID})")),
+            "{markdown}"
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_reject_invalid_render_options_up_front() {
+        // Any call to Notion would answer 404 here.
+        let state = with_notion(Router::new()).await;
+        let request = authorized(&format!("/database/{PAGE_ID}/export.zip?max_depth=0"))
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(response).await["error"],
+            "max_depth must be at least 1"
+        );
+    }
+}